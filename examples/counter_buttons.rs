@@ -12,35 +12,43 @@ fn main() {
     use domafic::listener::on;
     use domafic::web_render::{run, JsIo};
 
-    enum Msg {
+    // Messages produced by a single counter button
+    enum ButtonMsg {
         Increment,
         Decrement,
     }
 
-    let update_button = |state: &mut isize, msg: Msg| {
+    // Messages produced by the application as a whole
+    enum Msg {
+        Button(ButtonMsg),
+    }
+
+    let update_button = |state: &mut isize, msg: ButtonMsg| {
         *state = match msg {
-            Msg::Increment => *state + 1,
-            Msg::Decrement => *state - 1,
+            ButtonMsg::Increment => *state + 1,
+            ButtonMsg::Decrement => *state - 1,
         }
     };
 
     let render_button = |state: &isize| {
         div ((
             button ((
-                on("click", |_| Msg::Decrement),
+                on("click", |_| ButtonMsg::Decrement),
                 "-",
             )),
             state.to_string(),
             button ((
-                on("click", |_| Msg::Increment),
+                on("click", |_| ButtonMsg::Increment),
                 "+",
             )),
         ))
     };
 
     let update = |state: &mut Vec<isize>, msg: Msg, mut keys: KeyIter, _: &JsIo<Msg>| {
-        let key = keys.next().unwrap();
-        update_button(&mut state[key], msg)
+        let key = keys.next_index().unwrap();
+        match msg {
+            Msg::Button(button_msg) => update_button(&mut state[key], button_msg),
+        }
     };
 
     let render = |state: &Vec<isize>| {
@@ -48,7 +56,9 @@ fn main() {
             h1("Hello from rust!"),
             state
                 .iter().enumerate()
-                .map(|(index, count)| render_button(count).with_key(index))
+                .map(|(index, count)|
+                    render_button(count).map_messages(Msg::Button).with_key(index)
+                )
                 .collect::<Vec<_>>()
         ))
     };
@@ -0,0 +1,98 @@
+extern crate domafic;
+
+#[cfg(not(target_os = "emscripten"))]
+fn main() {
+    panic!("This example needs to be run in the browser via the asm.js or WebAssembly targets.")
+}
+
+// Compares how quickly large lists are drawn with and without batching DOM mutations.
+// Build in release mode for meaningful numbers.
+#[cfg(target_os = "emscripten")]
+fn main() {
+    use domafic::{DomNode, KeyIter};
+    use domafic::AttributeValue::Str;
+    use domafic::tags::{attributes, button, div, h1, li, ul};
+    use domafic::listener::on;
+    use domafic::web_render::{run, set_batched_mutations, set_render_mode, Cmd, JsIo, RenderMode};
+    use domafic::web_render::operation_counts;
+    use std::time::Instant;
+
+    const ROWS: usize = 10_000;
+
+    enum Msg {
+        Run(bool),
+        Finished,
+    }
+
+    struct State {
+        round: usize,
+        batched: bool,
+        started: Option<Instant>,
+        // Operations applied to the page before the round started
+        operations_before: usize,
+        results: Vec<String>,
+    }
+
+    let update = |state: &mut State, msg: Msg, _: KeyIter, _: &dyn JsIo<Msg>| match msg {
+        Msg::Run(batched) => {
+            set_batched_mutations(batched);
+            state.batched = batched;
+            state.round += 1;
+            state.operations_before = operation_counts().total();
+            state.started = Some(Instant::now());
+            Cmd::delay(0, Msg::Finished)
+        }
+        Msg::Finished => {
+            if let Some(started) = state.started.take() {
+                let elapsed = started.elapsed();
+                let millis = elapsed.as_secs() as f64 * 1000.0 +
+                    elapsed.subsec_nanos() as f64 / 1_000_000.0;
+                let ops = operation_counts().total() - state.operations_before;
+                state.results.push(format!(
+                    "{}: {} operations in {:.1} ms ({:.0} operations per second)",
+                    if state.batched { "Batched" } else { "Unbatched" },
+                    ops,
+                    millis,
+                    ops as f64 / millis * 1000.0,
+                ));
+            }
+            Cmd::none()
+        }
+    };
+
+    let render = |state: &State| {
+        // Each round uses new keys, so that every row is replaced
+        let first_key = state.round * ROWS;
+        let rows = if state.round == 0 { 0 } else { ROWS };
+        div ((
+            h1("DOM mutation benchmark"),
+            button ((
+                on("click", |_| Msg::Run(true)),
+                "Run batched",
+            )),
+            button ((
+                on("click", |_| Msg::Run(false)),
+                "Run unbatched",
+            )),
+            ul(state.results.iter().map(|result| li(result.clone())).collect::<Vec<_>>()),
+            ul(
+                (first_key..first_key + rows)
+                    .map(|key| li((
+                        attributes([("class", Str("row"))]),
+                        key.to_string(),
+                    )).with_key(key))
+                    .collect::<Vec<_>>()
+            ),
+        ))
+    };
+
+    // Render after every message, so that the delayed message arrives once the page is updated
+    set_render_mode(RenderMode::Sync);
+    run("body", update, render, State {
+        round: 0,
+        batched: true,
+        started: None,
+        operations_before: 0,
+        results: Vec::new(),
+    });
+}
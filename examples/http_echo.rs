@@ -12,7 +12,7 @@ fn main() {
 
     // If rendering client-side with asm.js or WebAssembly:
     #[cfg(target_os = "emscripten")]
-    use domafic::web_render::{run, JsIo, HttpRequest, HttpResult};
+    use domafic::web_render::{run, Cmd, JsIo, HttpRequest, HttpResult};
 
     #[derive(Debug, Clone)]
     struct State {
@@ -27,25 +27,26 @@ fn main() {
     }
 
     #[cfg(target_os = "emscripten")]
-    let update = |state: &mut State, msg: Msg, _keys, js_io: &JsIo<Msg>| {
+    let update = |state: &mut State, msg: Msg, _keys, _: &JsIo<Msg>| {
         match msg {
             Msg::Echo(message) => {
-                js_io.http(HttpRequest {
+                state.request_out = true;
+                Cmd::http(HttpRequest {
                     method: "POST",
                     headers: &[("key1", "value1"), ("key2", "value2"), ("key3", "value3")],
                     url: "https://httpbin.org/post",
-                    body: &message,
+                    body: message.as_bytes(),
                     timeout_millis: None,
-                }, Box::new(|response: HttpResult|
+                }, |response: HttpResult|
                     Msg::Received(format!("{:?}", response))
-                ));
-                state.request_out = true;
+                )
             }
             Msg::Received(received) => {
                 state.request_out = false;
                 state.last_response = Some(received);
+                Cmd::none()
             }
-        };
+        }
     };
 
     let render = |state: &State| {
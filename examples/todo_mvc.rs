@@ -11,7 +11,7 @@ fn main() {
     use domafic::AttributeValue::*;
     use domafic::tags::*;
     use domafic::listener::on;
-    use domafic::web_render::{run, JsIo};
+    use domafic::web_render::{run_persisted, JsIo, Persistence};
 
     enum Msg {
         UpdateField(String),
@@ -43,7 +43,7 @@ fn main() {
                 state.todos.push(todo);
             },
             Msg::Remove => {
-                state.todos.remove(keys.next().unwrap());
+                state.todos.remove(keys.next_index().unwrap());
             },
             Msg::None => {},
         }
@@ -96,5 +96,17 @@ fn main() {
         ))
     };
 
-    run("body", update, render, TodoState::new());
+    // Keep the todos across page reloads, one per line since the entry box is a single line
+    let persistence = Persistence::new("todo_mvc", 1,
+        |state: &TodoState| state.todos.join("\n"),
+        |saved, version| if version == 1 {
+            Some(TodoState {
+                entry_box: String::new(),
+                todos: saved.lines().map(String::from).collect(),
+            })
+        } else {
+            None
+        });
+
+    run_persisted("body", update, render, TodoState::new(), persistence);
 }
@@ -0,0 +1,642 @@
+use DomNode;
+use cmd::Cmd;
+use html_document::Head;
+use keys::KeyIter;
+use sub::Sub;
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::{fmt, str};
+#[cfg(feature = "serde")]
+use std::error::Error;
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+/// `Updater`s modify the current application state based on messages.
+pub trait Updater<State, Message>: Sized {
+    /// Modify the application state based on a message, returning a `Cmd` describing the side
+    /// effects to perform afterwards.
+    ///
+    /// `KeyIter` may be used to identify which component the message originated from.
+    fn update(&self, &mut State, Message, KeyIter, &JsIo<Message>) -> Cmd<Message>;
+
+    /// Describes the timers and animation frames the application listens to given the current
+    /// application state.
+    ///
+    /// The runtime calls this after every update, starting and stopping subscriptions as they
+    /// change. Defaults to `Sub::none()`.
+    fn subscriptions(&self, _state: &State) -> Sub<Message> {
+        Sub::none()
+    }
+}
+
+/// Functions returning either `()` or a `Cmd` are `Updater`s.
+impl<F, S, M, C> Updater<S, M> for F
+    where F: Fn(&mut S, M, KeyIter, &JsIo<M>) -> C, C: Into<Cmd<M>>
+{
+    fn update(
+        &self,
+        state: &mut S,
+        msg: M,
+        keys: KeyIter,
+        js_io: &JsIo<M>
+    ) -> Cmd<M> {
+        (self)(state, msg, keys, js_io).into()
+    }
+}
+
+/// Combines `updater` with a function describing the application's subscriptions for each state.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::KeyIter;
+/// use domafic::app::{with_subscriptions, JsIo, Updater};
+/// use domafic::sub::Sub;
+///
+/// struct Tick;
+///
+/// let updater = with_subscriptions(
+///     |seconds: &mut u32, _: Tick, _: KeyIter, _: &JsIo<Tick>| *seconds += 1,
+///     |seconds: &u32| if *seconds < 10 { Sub::interval(1000, |_| Tick) } else { Sub::none() },
+/// );
+/// assert_eq!(1, Updater::<u32, Tick>::subscriptions(&updater, &0).subscriptions().len());
+/// assert!(Updater::<u32, Tick>::subscriptions(&updater, &10).subscriptions().is_empty());
+/// ```
+pub fn with_subscriptions<U, F>(updater: U, subscriptions: F) -> WithSubscriptions<U, F> {
+    WithSubscriptions { updater, subscriptions }
+}
+
+/// An `Updater` which also describes the application's subscriptions.
+/// Created by `with_subscriptions`.
+pub struct WithSubscriptions<U, F> {
+    updater: U,
+    subscriptions: F,
+}
+
+impl<U, F, S, M> Updater<S, M> for WithSubscriptions<U, F>
+    where U: Updater<S, M>, F: Fn(&S) -> Sub<M>
+{
+    fn update(&self, state: &mut S, msg: M, keys: KeyIter, js_io: &JsIo<M>) -> Cmd<M> {
+        self.updater.update(state, msg, keys, js_io)
+    }
+    fn subscriptions(&self, state: &S) -> Sub<M> {
+        (self.subscriptions)(state)
+    }
+}
+
+/// `Renderer`s convert the current state to the current UI `DomNode`.
+pub trait Renderer<State, Message> {
+
+    // Note: this should really be Rendered<'a>: DomNode + 'a
+    // to allow for references to bits of state, but this is
+    // impossible without ATCs
+    /// Type of the rendered `DomNode`
+    type Rendered: DomNode<Message>;
+
+    /// Renders a `DomNode` given the current application state
+    fn render(&self, &State) -> Self::Rendered;
+
+    /// Describes the page's metadata given the current application state.
+    ///
+    /// If this returns `None` (the default), the page's metadata is left untouched.
+    fn head(&self, _state: &State) -> Option<Head> {
+        None
+    }
+}
+
+impl<F, S, R, M> Renderer<S, M> for F where F: Fn(&S) -> R, R: DomNode<M> {
+    type Rendered = R;
+    fn render(&self, state: &S) -> Self::Rendered {
+        (self)(state)
+    }
+}
+
+/// Combines `renderer` with a function describing the page's `Head` for each state.
+///
+/// When run in the browser, the title, language, `meta` tags, stylesheets and scripts of the
+/// page are kept in sync with the `Head` after every render. The same `Head` can be passed to
+/// an `HtmlDocument` to render the page on the server.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::app::{with_head, Renderer};
+/// use domafic::html_document::Head;
+/// use domafic::tags::h1;
+///
+/// let renderer = with_head(
+///     |count: &u32| h1(count.to_string()),
+///     |count: &u32| Head::new().title(format!("Count: {}", count)),
+/// );
+/// let head = Renderer::<u32, ()>::head(&renderer, &5);
+/// assert_eq!(Some("Count: 5".to_string()), head.and_then(|head| head.title));
+/// ```
+pub fn with_head<R, H>(renderer: R, head: H) -> WithHead<R, H> {
+    WithHead { renderer, head }
+}
+
+/// A `Renderer` which also describes the page's `Head`. Created by `with_head`.
+pub struct WithHead<R, H> {
+    renderer: R,
+    head: H,
+}
+
+impl<R, H, S, M> Renderer<S, M> for WithHead<R, H>
+    where R: Renderer<S, M>, H: Fn(&S) -> Head
+{
+    type Rendered = R::Rendered;
+    fn render(&self, state: &S) -> Self::Rendered {
+        self.renderer.render(state)
+    }
+    fn head(&self, state: &S) -> Option<Head> {
+        Some((self.head)(state))
+    }
+}
+
+/// When the application is re-rendered after its state is updated.
+///
+/// Set in the browser using `web_render::set_render_mode`, and in tests using
+/// `TestApp::set_render_mode`. Both default to `RenderMode::Sync`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RenderMode {
+    /// Messages update the state immediately, but rendering waits for the next animation frame,
+    /// so that a burst of messages (such as `mousemove` events) renders only once.
+    ///
+    /// Until then, events are handled by the listeners of the previous render, and the effects
+    /// of each message are performed before the page is updated.
+    AnimationFrame,
+    /// The application is rendered after every message, before its effects are performed.
+    ///
+    /// Useful when the page must reflect each update right away, such as when an `input`
+    /// listener rewrites the value of its element.
+    Sync,
+}
+
+/// A single HTTP request
+#[derive(Debug, Copy, Clone)]
+pub struct HttpRequest<'a> {
+    /// HTTP Method ("GET", "POST", etc.)
+    pub method: &'a str,
+    /// A list of HTTP header (key, value) pairs
+    pub headers: &'a [(&'a str, &'a str)],
+    /// Request URL
+    pub url: &'a str,
+    /// Request body, such as `b"..."` or `text.as_bytes()`
+    pub body: &'a [u8],
+    /// Optional request timeout in milliseconds
+    pub timeout_millis: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl<'a> HttpRequest<'a> {
+    /// Returns a copy of this request whose body is `value` serialized as JSON, adding a
+    /// `Content-Type: application/json` header unless the request already has a `Content-Type`.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use domafic::app::HttpRequest;
+    ///
+    /// let request = HttpRequest {
+    ///     method: "POST",
+    ///     headers: &[],
+    ///     url: "/todos",
+    ///     body: b"",
+    ///     timeout_millis: None,
+    /// }.json(&vec!["milk", "eggs"]).unwrap();
+    /// assert_eq!(b"[\"milk\",\"eggs\"]".to_vec(), request.body);
+    /// assert_eq!(
+    ///     vec![("Content-Type".to_string(), "application/json".to_string())],
+    ///     request.headers);
+    /// ```
+    pub fn json<T: Serialize + ?Sized>(&self, value: &T) -> Result<OwnedHttpRequest, JsonError> {
+        let mut request = OwnedHttpRequest::from(*self);
+        request.body = serde_json::to_vec(value).map_err(JsonError)?;
+        if !request.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("content-type")) {
+            request.headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        }
+        Ok(request)
+    }
+}
+
+/// An `HttpRequest` which owns its contents, such as one stored in a `Cmd`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct OwnedHttpRequest {
+    /// HTTP Method ("GET", "POST", etc.)
+    pub method: String,
+    /// A list of HTTP header (key, value) pairs
+    pub headers: Vec<(String, String)>,
+    /// Request URL
+    pub url: String,
+    /// Request body
+    pub body: Vec<u8>,
+    /// Optional request timeout in milliseconds
+    pub timeout_millis: Option<u32>,
+}
+
+impl OwnedHttpRequest {
+    /// Calls `f` with an `HttpRequest` borrowing the contents of this request.
+    pub fn with_borrowed<R, F: FnOnce(HttpRequest) -> R>(&self, f: F) -> R {
+        let headers: Vec<(&str, &str)> = self.headers.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        f(HttpRequest {
+            method: &self.method,
+            headers: &headers,
+            url: &self.url,
+            body: &self.body,
+            timeout_millis: self.timeout_millis,
+        })
+    }
+}
+
+impl<'a> From<HttpRequest<'a>> for OwnedHttpRequest {
+    fn from(request: HttpRequest<'a>) -> OwnedHttpRequest {
+        OwnedHttpRequest {
+            method: request.method.to_string(),
+            headers: request.headers.iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            url: request.url.to_string(),
+            body: request.body.to_vec(),
+            timeout_millis: request.timeout_millis,
+        }
+    }
+}
+
+/// HTTP request `Result` indicating a possible network error, timeout, invalid request or abort
+pub type HttpResult<'a> = Result<HttpResponse<'a>, HttpError>;
+
+/// A single HTTP response
+#[derive(Debug, Copy, Clone)]
+pub struct HttpResponse<'a> {
+    /// HTTP status
+    pub status_code: u16,
+    /// HTTP status text
+    pub status_text: &'a str,
+    /// A list of HTTP response header (key, value) pairs
+    pub headers: &'a [(&'a str, &'a str)],
+    /// The body of the HTTP response
+    pub body: &'a [u8],
+}
+
+impl<'a> HttpResponse<'a> {
+    /// Returns the value of the first header named `name`, ignoring ASCII case as header names
+    /// are case-insensitive.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use domafic::app::HttpResponse;
+    ///
+    /// let response = HttpResponse {
+    ///     status_code: 200,
+    ///     status_text: "OK",
+    ///     headers: &[("content-type", "text/plain")],
+    ///     body: b"Hello",
+    /// };
+    /// assert_eq!(Some("text/plain"), response.header("Content-Type"));
+    /// assert_eq!(Ok("Hello"), response.text());
+    /// ```
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter()
+            .find(|&&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, value)| value)
+    }
+
+    /// Returns the body as text, or an error if it isn't valid UTF-8.
+    pub fn text(&self) -> Result<&'a str, str::Utf8Error> {
+        str::from_utf8(self.body)
+    }
+
+    /// Parses the body as JSON, returning an error if it isn't valid JSON for a `T`.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use domafic::app::HttpResponse;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let response = HttpResponse {
+    ///     status_code: 200,
+    ///     status_text: "OK",
+    ///     headers: &[],
+    ///     body: b"{\"done\": 3, \"left\": 2}",
+    /// };
+    /// let counts: BTreeMap<String, u32> = response.json().unwrap();
+    /// assert_eq!(Some(&3), counts.get("done"));
+    /// assert!(!response.json::<Vec<u32>>().unwrap_err().is_syntax());
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        serde_json::from_slice(self.body).map_err(JsonError)
+    }
+}
+
+/// Error serializing a request body to JSON or parsing a response body from JSON.
+///
+/// Requires the `serde` feature.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct JsonError(serde_json::Error);
+
+#[cfg(feature = "serde")]
+impl JsonError {
+    /// Returns the underlying `serde_json` error, which describes where parsing failed.
+    pub fn inner(&self) -> &serde_json::Error {
+        &self.0
+    }
+
+    /// Returns whether the body was not valid JSON, as opposed to valid JSON which didn't match
+    /// the expected type.
+    pub fn is_syntax(&self) -> bool {
+        self.0.is_syntax() || self.0.is_eof()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for JsonError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, formatter)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error for JsonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// HTTP request error
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum HttpError {
+    /// The request failed due to a network connection error
+    NetworkError,
+    /// The request did not complete before its timeout expired
+    Timeout,
+    /// The request could not be sent, such as because of a malformed URL, method or header
+    InvalidRequest,
+    /// The request was aborted using `HttpRequestHandle::abort`
+    Aborted,
+}
+
+/// Progress of the upload of a request body or the download of a response body
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HttpProgress {
+    /// Whether this is the progress of the upload of the request body
+    pub upload: bool,
+    /// Number of bytes transferred so far
+    pub loaded: f64,
+    /// Total number of bytes to transfer, if known
+    pub total: Option<f64>,
+}
+
+/// Handler for an HTTP response
+pub trait HttpResponseHandler: 'static {
+    /// Type of message produced from the response
+    type Message;
+    /// Convert the result of an HTTP request into a message
+    fn handle<'a>(&self, HttpResult<'a>) -> Self::Message;
+
+    /// Whether `progress` should be called as the request body is uploaded and the response
+    /// body is downloaded. Defaults to `false`.
+    ///
+    /// Listening for upload progress makes cross-origin requests send a CORS preflight request.
+    fn reports_progress(&self) -> bool {
+        false
+    }
+
+    /// Convert upload or download progress into a message, if any.
+    /// Only called if `reports_progress` returns `true`.
+    fn progress(&self, _progress: HttpProgress) -> Option<Self::Message> {
+        None
+    }
+}
+impl<F, Message> HttpResponseHandler for F
+    where F: for<'a> Fn(HttpResult<'a>) -> Message + 'static
+{
+    type Message = Message;
+    fn handle<'a>(&self, response: HttpResult<'a>) -> Message {
+        (self)(response)
+    }
+}
+
+/// Combines an HTTP response `handler` with a function converting the request's upload and
+/// download progress into messages.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::app::{with_progress, HttpProgress, HttpResponseHandler, HttpResult};
+///
+/// enum Msg {
+///     Progress(f64),
+///     Done,
+/// }
+///
+/// let handler = with_progress(
+///     |_: HttpResult| Msg::Done,
+///     |progress: HttpProgress| Msg::Progress(progress.loaded / progress.total.unwrap_or(1.0)),
+/// );
+/// assert!(handler.reports_progress());
+/// ```
+pub fn with_progress<H, F>(handler: H, progress: F) -> WithProgress<H, F> {
+    WithProgress { handler, progress }
+}
+
+/// An `HttpResponseHandler` which also reports progress. Created by `with_progress`.
+pub struct WithProgress<H, F> {
+    handler: H,
+    progress: F,
+}
+
+impl<H, F> HttpResponseHandler for WithProgress<H, F>
+    where H: HttpResponseHandler, F: Fn(HttpProgress) -> H::Message + 'static
+{
+    type Message = H::Message;
+    fn handle<'a>(&self, response: HttpResult<'a>) -> H::Message {
+        self.handler.handle(response)
+    }
+    fn reports_progress(&self) -> bool {
+        true
+    }
+    fn progress(&self, progress: HttpProgress) -> Option<H::Message> {
+        Some((self.progress)(progress))
+    }
+}
+
+/// Handle to an HTTP request issued using `JsIo::http`.
+///
+/// Unlike a `WebSocket`, dropping the handle leaves the request running.
+pub struct HttpRequestHandle(Box<dyn Fn()>);
+
+impl HttpRequestHandle {
+    /// Creates a handle from a `JsIo`-specific function aborting the request.
+    pub fn new<F: Fn() + 'static>(abort: F) -> HttpRequestHandle {
+        HttpRequestHandle(Box::new(abort))
+    }
+
+    /// Aborts the request, so that its handler receives `Err(HttpError::Aborted)`.
+    /// Does nothing if the request has already completed.
+    pub fn abort(&self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for HttpRequestHandle {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("HttpRequestHandle(..)")
+    }
+}
+
+/// A single WebSocket data frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum WebSocketFrame<'a> {
+    /// A text frame
+    Text(&'a str),
+    /// A binary frame
+    Binary(&'a [u8]),
+}
+
+/// A `WebSocketFrame` which owns its contents
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum OwnedWebSocketFrame {
+    /// A text frame
+    Text(String),
+    /// A binary frame
+    Binary(Vec<u8>),
+}
+
+impl OwnedWebSocketFrame {
+    /// Returns a `WebSocketFrame` borrowing the contents of this frame.
+    pub fn as_frame(&self) -> WebSocketFrame<'_> {
+        match *self {
+            OwnedWebSocketFrame::Text(ref text) => WebSocketFrame::Text(text),
+            OwnedWebSocketFrame::Binary(ref data) => WebSocketFrame::Binary(data),
+        }
+    }
+}
+
+impl<'a> From<WebSocketFrame<'a>> for OwnedWebSocketFrame {
+    fn from(frame: WebSocketFrame<'a>) -> OwnedWebSocketFrame {
+        match frame {
+            WebSocketFrame::Text(text) => OwnedWebSocketFrame::Text(text.to_string()),
+            WebSocketFrame::Binary(data) => OwnedWebSocketFrame::Binary(data.to_vec()),
+        }
+    }
+}
+
+/// An event on a WebSocket connection
+#[derive(Debug, Copy, Clone)]
+pub enum WebSocketEvent<'a> {
+    /// The connection was established and frames can now be sent
+    Open,
+    /// A frame was received
+    Message(WebSocketFrame<'a>),
+    /// The connection failed. A `Close` event always follows.
+    Error,
+    /// The connection was closed. No further events are delivered.
+    Close {
+        /// Close code sent by the server, or 1006 if the connection was lost
+        code: u16,
+        /// Close reason sent by the server
+        reason: &'a str,
+        /// Whether the closing handshake completed
+        was_clean: bool,
+    },
+}
+
+/// Handler for the events of a WebSocket connection
+pub trait WebSocketHandler: 'static {
+    /// Type of message produced from events
+    type Message;
+    /// Convert a WebSocket event into a message
+    fn handle<'a>(&self, event: WebSocketEvent<'a>) -> Self::Message;
+}
+impl<F, Message> WebSocketHandler for F
+    where F: for<'a> Fn(WebSocketEvent<'a>) -> Message + 'static
+{
+    type Message = Message;
+    fn handle<'a>(&self, event: WebSocketEvent<'a>) -> Message {
+        (self)(event)
+    }
+}
+
+/// The operations of a WebSocket connection, implemented by each `JsIo`
+pub trait WebSocketConnection {
+    /// Send `frame` over the connection. Does nothing if the connection is closed.
+    fn send(&self, frame: WebSocketFrame);
+    /// Start closing the connection. Does nothing if the connection is already closed.
+    fn close(&self);
+}
+
+/// Handle to a WebSocket connection opened by `JsIo::websocket`.
+///
+/// The handle is usually kept in the application state so that the updater can send frames.
+/// Dropping the handle closes the connection.
+pub struct WebSocket(Box<dyn WebSocketConnection>);
+
+impl WebSocket {
+    /// Creates a handle from a `JsIo`-specific connection.
+    pub fn new<C: WebSocketConnection + 'static>(connection: C) -> WebSocket {
+        WebSocket(Box::new(connection))
+    }
+
+    /// Sends `frame` over the connection. Frames sent before the `Open` event are dropped.
+    pub fn send(&self, frame: WebSocketFrame) {
+        self.0.send(frame)
+    }
+
+    /// Sends a text frame over the connection.
+    pub fn send_text(&self, text: &str) {
+        self.send(WebSocketFrame::Text(text))
+    }
+
+    /// Sends a binary frame over the connection.
+    pub fn send_binary(&self, data: &[u8]) {
+        self.send(WebSocketFrame::Binary(data))
+    }
+
+    /// Starts closing the connection. The handler receives a `Close` event once it is closed.
+    pub fn close(&self) {
+        self.0.close()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        self.0.close()
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("WebSocket(..)")
+    }
+}
+
+/// JavaScript IO interface
+pub trait JsIo<Message> {
+    /// Issue an asynchronous HTTP request, returning a handle which can be used to abort it
+    fn http<'b> (
+        &self,
+        http_request: HttpRequest<'b>,
+        handler: Box<HttpResponseHandler<Message=Message>>,
+    ) -> HttpRequestHandle;
+
+    /// Open a WebSocket connection to `url`, optionally requesting one of the subprotocols
+    /// `protocols`. The messages produced by `handler` from the connection's events are passed
+    /// to the updater.
+    fn websocket(
+        &self,
+        url: &str,
+        protocols: &[&str],
+        handler: Box<dyn WebSocketHandler<Message=Message>>,
+    ) -> WebSocket;
+}
@@ -0,0 +1,316 @@
+use {DomNode, DomValue, Event, Key, KeyValue, Listener};
+use processors::{DomNodes, DomNodeProcessor, EmptyListeners, Listeners, ListenerProcessor};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::cell::OnceCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+/// Object-safe subset of `DomNode` used to access erased nodes
+trait ErasedDomNode<M> {
+    fn key(&self) -> Option<&Key>;
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue>;
+    fn value(&self) -> DomValue<'_>;
+    fn boxed_children(&self) -> Option<&Vec<BoxedNode<M>>>;
+    /// Erases the children of the node, which must be kept alive by `owner`
+    fn erase_children(&self, owner: &Rc<dyn ErasedDomNode<M>>) -> Vec<BoxedNode<M>>;
+    fn erase_listeners(&self) -> Vec<ErasedListener<M>>;
+}
+
+impl<M, T: DomNode<M>> ErasedDomNode<M> for T {
+    fn key(&self) -> Option<&Key> { DomNode::key(self) }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
+        DomNode::get_attribute(self, index)
+    }
+    fn value(&self) -> DomValue<'_> { DomNode::value(self) }
+    fn boxed_children(&self) -> Option<&Vec<BoxedNode<M>>> { DomNode::boxed_children(self) }
+    fn erase_children(&self, owner: &Rc<dyn ErasedDomNode<M>>) -> Vec<BoxedNode<M>> {
+        let mut children = (owner.clone(), Vec::new());
+        self.children().process_all::<NodeEraser<M>>(&mut children).unwrap();
+        children.1
+    }
+    fn erase_listeners(&self) -> Vec<ErasedListener<M>> {
+        let mut listeners = Vec::new();
+        self.listeners().process_all::<ListenerEraser<M>>(&mut listeners).unwrap();
+        listeners
+    }
+}
+
+/// A type-erased `DomNode`.
+///
+/// `BoxedNode`s allow `DomNode`s of different types to be stored in the same collection or
+/// returned from different branches of a `match`. The original node is kept alive behind a
+/// reference-counted pointer, and its children and listeners are only erased once they are
+/// first accessed, so boxing a node is cheap and nested `BoxedNode`s share their erased children.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::{BoxedNode, DomNode};
+/// use domafic::tags::{div, h1, p};
+///
+/// type Msg = ();
+///
+/// let render_page = |page: usize| -> BoxedNode<Msg> {
+///     match page {
+///         0 => h1("Home").boxed(),
+///         1 => div((h1("About"), p("Made with Rust"))).boxed(),
+///         _ => "Not found".boxed(),
+///     }
+/// };
+///
+/// let pages: Vec<BoxedNode<Msg>> = (0..3).map(render_page).collect();
+/// assert_eq!(
+///     "<div><h1>Home</h1><div><h1>About</h1><p>Made with Rust</p></div>Not found</div>",
+///     div(pages).to_string()
+/// );
+/// ```
+pub struct BoxedNode<M, L = BoxedListeners<M>> {
+    // Keeps the original node alive. `node` points into it.
+    owner: Rc<dyn ErasedDomNode<M>>,
+    node: *const dyn ErasedDomNode<M>,
+    children: OnceCell<Vec<BoxedNode<M>>>,
+    listeners: L,
+}
+
+impl<M> BoxedNode<M> {
+    /// Erases the type of `node`.
+    pub fn new<T: DomNode<M> + 'static>(node: T) -> BoxedNode<M> {
+        let owner: Rc<dyn ErasedDomNode<M>> = Rc::new(node);
+        let node = &*owner as *const dyn ErasedDomNode<M>;
+        BoxedNode::erased(owner, node)
+    }
+
+    /// Creates a `BoxedNode` for `node`, which must be kept alive by `owner`
+    fn erased(owner: Rc<dyn ErasedDomNode<M>>, node: *const dyn ErasedDomNode<M>) -> BoxedNode<M> {
+        BoxedNode {
+            owner: owner.clone(),
+            node,
+            children: OnceCell::new(),
+            listeners: BoxedListeners { _owner: owner, node, listeners: OnceCell::new() },
+        }
+    }
+}
+
+impl<M, L: Listeners<M>> DomNodes<M> for BoxedNode<M, L> {
+    fn process_all<'a, P: DomNodeProcessor<'a, M>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<M, L: Listeners<M>> DomNode<M> for BoxedNode<M, L> {
+    type Children = Vec<BoxedNode<M>>;
+    type Listeners = L;
+    type WithoutListeners = BoxedNode<M, EmptyListeners>;
+
+    fn key(&self) -> Option<&Key> {
+        unsafe { (*self.node).key() }
+    }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
+        unsafe { (*self.node).get_attribute(index) }
+    }
+    fn children(&self) -> &Self::Children {
+        let node = unsafe { &*self.node };
+        // Children of a node that wraps another `BoxedNode` have already been erased
+        node.boxed_children()
+            .unwrap_or_else(|| self.children.get_or_init(|| node.erase_children(&self.owner)))
+    }
+    fn listeners(&self) -> &Self::Listeners {
+        &self.listeners
+    }
+    fn children_and_listeners(&self) -> (&Self::Children, &Self::Listeners) {
+        (self.children(), &self.listeners)
+    }
+    fn split_listeners(self) -> (Self::WithoutListeners, Self::Listeners) {
+        let BoxedNode { owner, node, children, listeners } = self;
+        (BoxedNode { owner, node, children, listeners: EmptyListeners }, listeners)
+    }
+    fn value(&self) -> DomValue<'_> {
+        unsafe { (*self.node).value() }
+    }
+    fn boxed_children(&self) -> Option<&Vec<BoxedNode<M>>> {
+        Some(self.children())
+    }
+}
+
+impl<M, L: Listeners<M>> fmt::Display for BoxedNode<M, L> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.displayable().fmt(formatter)
+    }
+}
+
+/// The listeners of a `BoxedNode`
+pub struct BoxedListeners<M> {
+    // Keeps the listeners alive after they have been split from their `BoxedNode`
+    _owner: Rc<dyn ErasedDomNode<M>>,
+    node: *const dyn ErasedDomNode<M>,
+    listeners: OnceCell<Vec<ErasedListener<M>>>,
+}
+
+impl<M> Listeners<M> for BoxedListeners<M> {
+    fn process_all<'a, P: ListenerProcessor<'a, M>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        self.listeners
+            .get_or_init(|| unsafe { (*self.node).erase_listeners() })
+            .process_all::<P>(acc)
+    }
+}
+
+/// A single type-erased listener of a `BoxedNode`
+pub struct ErasedListener<M>(*const dyn Listener<M>);
+
+impl<M> Listeners<M> for ErasedListener<M> {
+    fn process_all<'a, P: ListenerProcessor<'a, M>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<M> Listener<M> for ErasedListener<M> {
+    fn event_type_handled(&self) -> &'static str {
+        unsafe { (*self.0).event_type_handled() }
+    }
+    fn handle_event(&self, event: Event) -> M {
+        unsafe { (*self.0).handle_event(event) }
+    }
+}
+
+struct NodeEraser<M>(PhantomData<M>);
+impl<'a, M> DomNodeProcessor<'a, M> for NodeEraser<M> {
+    type Acc = (Rc<dyn ErasedDomNode<M>>, Vec<BoxedNode<M>>);
+    type Error = ();
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), Self::Error> {
+        fn erase_node<M, T: DomNode<M>>(
+            acc: &mut (Rc<dyn ErasedDomNode<M>>, Vec<BoxedNode<M>>),
+            node: &T) -> Result<(), ()>
+        {
+            // Extend the lifetime of the node (it is kept alive by `owner`) and convert it to a *const
+            let node = unsafe { mem::transmute::<&dyn ErasedDomNode<M>, _>(node) };
+            acc.1.push(BoxedNode::erased(acc.0.clone(), node));
+            Ok(())
+        }
+        erase_node
+    }
+}
+
+struct ListenerEraser<M>(PhantomData<M>);
+impl<'a, M> ListenerProcessor<'a, M> for ListenerEraser<M> {
+    type Acc = Vec<ErasedListener<M>>;
+    type Error = ();
+
+    fn get_processor<L: Listener<M>>() -> fn(&mut Self::Acc, &'a L) -> Result<(), Self::Error> {
+        fn erase_listener<M, L: Listener<M>>(
+            vec: &mut Vec<ErasedListener<M>>,
+            listener: &L) -> Result<(), ()>
+        {
+            // Extend the lifetime of the listener (it is kept alive by the node's owner)
+            // and convert it to a *const
+            vec.push(ErasedListener(unsafe { mem::transmute::<&dyn Listener<M>, _>(listener) }));
+            Ok(())
+        }
+        erase_listener
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoxedNode;
+    use {DomNode, DomValue, Event, KeyIter, Listener, Listeners};
+    use app::JsIo;
+    use listener::on;
+    use processors::{DomNodes, DomNodeProcessor, ListenerProcessor};
+    use tags::{button, div, li, span, ul};
+    use std::rc::Rc;
+    use test_app::TestApp;
+
+    struct CountElements;
+    impl<'a, M> DomNodeProcessor<'a, M> for CountElements {
+        type Acc = usize;
+        type Error = ();
+
+        fn get_processor<T: DomNode<M>>() -> fn(&mut usize, &'a T) -> Result<(), ()> {
+            fn count<M, T: DomNode<M>>(acc: &mut usize, node: &T) -> Result<(), ()> {
+                if let DomValue::Element { .. } = node.value() {
+                    *acc += 1;
+                }
+                node.children().process_all::<CountElements>(acc)
+            }
+            count
+        }
+    }
+
+    struct CallListeners;
+    impl<'a, M> ListenerProcessor<'a, M> for CallListeners {
+        type Acc = Vec<M>;
+        type Error = ();
+
+        fn get_processor<L: Listener<M>>() -> fn(&mut Vec<M>, &'a L) -> Result<(), ()> {
+            fn call<M, L: Listener<M>>(acc: &mut Vec<M>, listener: &L) -> Result<(), ()> {
+                acc.push(listener.handle_event(Event::default()));
+                Ok(())
+            }
+            call
+        }
+    }
+
+    #[test]
+    fn works_with_user_processors() {
+        let node: BoxedNode<()> = div((
+            span("a").boxed(),
+            ul(vec![li("b").boxed(), "c".boxed()]),
+        )).boxed();
+
+        let mut count = 0;
+        node.process_all::<CountElements>(&mut count).unwrap();
+        assert_eq!(4, count);
+        assert_eq!("<div><span>a</span><ul><li>b</li>c</ul></div>", node.to_string());
+    }
+
+    #[test]
+    fn nested_boxes_share_erased_children() {
+        let inner = ul(li("a").boxed().with_key(0)).boxed();
+        let outer: BoxedNode<()> = div(inner).boxed();
+
+        // Nodes reached through `outer` are erased by the innermost `BoxedNode` containing them
+        let list = &outer.children()[0];
+        let item = &list.children()[0];
+        let text = &item.children()[0];
+        assert!(!Rc::ptr_eq(&outer.owner, &item.owner));
+        assert!(!Rc::ptr_eq(&item.owner, &text.owner));
+        assert_eq!("<div><ul><li>a</li></ul></div>", outer.to_string());
+    }
+
+    #[test]
+    fn listeners_outlive_split_node() {
+        let node: BoxedNode<u32> = button((on("click", |_| 5), "Go")).boxed();
+        let (without_listeners, listeners) = node.split_listeners();
+        drop(without_listeners);
+
+        let mut messages = Vec::new();
+        listeners.process_all::<CallListeners>(&mut messages).unwrap();
+        assert_eq!(vec![5], messages);
+    }
+
+    #[test]
+    fn renders_and_dispatches_through_vdom() {
+        fn update(state: &mut Vec<usize>, msg: usize, _: KeyIter, _: &dyn JsIo<usize>) {
+            state.push(msg);
+        }
+        fn render(state: &[usize]) -> BoxedNode<usize> {
+            if state.is_empty() {
+                button((on("click", |_| 1), "Start")).boxed()
+            } else {
+                div(state.iter().map(|&n|
+                    button((on("click", move |_| n + 1), n.to_string())).boxed().with_key(n)
+                ).collect::<Vec<_>>()).boxed()
+            }
+        }
+
+        let mut app = TestApp::new(update, |state: &Vec<usize>| render(state), Vec::new());
+        app.click("button");
+        app.click("button");
+        assert_eq!(vec![1, 2], *app.state());
+        assert_eq!("<div><button>1</button><button>2</button></div>", app.document().to_string());
+    }
+}
@@ -0,0 +1,224 @@
+use app::{HttpResponseHandler, JsIo, OwnedHttpRequest};
+use storage::{self, StorageArea, StorageOperation};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::fmt;
+
+type CustomFn<Message> = Box<dyn FnOnce(&dyn JsIo<Message>)>;
+
+/// A description of side effects to be performed by the runtime after an update.
+///
+/// `Updater`s return a `Cmd` instead of performing effects themselves. This keeps updaters
+/// free of IO, so they can be unit tested by calling them directly and inspecting the
+/// returned `effects`. The browser runtime (`web_render::run`) performs the effects after
+/// re-rendering, and `TestApp` simulates them.
+///
+/// Commands can be combined using `Cmd::batch`. Updaters which return `()` produce `Cmd::none()`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::{KeyIter, Keys};
+/// use domafic::app::JsIo;
+/// use domafic::cmd::{Cmd, Effect};
+/// use domafic::storage::{StorageArea, StorageOperation};
+///
+/// enum Msg {
+///     Save(String),
+///     Saved,
+/// }
+///
+/// let update = |state: &mut Vec<String>, msg: Msg, _: KeyIter, _: &JsIo<Msg>| match msg {
+///     Msg::Save(item) => {
+///         state.push(item.clone());
+///         Cmd::batch(vec![
+///             Cmd::set_storage("last_item", item),
+///             Cmd::delay(1000, Msg::Saved),
+///         ])
+///     }
+///     Msg::Saved => Cmd::none(),
+/// };
+///
+/// # use domafic::app::{HttpRequest, HttpRequestHandle, HttpResponseHandler};
+/// # use domafic::app::{WebSocket, WebSocketHandler};
+/// # struct NoIo;
+/// # impl JsIo<Msg> for NoIo {
+/// #     fn http(&self, _: HttpRequest, _: Box<dyn HttpResponseHandler<Message=Msg>>)
+/// #         -> HttpRequestHandle { unimplemented!() }
+/// #     fn websocket(&self, _: &str, _: &[&str], _: Box<dyn WebSocketHandler<Message=Msg>>)
+/// #         -> WebSocket { unimplemented!() }
+/// # }
+/// let mut state = Vec::new();
+/// let keys = Keys::new().into_iter();
+/// let cmd = update(&mut state, Msg::Save("milk".to_string()), keys, &NoIo);
+/// match cmd.effects() {
+///     [Effect::Storage {
+///         area: StorageArea::Local,
+///         operation: StorageOperation::Set { key, value, .. },
+///     }, Effect::Delay { millis: 1000, .. }] => {
+///         assert_eq!(("last_item", "milk"), (key.as_str(), value.as_str()));
+///     }
+///     _ => panic!("unexpected effects"),
+/// }
+/// ```
+pub struct Cmd<Message> {
+    effects: Vec<Effect<Message>>,
+}
+
+/// A single side effect requested by a `Cmd`.
+pub enum Effect<Message> {
+    /// Issue an asynchronous HTTP request, passing the message produced by `handler` from the
+    /// response to the updater
+    Http {
+        /// The request to issue
+        request: OwnedHttpRequest,
+        /// Handler converting the response into a message
+        handler: Box<dyn HttpResponseHandler<Message=Message>>,
+    },
+    /// Pass `message` to the updater after `millis` milliseconds
+    Delay {
+        /// Number of milliseconds to wait
+        millis: u32,
+        /// Message to pass to the updater
+        message: Message,
+    },
+    /// Perform `operation` on the browser's `localStorage` or `sessionStorage`
+    Storage {
+        /// The storage area to operate on
+        area: StorageArea,
+        /// The operation to perform
+        operation: StorageOperation<Message>,
+    },
+    /// Change the URL to `url` without reloading the page, notifying `popstate` subscriptions
+    Navigate {
+        /// The new URL
+        url: String,
+        /// Whether to replace the current history entry instead of adding a new one
+        replace: bool,
+    },
+    /// Run arbitrary code with access to the `JsIo` interface
+    Custom(CustomFn<Message>),
+}
+
+impl<M> Cmd<M> {
+    /// A command which does nothing.
+    pub fn none() -> Cmd<M> {
+        Cmd { effects: Vec::new() }
+    }
+
+    /// Combines several commands into one, whose effects are performed in order.
+    pub fn batch<I: IntoIterator<Item=Cmd<M>>>(cmds: I) -> Cmd<M> {
+        Cmd { effects: cmds.into_iter().flat_map(|cmd| cmd.effects).collect() }
+    }
+
+    /// Issues an asynchronous HTTP request. The message produced by `handler` from the response
+    /// is passed to the updater.
+    ///
+    /// `request` may be an `HttpRequest` or an `OwnedHttpRequest`, such as one created using
+    /// `HttpRequest::json`. Use `JsIo::http` instead to get a handle which can abort the request.
+    pub fn http<Req, H>(request: Req, handler: H) -> Cmd<M>
+        where Req: Into<OwnedHttpRequest>, H: HttpResponseHandler<Message=M>
+    {
+        Cmd::from_effect(Effect::Http {
+            request: request.into(),
+            handler: Box::new(handler),
+        })
+    }
+
+    /// Passes `message` to the updater after `millis` milliseconds.
+    pub fn delay(millis: u32, message: M) -> Cmd<M> {
+        Cmd::from_effect(Effect::Delay { millis, message })
+    }
+
+    /// Stores `value` under `key` in the browser's `localStorage`.
+    /// See the `storage` module for other storage operations.
+    pub fn set_storage<K: Into<String>, V: Into<String>>(key: K, value: V) -> Cmd<M> {
+        storage::set(StorageArea::Local, key, value)
+    }
+
+    /// Removes `key` from the browser's `localStorage`.
+    pub fn remove_storage<K: Into<String>>(key: K) -> Cmd<M> {
+        storage::remove(StorageArea::Local, key)
+    }
+
+    /// Pushes `url` onto the browser's history without reloading the page.
+    pub fn navigate<U: Into<String>>(url: U) -> Cmd<M> {
+        Cmd::from_effect(Effect::Navigate { url: url.into(), replace: false })
+    }
+
+    /// Replaces the current entry of the browser's history with `url` without reloading the page.
+    pub fn replace_url<U: Into<String>>(url: U) -> Cmd<M> {
+        Cmd::from_effect(Effect::Navigate { url: url.into(), replace: true })
+    }
+
+    /// Runs `f` with access to the `JsIo` interface, for effects not covered by other commands.
+    pub fn custom<F: FnOnce(&dyn JsIo<M>) + 'static>(f: F) -> Cmd<M> {
+        Cmd::from_effect(Effect::Custom(Box::new(f)))
+    }
+
+    /// Creates a command performing a single `effect`.
+    pub fn from_effect(effect: Effect<M>) -> Cmd<M> {
+        Cmd { effects: vec![effect] }
+    }
+
+    /// Returns whether this command has no effects.
+    pub fn is_none(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Returns the effects of this command, in the order they will be performed.
+    pub fn effects(&self) -> &[Effect<M>] {
+        &self.effects
+    }
+
+    /// Converts this command into its effects.
+    pub fn into_effects(self) -> Vec<Effect<M>> {
+        self.effects
+    }
+}
+
+impl<M> Default for Cmd<M> {
+    fn default() -> Cmd<M> {
+        Cmd::none()
+    }
+}
+
+impl<M> From<()> for Cmd<M> {
+    fn from(_: ()) -> Cmd<M> {
+        Cmd::none()
+    }
+}
+
+impl<M> From<Effect<M>> for Cmd<M> {
+    fn from(effect: Effect<M>) -> Cmd<M> {
+        Cmd::from_effect(effect)
+    }
+}
+
+impl<M: fmt::Debug> fmt::Debug for Cmd<M> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_tuple("Cmd").field(&self.effects).finish()
+    }
+}
+
+impl<M: fmt::Debug> fmt::Debug for Effect<M> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Effect::Http { ref request, .. } =>
+                formatter.debug_struct("Http").field("request", request).finish(),
+            Effect::Delay { millis, ref message } => formatter.debug_struct("Delay")
+                .field("millis", &millis)
+                .field("message", message)
+                .finish(),
+            Effect::Storage { area, ref operation } => formatter.debug_struct("Storage")
+                .field("area", &area)
+                .field("operation", operation)
+                .finish(),
+            Effect::Navigate { ref url, replace } => formatter.debug_struct("Navigate")
+                .field("url", url)
+                .field("replace", &replace)
+                .finish(),
+            Effect::Custom(_) => formatter.write_str("Custom(..)"),
+        }
+    }
+}
@@ -1,7 +1,14 @@
 use processors::{DomNodes, DomNodeProcessor, Listeners, EmptyListeners};
 use KeyValue;
 
+use opt_std::fmt;
 use opt_std::marker::PhantomData;
+#[cfg(any(feature = "use_std", test))]
+use std::any::Any;
+#[cfg(any(feature = "use_std", test))]
+use std::hash::{Hash, Hasher};
+#[cfg(any(feature = "use_std", test))]
+use std::rc::Rc;
 
 /// A `DomNode` specifies the HTML DOM (Document Object Model) representation of a type.
 ///
@@ -36,15 +43,19 @@ pub trait DomNode<Message>: DomNodes<Message> + Sized {
             Listeners=EmptyListeners
             >;
 
-    /// If present, the key will be included in the `KeyStack` returned alongside a message.
+    /// If present, the key will be included in the `Keys` returned alongside a message.
     /// This should be used to differentiate messages from peer `DomNode`s.
-    fn key(&self) -> Option<u32>;
+    fn key(&self) -> Option<&Key>;
 
     /// Add a key to this `DomNode`. This method will panic if the node already has a key.
     ///
     /// Keys are used to differentiate between large numbers of similar components.
     /// When an event occurs in a keyed component, the keys of that component and all of its
-    /// parent components will be returned to the ""
+    /// parent components will be returned to the updater.
+    ///
+    /// A key can be an index, a string such as a slug, or any other hashable value such as a
+    /// database id (see `Key`). When re-rendering, elements are matched up with their previous
+    /// versions by their keys.
     ///
     /// Example:
     ///
@@ -68,15 +79,15 @@ pub trait DomNode<Message>: DomNodes<Message> + Sized {
     /// );
     ///
     /// let _update = |_state: &mut State, _msg: Clicked, mut keys: KeyIter|
-    ///    println!("div number {} was clicked", keys.next().unwrap());
+    ///    println!("div number {} was clicked", keys.next_index().unwrap());
     ///
     /// // If using in a browser:
     /// #[cfg(target_os = "emscripten")]
     /// run("body", _update, _render, ());
     /// ```
-    fn with_key(self, key: usize) -> WithKey<Message, Self> {
-        assert!(self.key() == None, "Attempted to add multiple keys to a DomNode");
-        WithKey(self, key as u32, PhantomData)
+    fn with_key<K: Into<Key>>(self, key: K) -> WithKey<Message, Self> {
+        assert!(self.key().is_none(), "Attempted to add multiple keys to a DomNode");
+        WithKey(self, key.into(), PhantomData)
     }
 
     /// Returns a wrapper that can displayed as HTML
@@ -85,6 +96,18 @@ pub trait DomNode<Message>: DomNodes<Message> + Sized {
         ::html_writer::HtmlDisplayable(self, PhantomData)
     }
 
+    /// Erases the type of this `DomNode` so that it can be stored alongside nodes of other types.
+    #[cfg(any(feature = "use_std", test))]
+    fn boxed(self) -> ::boxed_node::BoxedNode<Message> where Self: 'static {
+        ::boxed_node::BoxedNode::new(self)
+    }
+
+    /// Returns the already type-erased children of this node if it wraps a `BoxedNode`, so that
+    /// they can be shared instead of being erased again when this node is boxed.
+    #[cfg(any(feature = "use_std", test))]
+    #[doc(hidden)]
+    fn boxed_children(&self) -> Option<&Vec<::boxed_node::BoxedNode<Message>>> { None }
+
     /// Get the nth attribute for a given `DomNode`.
     ///
     /// If `node.get_attribute(i)` returns `None`, `node.get_attribute(j)` should return `None`
@@ -145,9 +168,38 @@ pub trait DomNode<Message>: DomNodes<Message> + Sized {
         }
     }
 
-    // TODO once type ATCs land
-    // type Mapped<Mapper: Map<In=Self::Message>>: DomNode<Message=Mapper::Out>
-    // fn map_listeners<Mapper: Map<In=Self::Message>>(self) -> Mapped<Mapper>
+    /// Wrap the `DomNode` so that the messages produced by its listeners (and by the listeners
+    /// of all of its children) are converted using `f`.
+    ///
+    /// This allows components with their own message types to be used as part of a larger
+    /// application. `f` can be any function, including an enum variant constructor or a closure
+    /// capturing the information needed to build the outer message.
+    ///
+    /// Example:
+    ///
+    ///```rust
+    /// use domafic::DomNode;
+    /// use domafic::listener::on;
+    /// use domafic::tags::button;
+    ///
+    /// enum CounterMsg { Increment }
+    /// enum AppMsg { Counter(CounterMsg), Child(usize, CounterMsg) }
+    ///
+    /// // `AppMsg::Counter` converts each `CounterMsg` into an `AppMsg`
+    /// let counter = button((on("click", |_| CounterMsg::Increment), "+"));
+    /// let _app_counter = counter.map_messages(AppMsg::Counter);
+    ///
+    /// // Closures may capture variables, such as the index of the child
+    /// let id = 3;
+    /// let counter = button((on("click", |_| CounterMsg::Increment), "+"));
+    /// let _child_counter = counter.map_messages(move |msg| AppMsg::Child(id, msg));
+    ///```
+    #[cfg(any(feature = "use_std", test))]
+    fn map_messages<Outer, F>(self, f: F) -> ::map_messages::MapMessages<Message, Outer, Self, F>
+        where F: Fn(Message) -> Outer
+    {
+        ::map_messages::MapMessages::new(self, f)
+    }
 
     /// Returns a reference to the children of this `DomNode`
     fn children(&self) -> &Self::Children;
@@ -167,6 +219,24 @@ pub trait DomNode<Message>: DomNodes<Message> + Sized {
     /// the node's text value.
     fn value(&self) -> DomValue;
 
+    /// Writes the `DomNode`'s HTML representation to `writer`.
+    ///
+    /// Unlike `write_html`, this is available without `std`.
+    fn write_html_fmt<W: ::opt_std::fmt::Write>(&self, writer: &mut W) -> ::opt_std::fmt::Result {
+        use html_fmt_writer::HtmlFmtWriter;
+        self.process_all::<HtmlFmtWriter<W>>(writer)
+    }
+
+    /// Writes the `DomNode`'s HTML representation to `writer`, laid out according to `format`.
+    fn write_html_formatted<W: ::opt_std::fmt::Write>(
+        &self,
+        writer: &mut W,
+        format: ::html_fmt_writer::HtmlFormat,
+    ) -> ::opt_std::fmt::Result {
+        use html_fmt_writer::{FormattedHtmlWriter, HtmlFormatter};
+        self.process_all::<FormattedHtmlWriter<W>>(&mut HtmlFormatter::new(writer, format))
+    }
+
     /// Writes the `DomNode`'s HTML representation to `writer`.
     #[cfg(any(feature = "use_std", test))]
     fn write_html<W: ::std::io::Write>(&self, writer: &mut W) -> ::std::io::Result<()> {
@@ -188,8 +258,151 @@ pub enum DomValue<'a> {
     Text(&'a str),
 }
 
+/// Key of a `DomNode`, identifying it among its peers.
+///
+/// Created from a `usize` index, a `u64` id (`Key::Int`), a string, or any other hashable
+/// value using `Key::value`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::Key;
+///
+/// #[derive(Debug, Hash, Eq, PartialEq)]
+/// struct UserId(u128);
+///
+/// assert_eq!(Some(3), Key::from(3).as_index());
+/// assert_eq!(Some("intro"), Key::from("intro").as_str());
+/// assert_eq!(Some(&UserId(7)), Key::value(UserId(7)).downcast_ref::<UserId>());
+/// assert!(Key::value(UserId(7)) != Key::value(7u128));
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Key {
+    /// An integer, such as an index into a list or a numeric id
+    Int(u64),
+
+    /// A string represented by a static string reference
+    Str(&'static str),
+
+    /// A string represented by an owned, reference-counted `str`, such as a slug
+    #[cfg(any(feature = "use_std", test))]
+    OwnedStr(Rc<str>),
+
+    /// Any other hashable value, such as a UUID. Created using `Key::value`.
+    #[cfg(any(feature = "use_std", test))]
+    Value(KeyValueBox),
+}
+
+impl Key {
+    /// Creates a `Key` from any hashable value, which can be recovered using `downcast_ref`.
+    ///
+    /// Values of different types are never equal.
+    #[cfg(any(feature = "use_std", test))]
+    pub fn value<T: Hash + Eq + fmt::Debug + 'static>(value: T) -> Key {
+        Key::Value(KeyValueBox(Rc::new(value)))
+    }
+
+    /// Returns the key as an index, if it is an integer.
+    pub fn as_index(&self) -> Option<usize> {
+        match *self {
+            Key::Int(value) => Some(value as usize),
+            _ => None,
+        }
+    }
+
+    /// Returns the key as a string, if it is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Key::Str(value) => Some(value),
+            #[cfg(any(feature = "use_std", test))]
+            Key::OwnedStr(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the value the key was created from using `Key::value`, if it has
+    /// type `T`.
+    #[cfg(any(feature = "use_std", test))]
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        match *self {
+            Key::Value(ref value) => value.0.as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<usize> for Key {
+    fn from(index: usize) -> Key {
+        Key::Int(index as u64)
+    }
+}
+
+impl From<&'static str> for Key {
+    fn from(value: &'static str) -> Key {
+        Key::Str(value)
+    }
+}
+
+#[cfg(any(feature = "use_std", test))]
+impl From<String> for Key {
+    fn from(value: String) -> Key {
+        Key::OwnedStr(value.into())
+    }
+}
+
+/// Object-safe operations on the values of `Key::Value`
+#[cfg(any(feature = "use_std", test))]
+trait AnyKey: fmt::Debug {
+    fn as_any(&self) -> &dyn Any;
+    fn eq_key(&self, other: &dyn AnyKey) -> bool;
+    fn hash_key(&self, state: &mut dyn Hasher);
+}
+
+#[cfg(any(feature = "use_std", test))]
+impl<T: Hash + Eq + fmt::Debug + 'static> AnyKey for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn eq_key(&self, other: &dyn AnyKey) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+    fn hash_key(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+/// A hashable value of any type, stored in a `Key::Value`
+#[cfg(any(feature = "use_std", test))]
+#[derive(Clone)]
+pub struct KeyValueBox(Rc<dyn AnyKey>);
+
+#[cfg(any(feature = "use_std", test))]
+impl PartialEq for KeyValueBox {
+    fn eq(&self, other: &KeyValueBox) -> bool {
+        self.0.eq_key(&*other.0)
+    }
+}
+
+#[cfg(any(feature = "use_std", test))]
+impl Eq for KeyValueBox {}
+
+#[cfg(any(feature = "use_std", test))]
+impl Hash for KeyValueBox {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_any().type_id().hash(state);
+        self.0.hash_key(state);
+    }
+}
+
+#[cfg(any(feature = "use_std", test))]
+impl fmt::Debug for KeyValueBox {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(formatter)
+    }
+}
+
 /// A `DomNode` with a key
-pub struct WithKey<M, T: DomNode<M>>(T, u32, PhantomData<M>);
+pub struct WithKey<M, T: DomNode<M>>(T, Key, PhantomData<M>);
 impl<M, T: DomNode<M>> DomNodes<M> for WithKey<M, T> {
     fn process_all<'a, P: DomNodeProcessor<'a, M>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
         P::get_processor()(acc, self)
@@ -200,7 +413,7 @@ impl<M, T: DomNode<M>> DomNode<M> for WithKey<M, T> {
     type Listeners = T::Listeners;
     type WithoutListeners = WithKey<M, T::WithoutListeners>;
 
-    fn key(&self) -> Option<u32> { Some(self.1) }
+    fn key(&self) -> Option<&Key> { Some(&self.1) }
     fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
         self.0.get_attribute(index)
     }
@@ -218,6 +431,10 @@ impl<M, T: DomNode<M>> DomNode<M> for WithKey<M, T> {
         (WithKey(node, self.1, PhantomData), listeners)
     }
     fn value(&self) -> DomValue { self.0.value() }
+    #[cfg(any(feature = "use_std", test))]
+    fn boxed_children(&self) -> Option<&Vec<::boxed_node::BoxedNode<M>>> {
+        self.0.boxed_children()
+    }
 }
 
 /// Wrapper for `DomNode`s that adds attributes.
@@ -235,7 +452,7 @@ impl<M, T, A> DomNode<M> for WithAttributes<M, T, A> where T: DomNode<M>, A: AsR
     type Children = T::Children;
     type Listeners = T::Listeners;
     type WithoutListeners = WithAttributes<M, T::WithoutListeners, A>;
-    fn key(&self) -> Option<u32> { self.node.key() }
+    fn key(&self) -> Option<&Key> { self.node.key() }
     fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
         let attributes = self.attributes.as_ref();
         attributes
@@ -282,7 +499,7 @@ impl<M, T, L> DomNode<M> for WithListeners<M, T, L>
     type Children = T::Children;
     type Listeners = L;
     type WithoutListeners = T;
-    fn key(&self) -> Option<u32> { self.node.key() }
+    fn key(&self) -> Option<&Key> { self.node.key() }
     fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
         self.node.get_attribute(index)
     }
@@ -299,6 +516,10 @@ impl<M, T, L> DomNode<M> for WithListeners<M, T, L>
         (self.node, self.listeners)
     }
     fn value(&self) -> DomValue { self.node.value() }
+    #[cfg(any(feature = "use_std", test))]
+    fn boxed_children(&self) -> Option<&Vec<::boxed_node::BoxedNode<M>>> {
+        self.node.boxed_children()
+    }
 }
 
 /// Iterator over the attributes of a `DomNode`
@@ -331,7 +552,7 @@ impl<M> DomNode<M> for String {
     type Children = ();
     type Listeners = EmptyListeners;
     type WithoutListeners = String;
-    fn key(&self) -> Option<u32> { None }
+    fn key(&self) -> Option<&Key> { None }
     fn get_attribute(&self, _index: usize) -> Option<&KeyValue> {
         None
     }
@@ -359,7 +580,7 @@ impl<'a, M> DomNode<M> for &'a str {
     type Children = ();
     type Listeners = EmptyListeners;
     type WithoutListeners = Self;
-    fn key(&self) -> Option<u32> { None }
+    fn key(&self) -> Option<&Key> { None }
     fn get_attribute(&self, _index: usize) -> Option<&KeyValue> { None }
     fn children(&self) -> &Self::Children {
         EMPTY_NODES_REF
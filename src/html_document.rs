@@ -0,0 +1,304 @@
+use {AttributeValue, DomNode, DomNodes, DomValue, Key, KeyValue};
+use html_fmt_writer::HtmlFormat;
+use processors::{DomNodeProcessor, EmptyListeners};
+use tags::{attributes, body, head, html, link, meta, script, title};
+use AttributeValue::Str;
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Description of a page's metadata: its title, language, charset, `meta` tags, stylesheets
+/// and scripts.
+///
+/// A `Head` is written to the `<head>` of an `HtmlDocument` on the server. When rendering in the
+/// browser, a renderer created with `app::with_head` applies the `Head` for the current state to
+/// the page after every render, adding, updating and removing the elements it describes.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::html_document::Head;
+///
+/// let head = Head::new()
+///     .title("Todos (3)")
+///     .lang("en")
+///     .charset("utf-8")
+///     .meta("viewport", "width=device-width")
+///     .stylesheet("/style.css")
+///     .script("/app.js");
+/// assert_eq!(Some("Todos (3)"), head.title.as_ref().map(String::as_str));
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Head {
+    /// Contents of the `<title>` element
+    pub title: Option<String>,
+    /// Value of the `lang` attribute of the `<html>` element
+    pub lang: Option<String>,
+    /// Character encoding declared by `<meta charset="...">`.
+    ///
+    /// The charset can't be changed once a page has loaded, so it is only used when writing HTML.
+    pub charset: Option<String>,
+    /// `(name, content)` pairs of `<meta>` elements
+    pub meta: Vec<(String, String)>,
+    /// URLs of stylesheets included with `<link rel="stylesheet">`
+    pub stylesheets: Vec<String>,
+    /// URLs of scripts included with `<script src="...">`
+    pub scripts: Vec<String>,
+}
+
+impl Head {
+    /// Creates an empty `Head`.
+    pub fn new() -> Head {
+        Head::default()
+    }
+
+    /// Sets the title of the page.
+    pub fn title<T: Into<String>>(mut self, title: T) -> Head {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Sets the language of the page.
+    pub fn lang<T: Into<String>>(mut self, lang: T) -> Head {
+        self.lang = Some(lang.into());
+        self
+    }
+
+    /// Sets the character encoding of the page.
+    pub fn charset<T: Into<String>>(mut self, charset: T) -> Head {
+        self.charset = Some(charset.into());
+        self
+    }
+
+    /// Adds a `<meta name="..." content="...">` element.
+    pub fn meta<N: Into<String>, C: Into<String>>(mut self, name: N, content: C) -> Head {
+        self.meta.push((name.into(), content.into()));
+        self
+    }
+
+    /// Adds a stylesheet.
+    pub fn stylesheet<T: Into<String>>(mut self, href: T) -> Head {
+        self.stylesheets.push(href.into());
+        self
+    }
+
+    /// Adds a script.
+    pub fn script<T: Into<String>>(mut self, src: T) -> Head {
+        self.scripts.push(src.into());
+        self
+    }
+}
+
+/// `id` of the `<script>` element created by `state_script`.
+pub const STATE_SCRIPT_ID: &str = "domafic-state";
+
+/// Creates a `<script type="application/json">` element holding `json`, the serialized initial
+/// state of an application, so that the client can pick up where the server left off.
+///
+/// The `<`, `>` and `&` characters in `json` are replaced by their `\u` escapes, which are
+/// equivalent inside of JSON strings and keep the contents from being interpreted as HTML.
+/// On the client, read the state back using `web_render::read_initial_state` before calling
+/// `run` or `hydrate`.
+///
+/// `HtmlDocument::with_state` includes this element in the page's `<head>`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::DomNode;
+/// use domafic::html_document::state_script;
+///
+/// let state = state_script::<()>(r#"{"todos":["</script>"]}"#);
+/// assert_eq!(
+///     r#"<script type="application/json" id="domafic-state">{"todos":["\u003c/script\u003e"]}</script>"#,
+///     state.displayable().to_string()
+/// );
+/// ```
+pub fn state_script<M>(json: &str) -> impl DomNode<M> {
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        match c {
+            '<' => escaped.push_str("\\u003c"),
+            '>' => escaped.push_str("\\u003e"),
+            '&' => escaped.push_str("\\u0026"),
+            c => escaped.push(c),
+        }
+    }
+    script((
+        attributes([("type", Str("application/json")), ("id", Str(STATE_SCRIPT_ID))]),
+        escaped,
+    ))
+}
+
+/// A complete HTML page, consisting of a `Head` and a `DomNode` rendered inside of `<body>`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::html_document::{Head, HtmlDocument};
+/// use domafic::tags::h1;
+///
+/// let page = HtmlDocument::<(), _>::new(Head::new().title("Hello").lang("en"), h1("Hello!"));
+/// assert_eq!(
+///     "<!DOCTYPE html><html lang=\"en\"><head><title>Hello</title></head>\
+///     <body><h1>Hello!</h1></body></html>",
+///     page.to_string()
+/// );
+/// ```
+pub struct HtmlDocument<M, D: DomNode<M>> {
+    /// Metadata of the page
+    pub head: Head,
+    /// Contents of the `<body>` element
+    pub body: D,
+    /// Serialized initial state of the application, written using `state_script`
+    pub state: Option<String>,
+    _marker: PhantomData<M>,
+}
+
+impl<M, D: DomNode<M>> HtmlDocument<M, D> {
+    /// Creates a new `HtmlDocument` from a `Head` and the contents of the page's `<body>`.
+    pub fn new(head: Head, body: D) -> HtmlDocument<M, D> {
+        HtmlDocument { head, body, state: None, _marker: PhantomData }
+    }
+
+    /// Embeds `json`, the serialized initial state of the application, in the page.
+    ///
+    /// The state is written to the `<head>` before any of its scripts, so it can be read using
+    /// `web_render::read_initial_state` as soon as the application starts.
+    pub fn with_state<T: Into<String>>(mut self, json: T) -> HtmlDocument<M, D> {
+        self.state = Some(json.into());
+        self
+    }
+
+    /// Writes the page's HTML to `writer`, laid out according to `format`.
+    pub fn write_html_formatted<W: fmt::Write>(&self, writer: &mut W, format: HtmlFormat)
+        -> fmt::Result
+    {
+        writer.write_str("<!DOCTYPE html>")?;
+        if format.indent.is_some() {
+            writer.write_str(format.newline)?;
+        }
+
+        let owned = |value: &String| AttributeValue::OwnedStr(value.clone());
+        let page = html::<M, _, _, _, _>((
+            attributes(self.head.lang.iter()
+                .map(|lang| ("lang", owned(lang)))
+                .collect::<Vec<KeyValue>>()),
+            (
+                head((
+                    self.head.charset.as_ref().map(|charset|
+                        meta(attributes([("charset", owned(charset))]))),
+                    self.head.title.as_ref().map(|text| title(text.as_str())),
+                    self.head.meta.iter().map(|(name, content)|
+                        meta(attributes([("name", owned(name)), ("content", owned(content))]))
+                    ).collect::<Vec<_>>(),
+                    self.head.stylesheets.iter().map(|href| link(attributes([
+                        ("rel", Str("stylesheet")),
+                        ("href", owned(href)),
+                    ]))).collect::<Vec<_>>(),
+                    self.state.as_ref().map(|json| state_script(json)),
+                    self.head.scripts.iter().map(|src|
+                        script(attributes([("src", owned(src))]))
+                    ).collect::<Vec<_>>(),
+                )),
+                body(NodeRef(&self.body, PhantomData)),
+            ),
+        ));
+        page.write_html_formatted(writer, format)
+    }
+}
+
+impl<M, D: DomNode<M>> fmt::Display for HtmlDocument<M, D> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.write_html_formatted(formatter, HtmlFormat::compact())
+    }
+}
+
+/// `DomNode` which borrows another `DomNode`, hiding its listeners
+struct NodeRef<'a, M, T: DomNode<M> + 'a>(&'a T, PhantomData<M>);
+
+static EMPTY_LISTENERS: EmptyListeners = EmptyListeners;
+
+impl<'a, M, T: DomNode<M>> DomNodes<M> for NodeRef<'a, M, T> {
+    fn process_all<'b, P: DomNodeProcessor<'b, M>>(&'b self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<'a, M, T: DomNode<M>> DomNode<M> for NodeRef<'a, M, T> {
+    type Children = T::Children;
+    type Listeners = EmptyListeners;
+    type WithoutListeners = Self;
+
+    fn key(&self) -> Option<&Key> { self.0.key() }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> { self.0.get_attribute(index) }
+    fn children(&self) -> &Self::Children { self.0.children() }
+    fn listeners(&self) -> &Self::Listeners { &EMPTY_LISTENERS }
+    fn children_and_listeners(&self) -> (&Self::Children, &Self::Listeners) {
+        (self.0.children(), &EMPTY_LISTENERS)
+    }
+    fn split_listeners(self) -> (Self::WithoutListeners, Self::Listeners) {
+        (self, EmptyListeners)
+    }
+    fn value(&self) -> DomValue<'_> { self.0.value() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Head, HtmlDocument};
+    use html_fmt_writer::HtmlFormat;
+    use listener::on;
+    use tags::{button, div, h1};
+
+    #[test]
+    fn writes_complete_pretty_document() {
+        let head = Head::new()
+            .title("<Todos>")
+            .lang("en")
+            .charset("utf-8")
+            .meta("description", "A \"todo\" app")
+            .stylesheet("/style.css")
+            .script("/app.js");
+        let body = div((h1("Todos"), button((on("click", |_| ()), "Add"))));
+        let page = HtmlDocument::new(head, body);
+
+        let mut html = String::new();
+        page.write_html_formatted(&mut html, HtmlFormat::pretty()).unwrap();
+        assert_eq!(html, r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>&lt;Todos&gt;</title>
+    <meta name="description" content="A &quot;todo&quot; app">
+    <link rel="stylesheet" href="/style.css">
+    <script src="/app.js"></script>
+  </head>
+  <body>
+    <div>
+      <h1>Todos</h1>
+      <button>Add</button>
+    </div>
+  </body>
+</html>"#);
+    }
+
+    #[test]
+    fn writes_state_before_scripts() {
+        let page = HtmlDocument::<(), _>::new(Head::new().script("/app.js"), "text")
+            .with_state(r#"{"entry":"</script><!-- & >"}"#);
+        assert_eq!(
+            "<!DOCTYPE html><html><head>\
+            <script type=\"application/json\" id=\"domafic-state\">\
+            {\"entry\":\"\\u003c/script\\u003e\\u003c!-- \\u0026 \\u003e\"}</script>\
+            <script src=\"/app.js\"></script></head><body>text</body></html>",
+            page.to_string()
+        );
+    }
+
+    #[test]
+    fn writes_empty_head() {
+        let page = HtmlDocument::<(), _>::new(Head::new(), "text");
+        assert_eq!("<!DOCTYPE html><html><head></head><body>text</body></html>", page.to_string());
+    }
+}
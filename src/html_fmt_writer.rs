@@ -0,0 +1,464 @@
+use {AttributeValue, DomNode, DomNodes, DomValue, KeyValue};
+use processors::DomNodeProcessor;
+use tags::{is_inline_element, is_raw_text_element, is_void_element};
+
+use opt_std::fmt;
+use opt_std::marker::PhantomData;
+
+/// Type to use for processing a `DomNode` tree and writing it as HTML to a `core::fmt::Write`.
+///
+/// Unlike `HtmlWriter`, this writer doesn't require `std`, and it streams output directly into
+/// the writer without any intermediate allocations. The output is identical to `HtmlWriter`.
+///
+/// This type should not ever need to be instantiated. Instead, simply
+/// name the type in calls to `DomNodes::process_all::<HtmlFmtWriter<...>>(...)`
+/// or use `DomNode::write_html_fmt`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct HtmlFmtWriter<W: fmt::Write>(PhantomData<W>);
+impl<'a, M, W: fmt::Write> DomNodeProcessor<'a, M> for HtmlFmtWriter<W> {
+    type Acc = W;
+    type Error = fmt::Error;
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &T) -> Result<(), Self::Error> {
+        fn add_node<M, W, T>(w: &mut W, node: &T) -> Result<(), fmt::Error>
+                where W: fmt::Write, T: DomNode<M> {
+            match node.value() {
+                DomValue::Element { tag: tagname } => {
+                    write!(w, "<{}", tagname)?;
+                    for attr in node.attributes() {
+                        write!(w, "{}", HtmlAttribute(attr))?;
+                    }
+                    w.write_str(">")?;
+                    if is_void_element(tagname) {
+                        return Ok(());
+                    }
+                    if is_raw_text_element(tagname) {
+                        node.children().process_all::<RawTextFmtWriter<W>>(w)?;
+                    } else {
+                        node.children().process_all::<HtmlFmtWriter<W>>(w)?;
+                    }
+                    write!(w, "</{}>", tagname)
+                }
+                DomValue::Text(text) => write!(w, "{}", Escaped(text)),
+            }
+        }
+        add_node
+    }
+}
+
+/// Writes the contents of a raw text element, using `RawText` instead of `Escaped` for text
+struct RawTextFmtWriter<W: fmt::Write>(PhantomData<W>);
+impl<'a, M, W: fmt::Write> DomNodeProcessor<'a, M> for RawTextFmtWriter<W> {
+    type Acc = W;
+    type Error = fmt::Error;
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &T) -> Result<(), Self::Error> {
+        fn add_node<M, W, T>(w: &mut W, node: &T) -> Result<(), fmt::Error>
+                where W: fmt::Write, T: DomNode<M> {
+            match node.value() {
+                DomValue::Text(text) => write!(w, "{}", RawText(text)),
+                DomValue::Element { .. } => HtmlFmtWriter::<W>::get_processor::<T>()(w, node),
+            }
+        }
+        add_node
+    }
+}
+
+/// Wrapper which displays a string with the HTML special characters (`&`, `<`, `>`, `"`
+/// and `'`) escaped, making it safe to use as both text and attribute values.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Escaped<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Escaped<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut unwritten = self.0;
+        while let Some(index) = unwritten.find(['&', '<', '>', '"', '\'']) {
+            formatter.write_str(&unwritten[..index])?;
+            formatter.write_str(match unwritten.as_bytes()[index] {
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                b'"' => "&quot;",
+                _ => "&#39;",
+            })?;
+            unwritten = &unwritten[index + 1..];
+        }
+        formatter.write_str(unwritten)
+    }
+}
+
+/// Wrapper which displays the text of a raw text element (`script` or `style`).
+///
+/// Raw text isn't escaped, since the browser doesn't decode character references inside of these
+/// elements. To keep the text from closing the element early, `</` is written as `<\/` and
+/// `<!--` as `<\!--`, which mean the same thing inside of JavaScript strings and regular
+/// expressions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RawText<'a>(pub &'a str);
+
+impl<'a> fmt::Display for RawText<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut unwritten = self.0;
+        while let Some(index) = unwritten.find('<') {
+            formatter.write_str(&unwritten[..index + 1])?;
+            unwritten = &unwritten[index + 1..];
+            if unwritten.starts_with('/') || unwritten.starts_with("!--") {
+                formatter.write_str("\\")?;
+            }
+        }
+        formatter.write_str(unwritten)
+    }
+}
+
+/// Wrapper which displays an attribute as it appears inside of an HTML start tag, including
+/// the leading space.
+///
+/// Following HTML5, `Bool(true)` attributes are displayed as just the attribute name, and
+/// `Bool(false)` attributes aren't displayed at all.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HtmlAttribute<'a>(pub &'a KeyValue);
+
+impl<'a> fmt::Display for HtmlAttribute<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match (self.0).1 {
+            AttributeValue::Bool(false) => Ok(()),
+            AttributeValue::Bool(true) => write!(formatter, " {}", (self.0).0),
+            ref value => write!(formatter, " {}=\"{}\"", (self.0).0, Escaped(value.as_str())),
+        }
+    }
+}
+
+/// Layout options for `FormattedHtmlWriter`.
+///
+/// `HtmlFormat::compact()` (the default) produces the same output as `HtmlFmtWriter`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::DomNode;
+/// use domafic::html_fmt_writer::HtmlFormat;
+/// use domafic::tags::{b, div, h1, p};
+///
+/// let node = div((h1("Title"), p(("Some ", b("bold"), " text"))));
+///
+/// let mut html = String::new();
+/// DomNode::<()>::write_html_formatted(&node, &mut html, HtmlFormat::pretty()).unwrap();
+/// assert_eq!(html, "\
+/// <div>
+///   <h1>Title</h1>
+///   <p>Some <b>bold</b> text</p>
+/// </div>");
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HtmlFormat {
+    /// Number of spaces to indent by for each level of nested block elements.
+    ///
+    /// If `None`, no newlines or indentation are added to the output.
+    /// Inline elements (such as `span` or `b`) and text are never broken onto separate lines,
+    /// and the contents of whitespace-preserving elements (`pre`, `textarea`, `script` and
+    /// `style`) are written exactly as-is.
+    pub indent: Option<usize>,
+
+    /// Line separator used when `indent` is `Some`, such as `"\n"` or `"\r\n"`
+    pub newline: &'static str,
+
+    /// Whether to collapse each run of whitespace in text nodes to a single space.
+    ///
+    /// Text inside of whitespace-preserving elements is never collapsed.
+    pub collapse_whitespace: bool,
+}
+
+impl HtmlFormat {
+    /// Writes HTML exactly as it was rendered, on a single line.
+    pub fn compact() -> HtmlFormat {
+        HtmlFormat { indent: None, newline: "\n", collapse_whitespace: false }
+    }
+
+    /// Writes each block element on its own line, indented by two spaces per level.
+    pub fn pretty() -> HtmlFormat {
+        HtmlFormat { indent: Some(2), newline: "\n", collapse_whitespace: false }
+    }
+
+    /// Writes HTML on a single line, collapsing runs of whitespace in text.
+    pub fn minified() -> HtmlFormat {
+        HtmlFormat { indent: None, newline: "\n", collapse_whitespace: true }
+    }
+}
+
+impl Default for HtmlFormat {
+    fn default() -> HtmlFormat {
+        HtmlFormat::compact()
+    }
+}
+
+/// Returns whether the whitespace in the contents of `tagname` is significant.
+fn preserves_whitespace(tagname: &str) -> bool {
+    matches!(tagname, "pre" | "textarea" | "script" | "style")
+}
+
+/// State used by `FormattedHtmlWriter` while writing a `DomNode` tree
+pub struct HtmlFormatter<'w, W: fmt::Write + 'w> {
+    writer: &'w mut W,
+    format: HtmlFormat,
+    depth: usize,
+    // Whether the current element's children are each placed on their own lines
+    block_layout: bool,
+    // Whether the current line holds inline content that following inline content should join
+    in_inline_run: bool,
+    // Whether anything has been written yet
+    started: bool,
+}
+
+impl<'w, W: fmt::Write> HtmlFormatter<'w, W> {
+    /// Creates a new `HtmlFormatter` which writes to `writer` using `format`.
+    pub fn new(writer: &'w mut W, format: HtmlFormat) -> HtmlFormatter<'w, W> {
+        HtmlFormatter {
+            writer,
+            block_layout: format.indent.is_some(),
+            format,
+            depth: 0,
+            in_inline_run: false,
+            started: false,
+        }
+    }
+
+    fn start_line(&mut self) -> Result<(), fmt::Error> {
+        if self.started {
+            self.writer.write_str(self.format.newline)?;
+            let indent = self.format.indent.unwrap_or(0) * self.depth;
+            for _ in 0..indent {
+                self.writer.write_char(' ')?;
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    fn write_text(&mut self, text: &str) -> Result<(), fmt::Error> {
+        if self.format.collapse_whitespace {
+            let mut words = text.split(|c: char| c.is_whitespace());
+            if let Some(first) = words.next() {
+                write!(self.writer, "{}", Escaped(first))?;
+            }
+            let mut after_space = false;
+            for word in words {
+                if !after_space {
+                    self.writer.write_char(' ')?;
+                }
+                after_space = word.is_empty();
+                write!(self.writer, "{}", Escaped(word))?;
+            }
+            Ok(())
+        } else {
+            write!(self.writer, "{}", Escaped(text))
+        }
+    }
+}
+
+/// Type to use for processing a `DomNode` tree and writing it as HTML using an `HtmlFormat`.
+///
+/// This type should not ever need to be instantiated. Instead, simply
+/// name the type in calls to `DomNodes::process_all::<FormattedHtmlWriter<...>>(...)`
+/// or use `DomNode::write_html_formatted`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FormattedHtmlWriter<'w, W: fmt::Write + 'w>(PhantomData<&'w mut W>);
+impl<'a, 'w, M, W: fmt::Write> DomNodeProcessor<'a, M> for FormattedHtmlWriter<'w, W> {
+    type Acc = HtmlFormatter<'w, W>;
+    type Error = fmt::Error;
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &T) -> Result<(), Self::Error> {
+        fn add_node<'w, M, W, T>(f: &mut HtmlFormatter<'w, W>, node: &T) -> Result<(), fmt::Error>
+                where W: fmt::Write, T: DomNode<M> {
+            let tagname = match node.value() {
+                DomValue::Text(text) => {
+                    if f.block_layout {
+                        // Whitespace between blocks is replaced by the formatting
+                        if text.trim().is_empty() {
+                            return Ok(());
+                        }
+                        if !f.in_inline_run {
+                            f.start_line()?;
+                            f.in_inline_run = true;
+                        }
+                    }
+                    f.started = true;
+                    return f.write_text(text);
+                }
+                DomValue::Element { tag } => tag,
+            };
+
+            let is_block = !is_inline_element(tagname);
+            if f.block_layout && (is_block || !f.in_inline_run) {
+                f.start_line()?;
+            }
+            f.in_inline_run = !is_block;
+            f.started = true;
+
+            write!(f.writer, "<{}", tagname)?;
+            for attr in node.attributes() {
+                write!(f.writer, "{}", HtmlAttribute(attr))?;
+            }
+            f.writer.write_str(">")?;
+            if is_void_element(tagname) {
+                return Ok(());
+            }
+
+            if is_raw_text_element(tagname) {
+                node.children().process_all::<RawTextFmtWriter<W>>(f.writer)?;
+            } else if preserves_whitespace(tagname) {
+                node.children().process_all::<HtmlFmtWriter<W>>(f.writer)?;
+            } else {
+                let (block_layout, in_inline_run) = (f.block_layout, f.in_inline_run);
+                let children_are_blocks = f.format.indent.is_some() && {
+                    let mut has_block = false;
+                    node.children().process_all::<BlockFinder>(&mut has_block)?;
+                    has_block
+                };
+
+                f.block_layout = children_are_blocks;
+                f.in_inline_run = false;
+                f.depth += 1;
+                node.children().process_all::<FormattedHtmlWriter<W>>(f)?;
+                f.depth -= 1;
+                if children_are_blocks {
+                    f.start_line()?;
+                }
+                f.block_layout = block_layout;
+                f.in_inline_run = in_inline_run;
+            }
+            write!(f.writer, "</{}>", tagname)
+        }
+        add_node
+    }
+}
+
+/// Checks whether any of a collection of `DomNode`s is a block element
+struct BlockFinder;
+impl<'a, M> DomNodeProcessor<'a, M> for BlockFinder {
+    type Acc = bool;
+    type Error = fmt::Error;
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut bool, &T) -> Result<(), fmt::Error> {
+        fn is_block<M, T: DomNode<M>>(has_block: &mut bool, node: &T) -> Result<(), fmt::Error> {
+            if let DomValue::Element { tag } = node.value() {
+                *has_block |= !is_inline_element(tag);
+            }
+            Ok(())
+        }
+        is_block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Escaped, HtmlFormat, RawText};
+    use DomNode;
+    use AttributeValue::Str;
+    use tags::{attributes, br, div, li, p, pre, script, span, style, textarea, ul};
+
+    use std::fmt;
+
+    /// Fixed-capacity writer, standing in for the buffers used in `no_std` environments
+    struct ArrayWriter {
+        buf: [u8; 64],
+        len: usize,
+    }
+
+    impl fmt::Write for ArrayWriter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!("a &amp; b &lt;c&gt; &quot;d&quot; &#39;e&#39; ü",
+            Escaped("a & b <c> \"d\" 'e' ü").to_string());
+        assert_eq!("", Escaped("").to_string());
+    }
+
+    #[test]
+    fn writes_raw_text_elements() {
+        assert_eq!("a <\\/b> <\\!-- <c", RawText("a </b> <!-- <c").to_string());
+
+        let node = div((
+            script("if (a < b && c) { s = \"</script>\"; }"),
+            style("a > b { content: '&'; }"),
+        ));
+        let expected = "<div><script>if (a < b && c) { s = \"<\\/script>\"; }</script>\
+            <style>a > b { content: '&'; }</style></div>";
+        assert_eq!(expected, DomNode::<()>::displayable(&node).to_string());
+
+        let mut html = String::new();
+        DomNode::<()>::write_html_formatted(&node, &mut html, HtmlFormat::minified()).unwrap();
+        assert_eq!(expected, html);
+    }
+
+    #[test]
+    fn writes_into_fixed_buffers() {
+        let node = div((attributes([("class", Str("x&y"))]), p("1 < 2")));
+        let mut writer = ArrayWriter { buf: [0; 64], len: 0 };
+        DomNode::<()>::write_html_fmt(&node, &mut writer).unwrap();
+        assert_eq!(
+            "<div class=\"x&amp;y\"><p>1 &lt; 2</p></div>",
+            ::std::str::from_utf8(&writer.buf[..writer.len]).unwrap()
+        );
+
+        let mut small_writer = ArrayWriter { buf: [0; 64], len: 60 };
+        assert!(DomNode::<()>::write_html_fmt(&node, &mut small_writer).is_err());
+    }
+
+    fn write_formatted<T: DomNode<()>>(node: &T, format: HtmlFormat) -> String {
+        let mut string = String::new();
+        node.write_html_formatted(&mut string, format).unwrap();
+        string
+    }
+
+    fn sample() -> impl DomNode<()> {
+        div((
+            p(("  Some   ", span("spaced\n  out"), " text ")),
+            "\n  ",
+            ul((li("one"), li(("two", br(()), "lines")))),
+            pre("  keep\n    this  "),
+            textarea(" and  this"),
+        ))
+    }
+
+    #[test]
+    fn compact_format_matches_unformatted_output() {
+        assert_eq!(sample().displayable().to_string(), write_formatted(&sample(), HtmlFormat::compact()));
+    }
+
+    #[test]
+    fn pretty_prints_block_elements() {
+        let crlf = HtmlFormat { newline: "\r\n", ..HtmlFormat::pretty() };
+        assert_eq!(
+            "<div>\r\n\
+            \x20 <p>  Some   <span>spaced\n  out</span> text </p>\r\n\
+            \x20 <ul>\r\n\
+            \x20   <li>one</li>\r\n\
+            \x20   <li>two<br>lines</li>\r\n\
+            \x20 </ul>\r\n\
+            \x20 <pre>  keep\n    this  </pre>\r\n\
+            \x20 <textarea> and  this</textarea>\r\n\
+            </div>",
+            write_formatted(&sample(), crlf)
+        );
+    }
+
+    #[test]
+    fn minifies_whitespace_outside_of_preformatted_elements() {
+        assert_eq!(
+            "<div><p> Some <span>spaced out</span> text </p> <ul><li>one</li>\
+            <li>two<br>lines</li></ul><pre>  keep\n    this  </pre>\
+            <textarea> and  this</textarea></div>",
+            write_formatted(&sample(), HtmlFormat::minified())
+        );
+    }
+}
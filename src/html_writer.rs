@@ -1,8 +1,7 @@
-extern crate marksman_escape;
-use self::marksman_escape::Escape;
-
 use {DomNode, DomNodes, DomValue};
+use html_fmt_writer::{Escaped, HtmlAttribute, HtmlFmtWriter, RawText};
 use processors::DomNodeProcessor;
+use tags::{is_raw_text_element, is_void_element};
 
 // This module as a whole is "use_std"-only, so these don't need to be cfg'd
 use std::marker::PhantomData;
@@ -13,6 +12,12 @@ use std::io;
 ///
 /// This type should not ever need to be instantiated. Instead, simply
 /// name the type in calls to `DomNodes::process_all::<HtmlWriter<...>>(...)`.
+///
+/// Output follows the HTML5 serialization rules: text and attribute values are escaped,
+/// `Bool` attributes are written as a bare attribute name when `true` and omitted when `false`,
+/// and void elements such as `br` or `img` are written without a closing tag (any children they
+/// have are skipped, since void elements cannot have contents in HTML). Text inside of `script`
+/// and `style` elements is written as `RawText`.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct HtmlWriter<W: io::Write>(PhantomData<W>);
 impl<'a, M, W: io::Write> DomNodeProcessor<'a, M> for HtmlWriter<W> {
@@ -26,18 +31,38 @@ impl<'a, M, W: io::Write> DomNodeProcessor<'a, M> for HtmlWriter<W> {
                 DomValue::Element { tag: tagname } => {
                     write!(w, "<{}", tagname)?;
                     for attr in node.attributes() {
-                        write!(w, " {}=\"{}\"", attr.0, attr.1)?;
+                        write!(w, "{}", HtmlAttribute(attr))?;
                     }
                     write!(w, ">")?;
-                    node.children().process_all::<HtmlWriter<W>>(w)?;
-                    write!(w, "</{}>", tagname)
-                }
-                DomValue::Text(text) => {
-                    for escaped_u8 in Escape::new(text.bytes()) {
-                        w.write(&[escaped_u8])?;
+                    if is_void_element(tagname) {
+                        return Ok(());
+                    }
+                    if is_raw_text_element(tagname) {
+                        node.children().process_all::<RawTextWriter<W>>(w)?;
+                    } else {
+                        node.children().process_all::<HtmlWriter<W>>(w)?;
                     }
-                    Ok(())
+                    write!(w, "</{}>", tagname)
                 }
+                DomValue::Text(text) => write!(w, "{}", Escaped(text)),
+            }
+        }
+        add_node
+    }
+}
+
+/// Writes the contents of a raw text element, using `RawText` instead of `Escaped` for text
+struct RawTextWriter<W: io::Write>(PhantomData<W>);
+impl<'a, M, W: io::Write> DomNodeProcessor<'a, M> for RawTextWriter<W> {
+    type Acc = W;
+    type Error = io::Error;
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &T) -> Result<(), Self::Error> {
+        fn add_node<M, W, T>(w: &mut W, node: &T) -> Result<(), io::Error>
+                where W: io::Write, T: DomNode<M> {
+            match node.value() {
+                DomValue::Text(text) => write!(w, "{}", RawText(text)),
+                DomValue::Element { .. } => HtmlWriter::<W>::get_processor::<T>()(w, node),
             }
         }
         add_node
@@ -50,12 +75,6 @@ pub struct HtmlDisplayable<'a, M, T: DomNode<M> + 'a>(pub &'a T, pub PhantomData
 
 impl<'a, M, T: DomNode<M>> fmt::Display for HtmlDisplayable<'a, M, T> {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        // TODO the extra string allocation here is almost certainly avoidable
-        let mut string_buffer = Vec::new();
-        self.0.write_html(&mut string_buffer)
-            .map_err(|_| fmt::Error)?;
-        let string = String::from_utf8(string_buffer)
-            .map_err(|_| fmt::Error)?;
-        formatter.write_str(&string)
+        self.0.process_all::<HtmlFmtWriter<fmt::Formatter>>(formatter)
     }
 }
@@ -1,64 +1,199 @@
-const KEY_STACK_LEN: u32 = 32;
+use Key;
 
-#[derive(Clone, Copy, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
-pub struct Keys {
-    pub size: u32,
-    pub stack: [u32; KEY_STACK_LEN as usize],
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::rc::Rc;
+use std::vec;
+
+/// The stack of keys belonging to a `DomNode` and all of its parents.
+///
+/// There is no limit on the number of keys. The stack is a persistent linked list: pushing a key
+/// shares the keys of the parent instead of copying them, and nodes without a key of their own
+/// share the stack of their parent, so both pushing and cloning are cheap.
+#[derive(Clone, Default)]
+pub struct Keys(Option<Rc<KeyNode>>);
+
+/// A key on a `Keys` stack, linked to the keys beneath it
+struct KeyNode {
+    key: Key,
+    parent: Option<Rc<KeyNode>>,
+    len: usize,
 }
+
 impl Keys {
     /// Create a new `Keys` with no elements
-    #[cfg_attr(not(target_os = "emscripten"), allow(dead_code))]
     pub fn new() -> Keys {
-        Keys { size: 0, stack: [0; KEY_STACK_LEN as usize] }
+        Keys::default()
     }
 
     /// Push a new key onto the `Keys`
     /// Immutable. Creates a new `Keys` with the top element.
-    #[cfg_attr(not(target_os = "emscripten"), allow(dead_code))]
-    pub fn push(&self, key: u32) -> Keys {
-        let mut stack = self.stack; // Copied
+    pub fn push(&self, key: Key) -> Keys {
+        Keys(Some(Rc::new(KeyNode { key, parent: self.0.clone(), len: self.len() + 1 })))
+    }
+
+    /// Returns the number of keys on the stack
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map_or(0, |node| node.len)
+    }
+
+    /// Returns whether the stack is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Returns the keys from bottom to top
+    pub fn to_vec(&self) -> Vec<Key> {
+        let mut keys: Vec<Key> = self.nodes().map(|node| node.key.clone()).collect();
+        keys.reverse();
+        keys
+    }
+
+    /// Returns the nodes of the stack from top to bottom
+    fn nodes(&self) -> impl Iterator<Item=&KeyNode> {
+        iter::successors(self.0.as_ref(), |node| node.parent.as_ref()).map(|node| &**node)
+    }
+}
+
+impl PartialEq for Keys {
+    fn eq(&self, other: &Keys) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) if Rc::ptr_eq(a, b) => true,
+            _ => self.len() == other.len() &&
+                self.nodes().zip(other.nodes()).all(|(a, b)| a.key == b.key),
+        }
+    }
+}
 
-        debug_assert!(
-            self.size < KEY_STACK_LEN,
-            "Only {} elements fit on a `Keys`. Your structure may be too deep.",
-            KEY_STACK_LEN
-        );
+impl Eq for Keys {}
 
-        stack[self.size as usize] = key;
-        Keys { size: self.size + 1, stack: stack }
+impl Hash for Keys {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for node in self.nodes() {
+            node.key.hash(state);
+        }
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.to_vec()).finish()
     }
 }
 
 /// An iterator over keys into a `DomNode` tree.
-pub struct KeyIter(Keys, u32);
+pub struct KeyIter(vec::IntoIter<Key>);
+
+impl KeyIter {
+    /// Returns the next key as an index, for nodes keyed by `usize` or `Key::Int`.
+    ///
+    /// Returns `None` if there are no more keys or the next key is not an integer.
+    pub fn next_index(&mut self) -> Option<usize> {
+        self.next().and_then(|key| key.as_index())
+    }
+
+    /// Returns the next key as a string, for nodes keyed by `&'static str` or `String`.
+    ///
+    /// Returns `None` if there are no more keys or the next key is not a string.
+    pub fn next_str(&mut self) -> Option<String> {
+        self.next().and_then(|key| key.as_str().map(String::from))
+    }
+
+    /// Returns the next key as a `T`, for nodes keyed by `Key::value`.
+    ///
+    /// Returns `None` if there are no more keys or the next key is not a `T`.
+    pub fn next_value<T: Clone + 'static>(&mut self) -> Option<T> {
+        self.next().and_then(|key| key.downcast_ref::<T>().cloned())
+    }
+}
 
 impl Iterator for KeyIter {
-    type Item = usize;
+    type Item = Key;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.1 < self.0.size {
-            let result = Some(self.0.stack[self.1 as usize] as usize);
-            self.1 += 1;
-            result
-        } else {
-            None
-        }
+        self.0.next()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let remaining = (self.0.size - self.1) as usize;
-        (remaining, Some(remaining))
+        self.0.size_hint()
     }
 }
 
 impl ExactSizeIterator for KeyIter {}
 
 impl IntoIterator for Keys {
-    type Item = usize;
+    type Item = Key;
     type IntoIter = KeyIter;
 
     /// Returns an iterator over the keys from bottom to top
     fn into_iter(self) -> KeyIter {
-        KeyIter(self, 0)
+        KeyIter(self.to_vec().into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keys;
+    use Key;
+
+    #[test]
+    fn holds_arbitrarily_many_keys() {
+        let root = Keys::new();
+        let deep = (0..100).fold(root.clone(), |keys, key| keys.push(Key::from(key)));
+        assert_eq!(100, deep.len());
+        assert!(root.is_empty());
+
+        let mut iter = deep.push(Key::from(7)).into_iter();
+        assert_eq!(101, iter.len());
+        assert_eq!(Some(0), iter.next_index());
+        assert_eq!((100, Some(100)), iter.size_hint());
+        assert_eq!(Some(Key::Int(7)), iter.last());
+    }
+
+    #[test]
+    fn shares_parent_keys() {
+        use std::collections::HashSet;
+        use std::rc::Rc;
+
+        let parent = Keys::new().push(Key::from(1));
+        let first = parent.push(Key::from(2));
+        let second = parent.push(Key::from(3));
+        for child in &[&first, &second] {
+            let node = child.0.as_ref().unwrap();
+            assert!(Rc::ptr_eq(node.parent.as_ref().unwrap(), parent.0.as_ref().unwrap()));
+        }
+
+        // Stacks built separately are equal if they hold the same keys
+        let rebuilt = Keys::new().push(Key::from(1)).push(Key::from(2));
+        assert_eq!(first, rebuilt);
+        assert!(first != second);
+        assert!(first != parent);
+        let set: HashSet<Keys> = vec![first, rebuilt, second].into_iter().collect();
+        assert_eq!(2, set.len());
+        assert_eq!(vec![Key::from(1), Key::from(3)], parent.push(Key::from(3)).to_vec());
+    }
+
+    #[test]
+    fn yields_typed_keys() {
+        #[derive(Clone, Debug, Hash, Eq, PartialEq)]
+        struct Slug(&'static str);
+
+        let keys = Keys::new()
+            .push(Key::from("posts"))
+            .push(Key::from("first-post".to_string()))
+            .push(Key::value(Slug("comments")))
+            .push(Key::Int(1 << 40));
+        let mut iter = keys.clone().into_iter();
+        assert_eq!(Some("posts".to_string()), iter.next_str());
+        assert_eq!(Some("first-post".to_string()), iter.next_str());
+        assert_eq!(Some(Slug("comments")), iter.next_value::<Slug>());
+        assert_eq!(Some(Key::Int(1 << 40)), iter.next());
+        assert_eq!(None, iter.next());
+
+        // Keys of other kinds are consumed without being converted
+        assert_eq!(None, keys.into_iter().next_index());
     }
 }
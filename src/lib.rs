@@ -26,7 +26,7 @@
 //! ));
 //!
 //! assert_eq!(
-//!     "<div><h1>Hello, world&#33; Your birthday is: Christmas</h1></div>".to_string(),
+//!     "<div><h1>Hello, world! Your birthday is: Christmas</h1></div>".to_string(),
 //!     render("Christmas").to_string()
 //! );
 //! ```
@@ -123,31 +123,92 @@
 //! `conservative_impl_trait` feature, which will allow you to write the function signature of
 //! `render` like `fn render(state: &State) -> impl DomNode<Message=Msg>`.
 
-#![cfg_attr(test, feature(conservative_impl_trait))]
 #![cfg_attr(not(any(feature = "use_std", test)), no_std)]
 #![allow(unused_unsafe)]
 #![deny(missing_docs)]
 
 #[macro_use] extern crate error_chain;
+#[cfg(feature = "serde")] extern crate serde;
+#[cfg(feature = "serde")] extern crate serde_json;
 /// Trait for elements that can be drawn as to HTML DOM nodes
 pub mod dom_node;
-pub use dom_node::{DomNode, DomValue};
+pub use dom_node::{DomNode, DomValue, Key};
+
+/// Traits describing an application's updater and renderer and its interface to the outside world
+#[cfg(any(feature = "use_std", test))]
+pub mod app;
+
+/// Commands describing the side effects requested by an `Updater`
+#[cfg(any(feature = "use_std", test))]
+pub mod cmd;
+#[cfg(any(feature = "use_std", test))]
+pub use cmd::Cmd;
+
+/// A type-erased `DomNode` for building trees whose shape is only known at runtime
+#[cfg(any(feature = "use_std", test))]
+pub mod boxed_node;
+#[cfg(any(feature = "use_std", test))]
+pub use boxed_node::BoxedNode;
+
+/// Types for writing a `DomNode` to HTML through `core::fmt::Write`, with or without `std`
+pub mod html_fmt_writer;
 
 /// Types, traits and functions for writing a `DomNode` to HTML
 #[cfg(any(feature = "use_std", test))]
 pub mod html_writer;
 
+/// Types for describing a page's metadata and writing complete HTML pages
+#[cfg(any(feature = "use_std", test))]
+pub mod html_document;
+
+#[cfg(any(feature = "use_std", test))]
 mod keys;
-pub use keys::KeyIter;
+#[cfg(any(feature = "use_std", test))]
+pub use keys::{KeyIter, Keys};
 /// Types, traits, and functions for creating event handlers
 pub mod listener;
 pub use listener::{Listener, Event, on};
+/// Wrappers converting the messages produced by a `DomNode`, used by `DomNode::map_messages`
+#[cfg(any(feature = "use_std", test))]
+pub mod map_messages;
+/// Recording the changes made by the diffing engine as a list of patches, and applying them
+#[cfg(any(feature = "use_std", test))]
+pub mod patch;
+/// Saving the application state to storage and restoring it across page reloads
+#[cfg(any(feature = "use_std", test))]
+pub mod persist;
 /// Traits for processing collections of `DomNode`s or `Listener`s
 pub mod processors;
 pub use processors::{DomNodes, Listeners};
+/// Typed routes parsed from and formatted to URLs, and links and commands for navigating between them
+#[cfg(any(feature = "use_std", test))]
+pub mod router;
+/// Key-value storage in the browser's `localStorage` and `sessionStorage`, and an in-memory stand-in
+#[cfg(any(feature = "use_std", test))]
+pub mod storage;
+/// Subscriptions to timers, animation frames and global events, which feed messages to the updater
+#[cfg(any(feature = "use_std", test))]
+pub mod sub;
+#[cfg(any(feature = "use_std", test))]
+pub use sub::Sub;
+
 /// Types and functions for creating tag elements such as `div`s or `span`s
 pub mod tags;
 
+/// Virtual DOM diffing and the `DomBackend` trait used to apply its changes to a document
+#[cfg(any(feature = "use_std", test))]
+pub mod vdom;
+#[cfg(any(feature = "use_std", test))]
+pub use vdom::{DomBackend, HydrationMismatch, VDom};
+
+/// An in-memory document for running the diffing engine natively
+#[cfg(any(feature = "use_std", test))]
+pub mod virtual_document;
+
+/// A driver for simulating complete applications in native tests
+#[cfg(any(feature = "use_std", test))]
+pub mod test_app;
+
 /// Functions for interacting with a webpage when rendering client-side using asmjs or emscripten
 #[cfg(all(feature = "web_render", target_os = "emscripten"))]
 pub mod web_render;
@@ -194,6 +255,47 @@ impl std::fmt::Display for AttributeValue {
     }
 }
 
+/// Serializes strings as strings and booleans as booleans.
+#[cfg(feature = "serde")]
+impl serde::Serialize for AttributeValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            AttributeValue::Bool(value) => serializer.serialize_bool(value),
+            _ => serializer.serialize_str(self.as_str()),
+        }
+    }
+}
+
+/// Deserializes strings as `OwnedStr` and booleans as `Bool`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AttributeValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+        impl<'de> serde::de::Visitor<'de> for ValueVisitor {
+            type Value = AttributeValue;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string or a boolean")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, value: bool) -> Result<AttributeValue, E> {
+                Ok(AttributeValue::Bool(value))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<AttributeValue, E> {
+                Ok(AttributeValue::OwnedStr(value.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, value: String)
+                -> Result<AttributeValue, E>
+            {
+                Ok(AttributeValue::OwnedStr(value))
+            }
+        }
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
 mod opt_std {
     #[cfg(not(any(feature = "use_std", test)))]
     pub extern crate core as std;
@@ -206,10 +308,11 @@ mod opt_std {
 
 #[cfg(test)]
 mod tests {
-    use super::{DomNode, DomNodes, DomValue, KeyValue};
-    use super::AttributeValue::Str;
+    use super::{DomNode, DomNodes, DomValue, Key, KeyValue};
+    use super::AttributeValue::{Bool, Str};
     use super::tags::*;
     use super::processors::{DomNodeProcessor, EmptyListeners};
+    use super::html_fmt_writer::HtmlFormat;
 
     #[cfg(feature = "use_either_n")]
     extern crate either_n;
@@ -242,7 +345,7 @@ mod tests {
             (BogusOne, EmptyListeners)
         }
 
-        fn key(&self) -> Option<u32> { None }
+        fn key(&self) -> Option<&Key> { None }
         fn get_attribute(&self, _index: usize) -> Option<&KeyValue> { None }
         fn value(&self) -> DomValue {
             DomValue::Element { tag: "bogus_tag_one" }
@@ -260,7 +363,7 @@ mod tests {
         type Listeners = EmptyListeners;
         type WithoutListeners = BogusTwo;
 
-        fn key(&self) -> Option<u32> { None }
+        fn key(&self) -> Option<&Key> { None }
         fn get_attribute(&self, _index: usize) -> Option<&KeyValue> { None }
 
         fn children(&self) -> &Self::Children { EMPTY_NODES_REF }
@@ -327,33 +430,19 @@ mod tests {
         ))
     }
 
-    #[cfg(feature = "use_either_n")]
-    fn builds_an_either_string(arg: bool, expected: &'static str) {
-        assert_eq!(
-            without_whitespace(expected.to_string()),
-            without_whitespace(html_either(arg).displayable().to_string())
-        );
-    }
-
     #[cfg(feature = "use_either_n")]
     #[test]
     fn builds_either_string() {
-        builds_an_either_string(true, r#"
-        <div>
-            <table>
-                <tr>a</tr>
-                <tr>b</tr>
-            </table>
-        </div>
-        "#);
-
-        builds_an_either_string(false, r#"
-        <div>
-            <table>
-                sumthin else
-            </table>
-        </div>
-        "#);
+        assert_eq!(pretty(&html_either(true)), r#"<div>
+    <table>
+        <tr>a</tr>
+        <tr>b</tr>
+    </table>
+</div>"#);
+
+        assert_eq!(pretty(&html_either(false)), r#"<div>
+    <table>sumthin else</table>
+</div>"#);
     }
 
     #[test]
@@ -403,31 +492,71 @@ mod tests {
         assert_eq!(4, count);
     }
 
-    fn without_whitespace(string: String) -> String {
-        string.chars().filter(|c| !c.is_whitespace()).collect()
+    fn pretty<M, T: DomNode<M>>(node: &T) -> String {
+        let mut string = String::new();
+        node.write_html_formatted(&mut string, HtmlFormat { indent: Some(4), ..HtmlFormat::pretty() })
+            .unwrap();
+        string
     }
 
     #[test]
     fn builds_string() {
-        let string = html_sample().displayable().to_string();
+        assert_eq!(pretty(&html_sample()), r#"<div attr="value">
+    <bogus_tag_one></bogus_tag_one>
+    <bogus_tag_one></bogus_tag_one>
+    <bogus_tag_two></bogus_tag_two>
+    <table>
+        something&amp;
+        <th></th>
+        <tr></tr>
+        <tr></tr>
+    </table>
+</div>"#);
+
+        assert_eq!(
+            concat!(
+                r#"<div attr="value"><bogus_tag_one></bogus_tag_one><bogus_tag_one></bogus_tag_one>"#,
+                "<bogus_tag_two></bogus_tag_two><table>something&amp;<th></th><tr></tr><tr></tr>",
+                "</table></div>"
+            ),
+            html_sample().displayable().to_string()
+        );
+    }
+
+    #[test]
+    fn escapes_attribute_values() {
+        let link: Tag<Never, _, _, _> = a((
+            attributes([("title", Str("\"><script>alert('hi')</script>"))]),
+            "x",
+        ));
+        assert_eq!(
+            "<a title=\"&quot;&gt;&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt;\">x</a>",
+            link.to_string()
+        );
+    }
+
+    #[test]
+    fn writes_boolean_attributes() {
+        let inputs: Tag<Never, _, _, _> = form((
+            input(attributes([("type", Str("checkbox")), ("checked", Bool(true))])),
+            input(attributes([("type", Str("text")), ("disabled", Bool(false))])),
+        ));
         assert_eq!(
-            without_whitespace(r#"
-            <div attr="value">
-                <bogus_tag_one></bogus_tag_one>
-                <bogus_tag_one></bogus_tag_one>
-                <bogus_tag_two></bogus_tag_two>
-                <table>
-                    something&amp;
-                    <th></th>
-                    <tr></tr>
-                    <tr></tr>
-                </table>
-            </div>
-            "#.to_string()),
-            without_whitespace(string)
+            "<form><input type=\"checkbox\" checked><input type=\"text\"></form>",
+            inputs.to_string()
         );
     }
 
+    #[test]
+    fn omits_closing_tags_of_void_elements() {
+        let node: Tag<Never, _, _, _> = p((
+            "one", br(()), "two",
+            img(attributes([("src", Str("a.png"))])),
+            hr(()),
+        ));
+        assert_eq!("<p>one<br>two<img src=\"a.png\"><hr></p>", node.to_string());
+    }
+
     fn check_attribute_list<M, T: DomNode<M>>(div: T) {
         assert_eq!(div.get_attribute(0), Some(&("attr1", Str("val1"))));
         assert_eq!(div.get_attribute(1), Some(&("attr2", Str("val2"))));
@@ -454,4 +583,105 @@ mod tests {
         )).with_attributes([("attr1", Str("val1"))]);
         check_attribute_list(div2);
     }
+
+    #[test]
+    fn maps_messages() {
+        use super::KeyIter;
+        use super::app::JsIo;
+        use super::listener::on;
+        use super::test_app::TestApp;
+
+        #[derive(Debug, PartialEq)]
+        enum CounterMsg { Increment, Decrement }
+
+        #[derive(Debug, PartialEq)]
+        enum AppMsg { Counter(CounterMsg), Reset }
+
+        let render_counter = |count: &i32| div((
+            button((on("click", |_| CounterMsg::Decrement), "-")),
+            span(count.to_string()),
+            button((on("click", |_| CounterMsg::Increment), "+")),
+        ));
+
+        let render = |state: &Vec<i32>| div((
+            button((attributes([("id", Str("reset"))]), on("click", |_| AppMsg::Reset), "0")),
+            state.iter().enumerate().map(|(index, count)|
+                render_counter(count).map_messages(AppMsg::Counter).with_key(index)
+            ).collect::<Vec<_>>(),
+        ));
+
+        let update = |state: &mut Vec<i32>, msg: AppMsg, mut keys: KeyIter, _: &dyn JsIo<AppMsg>| {
+            match msg {
+                AppMsg::Counter(CounterMsg::Increment) => state[keys.next_index().unwrap()] += 1,
+                AppMsg::Counter(CounterMsg::Decrement) => state[keys.next_index().unwrap()] -= 1,
+                AppMsg::Reset => for count in state.iter_mut() { *count = 0 },
+            }
+        };
+
+        let mut app = TestApp::new(update, render, vec![0, 0]);
+        let buttons = app.document().query_selector_all("div div button");
+        app.click("div div button");
+        app.dispatch_to(buttons[3], super::Event { type_str: Some("click"), ..Default::default() });
+        app.dispatch_to(buttons[3], super::Event { type_str: Some("click"), ..Default::default() });
+        assert_eq!(&vec![-1, 2], app.state());
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+
+        app.click("#reset");
+        assert_eq!(&vec![0, 0], app.state());
+    }
+
+    #[test]
+    fn maps_messages_with_capturing_closures() {
+        use super::KeyIter;
+        use super::app::JsIo;
+        use super::listener::on;
+        use super::test_app::TestApp;
+
+        enum CounterMsg { Increment, Decrement }
+        enum AppMsg { Child(usize, CounterMsg), Scaled(i32) }
+
+        fn scale(msg: CounterMsg) -> AppMsg {
+            match msg {
+                CounterMsg::Increment => AppMsg::Scaled(10),
+                CounterMsg::Decrement => AppMsg::Scaled(-10),
+            }
+        }
+
+        let render_counter = |count: &i32| div((
+            button((on("click", |_| CounterMsg::Decrement), "-")),
+            span(count.to_string()),
+            button((on("click", |_| CounterMsg::Increment), "+")),
+        ));
+
+        let render = |state: &Vec<i32>| {
+            // Function pointers are accepted as well as closures
+            let scale: fn(CounterMsg) -> AppMsg = scale;
+            div((
+                state.iter().enumerate().map(|(id, count)|
+                    render_counter(count).map_messages(move |msg| AppMsg::Child(id, msg))
+                ).collect::<Vec<_>>(),
+                render_counter(&state.iter().sum()).map_messages(scale),
+            ))
+        };
+
+        let update = |state: &mut Vec<i32>, msg: AppMsg, _: KeyIter, _: &dyn JsIo<AppMsg>| {
+            match msg {
+                AppMsg::Child(id, CounterMsg::Increment) => state[id] += 1,
+                AppMsg::Child(id, CounterMsg::Decrement) => state[id] -= 1,
+                AppMsg::Scaled(delta) => state[0] += delta,
+            }
+        };
+
+        let mut app = TestApp::new(update, render, vec![0, 0]);
+        let buttons = app.document().query_selector_all("div div button");
+        let click = super::Event { type_str: Some("click"), ..Default::default() };
+        app.dispatch_to(buttons[3], click);
+        app.dispatch_to(buttons[0], click);
+        app.dispatch_to(buttons[3], click);
+        assert_eq!(&vec![-1, 2], app.state());
+
+        app.dispatch_to(buttons[5], click);
+        assert_eq!(&vec![9, 2], app.state());
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+    }
 }
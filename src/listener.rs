@@ -2,7 +2,7 @@ use processors::{Listeners, ListenerProcessor};
 
 // TODO make it possible to add fields w/o API breakage-- blocked on pub(restricted)
 /// Description of a `DOM` event that caused a listener to be called.
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
 pub struct Event<'a> {
     /// Type of event
     pub type_str: Option<&'a str>,
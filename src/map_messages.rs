@@ -0,0 +1,262 @@
+use {DomNode, DomValue, Event, Key, KeyValue, Listener};
+use processors::{DomNodes, DomNodeProcessor, EmptyListeners, Listeners, ListenerProcessor};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::cell::OnceCell;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+/// Object-safe subset of `DomNode` used to access the nodes inside of a `MapMessages`
+trait ErasedDomNode<M> {
+    fn key(&self) -> Option<&Key>;
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue>;
+    fn value(&self) -> DomValue<'_>;
+    fn erased_children(&self) -> Vec<*const dyn ErasedDomNode<M>>;
+    fn erased_listeners(&self) -> Vec<*const dyn Listener<M>>;
+}
+
+impl<M, T: DomNode<M>> ErasedDomNode<M> for T {
+    fn key(&self) -> Option<&Key> { DomNode::key(self) }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
+        DomNode::get_attribute(self, index)
+    }
+    fn value(&self) -> DomValue<'_> { DomNode::value(self) }
+    fn erased_children(&self) -> Vec<*const dyn ErasedDomNode<M>> {
+        let mut children = Vec::new();
+        self.children().process_all::<NodesToVec<M>>(&mut children).unwrap();
+        children
+    }
+    fn erased_listeners(&self) -> Vec<*const dyn Listener<M>> {
+        let mut listeners = Vec::new();
+        self.listeners().process_all::<ListenersToVec<M>>(&mut listeners).unwrap();
+        listeners
+    }
+}
+
+/// Wrapper for `DomNode`s that converts the messages produced by its listeners, and by the
+/// listeners of all of its children, using a function.
+///
+/// The wrapped node is kept at a fixed address behind a reference-counted pointer. Its children
+/// and listeners are wrapped in `MappedNode`s and `MappedListener`s the first time they are
+/// accessed. See `DomNode::map_messages` for details.
+pub struct MapMessages<Inner, Outer, T, F, L = MappedListeners<Inner, Outer, F, Rc<T>>> {
+    node: Rc<T>,
+    f: Rc<F>,
+    children: OnceCell<Vec<MappedNode<Inner, Outer, F>>>,
+    listeners: L,
+}
+
+impl<Inner, Outer, T: DomNode<Inner>, F> MapMessages<Inner, Outer, T, F> {
+    /// Wraps `node` so that its messages are converted using `f`.
+    pub fn new(node: T, f: F) -> MapMessages<Inner, Outer, T, F> {
+        let node = Rc::new(node);
+        let f = Rc::new(f);
+        let node_ptr = erase(&*node);
+        MapMessages {
+            node: node.clone(),
+            f: f.clone(),
+            children: OnceCell::new(),
+            listeners: MappedListeners::new(node, node_ptr, f),
+        }
+    }
+}
+
+impl<Inner, Outer, T, F, L> DomNodes<Outer> for MapMessages<Inner, Outer, T, F, L>
+    where T: DomNode<Inner>, F: Fn(Inner) -> Outer, L: Listeners<Outer>
+{
+    fn process_all<'a, P: DomNodeProcessor<'a, Outer>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<Inner, Outer, T, F, L> DomNode<Outer> for MapMessages<Inner, Outer, T, F, L>
+    where T: DomNode<Inner>, F: Fn(Inner) -> Outer, L: Listeners<Outer>
+{
+    type Children = Vec<MappedNode<Inner, Outer, F>>;
+    type Listeners = L;
+    type WithoutListeners = MapMessages<Inner, Outer, T, F, EmptyListeners>;
+
+    fn key(&self) -> Option<&Key> { self.node.key() }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
+        self.node.get_attribute(index)
+    }
+    fn children(&self) -> &Self::Children {
+        self.children.get_or_init(|| map_children(erase(&*self.node), &self.f))
+    }
+    fn listeners(&self) -> &Self::Listeners {
+        &self.listeners
+    }
+    fn children_and_listeners(&self) -> (&Self::Children, &Self::Listeners) {
+        (self.children(), &self.listeners)
+    }
+    fn split_listeners(self) -> (Self::WithoutListeners, Self::Listeners) {
+        let MapMessages { node, f, children, listeners } = self;
+        (MapMessages { node, f, children, listeners: EmptyListeners }, listeners)
+    }
+    fn value(&self) -> DomValue<'_> { self.node.value() }
+}
+
+/// A child (or further descendant) of a `MapMessages` node, whose messages are converted using
+/// the same function.
+pub struct MappedNode<Inner, Outer, F, L = MappedListeners<Inner, Outer, F>> {
+    // Points into the node owned by the enclosing `MapMessages`
+    node: *const dyn ErasedDomNode<Inner>,
+    f: Rc<F>,
+    children: OnceCell<Vec<MappedNode<Inner, Outer, F>>>,
+    listeners: L,
+}
+
+impl<Inner, Outer, F, L> DomNodes<Outer> for MappedNode<Inner, Outer, F, L>
+    where F: Fn(Inner) -> Outer, L: Listeners<Outer>
+{
+    fn process_all<'a, P: DomNodeProcessor<'a, Outer>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<Inner, Outer, F, L> DomNode<Outer> for MappedNode<Inner, Outer, F, L>
+    where F: Fn(Inner) -> Outer, L: Listeners<Outer>
+{
+    type Children = Vec<MappedNode<Inner, Outer, F>>;
+    type Listeners = L;
+    type WithoutListeners = MappedNode<Inner, Outer, F, EmptyListeners>;
+
+    fn key(&self) -> Option<&Key> {
+        unsafe { (*self.node).key() }
+    }
+    fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
+        unsafe { (*self.node).get_attribute(index) }
+    }
+    fn children(&self) -> &Self::Children {
+        self.children.get_or_init(|| map_children(self.node, &self.f))
+    }
+    fn listeners(&self) -> &Self::Listeners {
+        &self.listeners
+    }
+    fn children_and_listeners(&self) -> (&Self::Children, &Self::Listeners) {
+        (self.children(), &self.listeners)
+    }
+    fn split_listeners(self) -> (Self::WithoutListeners, Self::Listeners) {
+        let MappedNode { node, f, children, listeners } = self;
+        (MappedNode { node, f, children, listeners: EmptyListeners }, listeners)
+    }
+    fn value(&self) -> DomValue<'_> {
+        unsafe { (*self.node).value() }
+    }
+}
+
+/// The listeners of a `MapMessages` node or one of its descendants, converting their messages.
+///
+/// `O` keeps the wrapped node alive after the listeners have been split from a `MapMessages`.
+pub struct MappedListeners<Inner, Outer, F, O = ()> {
+    _owner: O,
+    node: *const dyn ErasedDomNode<Inner>,
+    f: Rc<F>,
+    listeners: OnceCell<Vec<MappedListener<Inner, Outer, F>>>,
+}
+
+impl<Inner, Outer, F, O> MappedListeners<Inner, Outer, F, O> {
+    fn new(owner: O, node: *const dyn ErasedDomNode<Inner>, f: Rc<F>) -> Self {
+        MappedListeners { _owner: owner, node, f, listeners: OnceCell::new() }
+    }
+}
+
+impl<Inner, Outer, F, O> Listeners<Outer> for MappedListeners<Inner, Outer, F, O>
+    where F: Fn(Inner) -> Outer
+{
+    fn process_all<'a, P: ListenerProcessor<'a, Outer>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        self.listeners
+            .get_or_init(|| {
+                let listeners = unsafe { (*self.node).erased_listeners() };
+                listeners.into_iter()
+                    .map(|listener| MappedListener {
+                        listener,
+                        f: self.f.clone(),
+                        _marker: PhantomData,
+                    })
+                    .collect()
+            })
+            .process_all::<P>(acc)
+    }
+}
+
+/// A `Listener` whose messages are converted using `F`
+pub struct MappedListener<Inner, Outer, F> {
+    // Points into the node owned by the enclosing `MapMessages`
+    listener: *const dyn Listener<Inner>,
+    f: Rc<F>,
+    _marker: PhantomData<Outer>,
+}
+
+impl<Inner, Outer, F: Fn(Inner) -> Outer> Listeners<Outer> for MappedListener<Inner, Outer, F> {
+    fn process_all<'a, P: ListenerProcessor<'a, Outer>>(&'a self, acc: &mut P::Acc) -> Result<(), P::Error> {
+        P::get_processor()(acc, self)
+    }
+}
+
+impl<Inner, Outer, F: Fn(Inner) -> Outer> Listener<Outer> for MappedListener<Inner, Outer, F> {
+    fn event_type_handled(&self) -> &'static str {
+        unsafe { (*self.listener).event_type_handled() }
+    }
+    fn handle_event(&self, event: Event) -> Outer {
+        (self.f)(unsafe { (*self.listener).handle_event(event) })
+    }
+}
+
+/// Converts `node` to a *const, extending its lifetime. It must be kept alive (and in place) by a
+/// `MapMessages`.
+fn erase<M, T: DomNode<M>>(node: &T) -> *const dyn ErasedDomNode<M> {
+    unsafe { mem::transmute::<&dyn ErasedDomNode<M>, _>(node) }
+}
+
+/// Wraps the children of `node` in `MappedNode`s converting their messages using `f`
+fn map_children<Inner, Outer, F>(node: *const dyn ErasedDomNode<Inner>, f: &Rc<F>)
+    -> Vec<MappedNode<Inner, Outer, F>>
+{
+    let children = unsafe { (*node).erased_children() };
+    children.into_iter()
+        .map(|child| MappedNode {
+            node: child,
+            f: f.clone(),
+            children: OnceCell::new(),
+            listeners: MappedListeners::new((), child, f.clone()),
+        })
+        .collect()
+}
+
+struct NodesToVec<M>(PhantomData<M>);
+impl<'a, M> DomNodeProcessor<'a, M> for NodesToVec<M> {
+    type Acc = Vec<*const dyn ErasedDomNode<M>>;
+    type Error = ();
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), Self::Error> {
+        fn add_node_to_vec<M, T: DomNode<M>>(
+            vec: &mut Vec<*const dyn ErasedDomNode<M>>,
+            node: &T) -> Result<(), ()>
+        {
+            vec.push(erase(node));
+            Ok(())
+        }
+        add_node_to_vec
+    }
+}
+
+struct ListenersToVec<M>(PhantomData<M>);
+impl<'a, M> ListenerProcessor<'a, M> for ListenersToVec<M> {
+    type Acc = Vec<*const dyn Listener<M>>;
+    type Error = ();
+
+    fn get_processor<L: Listener<M>>() -> fn(&mut Self::Acc, &'a L) -> Result<(), Self::Error> {
+        fn add_listener_to_vec<M, L: Listener<M>>(
+            vec: &mut Vec<*const dyn Listener<M>>,
+            listener: &L) -> Result<(), ()>
+        {
+            // Extend the lifetime of the listener (it is kept alive by the `MapMessages` owning
+            // it) and convert it to a *const
+            vec.push(unsafe { mem::transmute::<&dyn Listener<M>, _>(listener) });
+            Ok(())
+        }
+        add_listener_to_vec
+    }
+}
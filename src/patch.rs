@@ -0,0 +1,873 @@
+use {AttributeValue, Event, KeyValue, Listener};
+use keys::Keys;
+use vdom::DomBackend;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::SerializeStructVariant};
+#[cfg(feature = "serde")]
+use serde::de::{self, EnumAccess, IgnoredAny, MapAccess, SeqAccess, VariantAccess, Visitor};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Identifier of an element or text node created by a `PatchRecorder`.
+pub type NodeId = usize;
+
+/// Identifier of a listener added by a `PatchRecorder`.
+pub type ListenerId = usize;
+
+/// A single change to a document, produced by reconciling a `VDom` against a `PatchRecorder`.
+///
+/// Nodes and listeners are referred to by identifiers rather than backend handles, so that a
+/// list of patches can be compared in tests, applied to any `DomBackend` with a `PatchApplier`,
+/// or serialized (with the `serde` feature) and sent elsewhere to be applied.
+///
+/// Tag names, attribute keys and event types are borrowed when recorded, and owned when
+/// deserialized.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Patch {
+    /// Create a detached element with the tag name `tag`
+    CreateElement {
+        /// Identifier of the new element
+        id: NodeId,
+        /// Tag name of the element
+        tag: Cow<'static, str>,
+    },
+    /// Create a detached text node
+    CreateText {
+        /// Identifier of the new text node
+        id: NodeId,
+        /// Contents of the text node
+        text: String,
+    },
+    /// Insert `child` into `parent` so that it becomes the child at position `index`
+    Insert {
+        /// Parent element
+        parent: NodeId,
+        /// Position of the child once inserted
+        index: usize,
+        /// Node to insert
+        child: NodeId,
+    },
+    /// Move the child of `parent` at position `old_index` to position `new_index`
+    Move {
+        /// Parent element
+        parent: NodeId,
+        /// Current position of the child
+        old_index: usize,
+        /// Position of the child once moved
+        new_index: usize,
+    },
+    /// Set an attribute on `element`, replacing any previous value for the same key
+    SetAttribute {
+        /// Element to change
+        element: NodeId,
+        /// Name of the attribute
+        key: Cow<'static, str>,
+        /// New value of the attribute
+        value: AttributeValue,
+    },
+    /// Remove the attribute `key` from `element`
+    RemoveAttribute {
+        /// Element to change
+        element: NodeId,
+        /// Name of the attribute
+        key: String,
+    },
+    /// Start forwarding events of type `event_type` on `element` to `listener`
+    AddListener {
+        /// Element to listen to
+        element: NodeId,
+        /// Type of event handled by the listener
+        event_type: Cow<'static, str>,
+        /// Listener to forward events to, see `PatchRecorder::handle_event`
+        listener: ListenerId,
+    },
+    /// Stop forwarding events to `listener`
+    RemoveListener {
+        /// Element the listener was added to
+        element: NodeId,
+        /// Type of event handled by the listener
+        event_type: Cow<'static, str>,
+        /// Listener to remove
+        listener: ListenerId,
+    },
+    /// Detach `element` from its parent. The element and its children are never used again.
+    Remove {
+        /// Node to remove
+        element: NodeId,
+    },
+}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Patch::CreateElement { id, ref tag } => write!(f, "create {} <{}>", id, tag),
+            Patch::CreateText { id, ref text } => write!(f, "create {} {:?}", id, text),
+            Patch::Insert { parent, index, child } =>
+                write!(f, "insert {} {} {}", parent, index, child),
+            Patch::Move { parent, old_index, new_index } =>
+                write!(f, "move {} {} {}", parent, old_index, new_index),
+            Patch::SetAttribute { element, ref key, ref value } =>
+                write!(f, "set {} {}={:?}", element, key, value.as_str()),
+            Patch::RemoveAttribute { element, ref key } => write!(f, "unset {} {}", element, key),
+            Patch::AddListener { element, ref event_type, listener } =>
+                write!(f, "listen {} {} #{}", element, event_type, listener),
+            Patch::RemoveListener { element, ref event_type, listener } =>
+                write!(f, "unlisten {} {} #{}", element, event_type, listener),
+            Patch::Remove { element } => write!(f, "remove {}", element),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Patch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Patch::CreateElement { id, ref tag } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 0, "CreateElement", 2)?;
+                variant.serialize_field("id", &id)?;
+                variant.serialize_field("tag", tag)?;
+                variant.end()
+            }
+            Patch::CreateText { id, ref text } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 1, "CreateText", 2)?;
+                variant.serialize_field("id", &id)?;
+                variant.serialize_field("text", text)?;
+                variant.end()
+            }
+            Patch::Insert { parent, index, child } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 2, "Insert", 3)?;
+                variant.serialize_field("parent", &parent)?;
+                variant.serialize_field("index", &index)?;
+                variant.serialize_field("child", &child)?;
+                variant.end()
+            }
+            Patch::Move { parent, old_index, new_index } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 3, "Move", 3)?;
+                variant.serialize_field("parent", &parent)?;
+                variant.serialize_field("old_index", &old_index)?;
+                variant.serialize_field("new_index", &new_index)?;
+                variant.end()
+            }
+            Patch::SetAttribute { element, ref key, ref value } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 4, "SetAttribute", 3)?;
+                variant.serialize_field("element", &element)?;
+                variant.serialize_field("key", key)?;
+                variant.serialize_field("value", value)?;
+                variant.end()
+            }
+            Patch::RemoveAttribute { element, ref key } => {
+                let mut variant =
+                    serializer.serialize_struct_variant("Patch", 5, "RemoveAttribute", 2)?;
+                variant.serialize_field("element", &element)?;
+                variant.serialize_field("key", key)?;
+                variant.end()
+            }
+            Patch::AddListener { element, ref event_type, listener } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 6, "AddListener", 3)?;
+                variant.serialize_field("element", &element)?;
+                variant.serialize_field("event_type", event_type)?;
+                variant.serialize_field("listener", &listener)?;
+                variant.end()
+            }
+            Patch::RemoveListener { element, ref event_type, listener } => {
+                let mut variant =
+                    serializer.serialize_struct_variant("Patch", 7, "RemoveListener", 3)?;
+                variant.serialize_field("element", &element)?;
+                variant.serialize_field("event_type", event_type)?;
+                variant.serialize_field("listener", &listener)?;
+                variant.end()
+            }
+            Patch::Remove { element } => {
+                let mut variant = serializer.serialize_struct_variant("Patch", 8, "Remove", 1)?;
+                variant.serialize_field("element", &element)?;
+                variant.end()
+            }
+        }
+    }
+}
+
+/// Names of the variants of `Patch`, in order
+#[cfg(feature = "serde")]
+const VARIANTS: &[&str] = &[
+    "CreateElement", "CreateText", "Insert", "Move", "SetAttribute", "RemoveAttribute",
+    "AddListener", "RemoveListener", "Remove",
+];
+
+/// Names of the fields of each variant of `Patch`, in order
+#[cfg(feature = "serde")]
+const VARIANT_FIELDS: &[&[&str]] = &[
+    &["id", "tag"],
+    &["id", "text"],
+    &["parent", "index", "child"],
+    &["parent", "old_index", "new_index"],
+    &["element", "key", "value"],
+    &["element", "key"],
+    &["element", "event_type", "listener"],
+    &["element", "event_type", "listener"],
+    &["element"],
+];
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Patch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Patch, D::Error> {
+        deserializer.deserialize_enum("Patch", VARIANTS, PatchVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct PatchVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> Visitor<'de> for PatchVisitor {
+    type Value = Patch;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a patch")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Patch, A::Error> {
+        let (variant, fields) = data.variant::<PatchVariant>()?;
+        fields.struct_variant(VARIANT_FIELDS[variant.0], variant)
+    }
+}
+
+/// Index of a variant of `Patch` in `VARIANTS`, which visits the fields of that variant
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy)]
+struct PatchVariant(usize);
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for PatchVariant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PatchVariant, D::Error> {
+        struct VariantVisitor;
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = PatchVariant;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a patch variant")
+            }
+
+            fn visit_u64<E: de::Error>(self, index: u64) -> Result<PatchVariant, E> {
+                if (index as usize) < VARIANTS.len() {
+                    Ok(PatchVariant(index as usize))
+                } else {
+                    Err(E::invalid_value(de::Unexpected::Unsigned(index), &self))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<PatchVariant, E> {
+                VARIANTS.iter().position(|&variant| variant == name)
+                    .map(PatchVariant)
+                    .ok_or_else(|| E::unknown_variant(name, VARIANTS))
+            }
+        }
+        deserializer.deserialize_identifier(VariantVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Visitor<'de> for PatchVariant {
+    type Value = Patch;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the fields of a {} patch", VARIANTS[self.0])
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Patch, A::Error> {
+        fn next<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(seq: &mut A, index: usize)
+            -> Result<T, A::Error>
+        {
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &"more fields"))
+        }
+
+        Ok(match self.0 {
+            0 => Patch::CreateElement {
+                id: next(&mut seq, 0)?,
+                tag: Cow::Owned(next(&mut seq, 1)?),
+            },
+            1 => Patch::CreateText { id: next(&mut seq, 0)?, text: next(&mut seq, 1)? },
+            2 => Patch::Insert {
+                parent: next(&mut seq, 0)?,
+                index: next(&mut seq, 1)?,
+                child: next(&mut seq, 2)?,
+            },
+            3 => Patch::Move {
+                parent: next(&mut seq, 0)?,
+                old_index: next(&mut seq, 1)?,
+                new_index: next(&mut seq, 2)?,
+            },
+            4 => Patch::SetAttribute {
+                element: next(&mut seq, 0)?,
+                key: Cow::Owned(next(&mut seq, 1)?),
+                value: next(&mut seq, 2)?,
+            },
+            5 => Patch::RemoveAttribute { element: next(&mut seq, 0)?, key: next(&mut seq, 1)? },
+            6 => Patch::AddListener {
+                element: next(&mut seq, 0)?,
+                event_type: Cow::Owned(next(&mut seq, 1)?),
+                listener: next(&mut seq, 2)?,
+            },
+            7 => Patch::RemoveListener {
+                element: next(&mut seq, 0)?,
+                event_type: Cow::Owned(next(&mut seq, 1)?),
+                listener: next(&mut seq, 2)?,
+            },
+            _ => Patch::Remove { element: next(&mut seq, 0)? },
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Patch, A::Error> {
+        let mut numbers: [Option<usize>; 3] = [None; 3];
+        let mut string = None;
+        let mut value = None;
+        let fields = VARIANT_FIELDS[self.0];
+        while let Some(name) = map.next_key::<String>()? {
+            match fields.iter().position(|&field| field == name) {
+                Some(_) if name == "value" => value = Some(map.next_value()?),
+                Some(_) if ["tag", "text", "key", "event_type"].contains(&&*name) =>
+                    string = Some(map.next_value::<String>()?),
+                Some(index) => numbers[index] = Some(map.next_value()?),
+                None => { map.next_value::<IgnoredAny>()?; }
+            }
+        }
+
+        let number = |index: usize| numbers[index].ok_or_else(|| de::Error::missing_field(fields[index]));
+        let string = || string.ok_or_else(|| de::Error::missing_field(fields[1]));
+        Ok(match self.0 {
+            0 => Patch::CreateElement { id: number(0)?, tag: Cow::Owned(string()?) },
+            1 => Patch::CreateText { id: number(0)?, text: string()? },
+            2 => Patch::Insert { parent: number(0)?, index: number(1)?, child: number(2)? },
+            3 => Patch::Move { parent: number(0)?, old_index: number(1)?, new_index: number(2)? },
+            4 => Patch::SetAttribute {
+                element: number(0)?,
+                key: Cow::Owned(string()?),
+                value: value.ok_or_else(|| de::Error::missing_field("value"))?,
+            },
+            5 => Patch::RemoveAttribute { element: number(0)?, key: string()? },
+            6 => Patch::AddListener {
+                element: number(0)?,
+                event_type: Cow::Owned(string()?),
+                listener: number(2)?,
+            },
+            7 => Patch::RemoveListener {
+                element: number(0)?,
+                event_type: Cow::Owned(string()?),
+                listener: number(2)?,
+            },
+            _ => Patch::Remove { element: number(0)? },
+        })
+    }
+}
+
+/// Parent and child relationships of the nodes referred to by patches, used to forget about
+/// every node in a removed subtree.
+#[derive(Debug, Default)]
+struct PatchTree {
+    parents: HashMap<NodeId, NodeId>,
+    children: HashMap<NodeId, Vec<NodeId>>,
+}
+
+impl PatchTree {
+    fn insert(&mut self, parent: NodeId, child: NodeId) {
+        self.parents.insert(child, parent);
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    /// Detaches `node` and returns it along with all of its descendants
+    fn remove(&mut self, node: NodeId) -> Vec<NodeId> {
+        if let Some(parent) = self.parents.remove(&node) {
+            if let Some(siblings) = self.children.get_mut(&parent) {
+                siblings.retain(|&sibling| sibling != node);
+            }
+        }
+        let mut removed = vec![node];
+        let mut index = 0;
+        while index < removed.len() {
+            if let Some(children) = self.children.remove(&removed[index]) {
+                for &child in &children {
+                    self.parents.remove(&child);
+                }
+                removed.extend(children);
+            }
+            index += 1;
+        }
+        removed
+    }
+}
+
+struct RecordedListener<Message> {
+    element: NodeId,
+    listener: *const dyn Listener<Message>,
+    keys: Keys,
+}
+
+/// A `DomBackend` which records the operations performed by a `VDom` as a list of `Patch`es
+/// instead of applying them to a document.
+///
+/// The recorded patches can be inspected in tests, applied to another backend using a
+/// `PatchApplier`, or serialized and sent to a remote client, which reports events back by
+/// listener identifier to `handle_event`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::DomNode;
+/// use domafic::patch::{Patch, PatchRecorder};
+/// use domafic::tags::div;
+/// use domafic::vdom::VDom;
+///
+/// let mut recorder = PatchRecorder::<()>::new();
+/// let mut vdom = VDom::new(recorder.root());
+/// let (first, second) = (div("Hello").with_key(1), div("Goodbye").with_key(1));
+///
+/// unsafe { vdom.update(&mut recorder, &first); }
+/// let created: Vec<String> = recorder.take_patches().iter().map(|p| p.to_string()).collect();
+/// assert_eq!(vec!["create 1 <div>", "create 2 \"Hello\"", "insert 1 0 2", "insert 0 0 1"], created);
+///
+/// unsafe { vdom.update(&mut recorder, &second); }
+/// assert_eq!(vec![
+///     Patch::CreateText { id: 3, text: "Goodbye".to_string() },
+///     Patch::Insert { parent: 1, index: 0, child: 3 },
+///     Patch::Remove { element: 2 },
+/// ], recorder.take_patches());
+/// ```
+pub struct PatchRecorder<Message> {
+    patches: Vec<Patch>,
+    tree: PatchTree,
+    listeners: HashMap<ListenerId, RecordedListener<Message>>,
+    next_node_id: NodeId,
+    next_listener_id: ListenerId,
+}
+
+impl<M> Default for PatchRecorder<M> {
+    fn default() -> PatchRecorder<M> {
+        PatchRecorder::new()
+    }
+}
+
+impl<M> PatchRecorder<M> {
+    /// Creates a recorder without any patches, whose root element has the identifier `0`.
+    pub fn new() -> PatchRecorder<M> {
+        PatchRecorder {
+            patches: Vec::new(),
+            tree: PatchTree::default(),
+            listeners: HashMap::new(),
+            next_node_id: 1,
+            next_listener_id: 0,
+        }
+    }
+
+    /// Returns the root element, which a `VDom` using this recorder should be created with.
+    pub fn root(&self) -> NodeId {
+        0
+    }
+
+    /// Returns the patches recorded since creation or the last `take_patches`.
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    /// Removes and returns the recorded patches.
+    pub fn take_patches(&mut self) -> Vec<Patch> {
+        ::std::mem::take(&mut self.patches)
+    }
+
+    /// Calls `listener` with `event`, returning the resulting message along with the keys of the
+    /// element it was added to, or `None` if there is no such listener.
+    ///
+    /// # Safety
+    ///
+    /// Requires that the `DomNode` most recently passed to `VDom::update` for this recorder
+    /// is still alive, since the recorder only holds pointers to its listeners.
+    pub unsafe fn handle_event(&self, listener: ListenerId, event: Event) -> Option<(M, Keys)> {
+        self.listeners.get(&listener)
+            .map(|recorded| ((*recorded.listener).handle_event(event), recorded.keys.clone()))
+    }
+
+    fn new_node(&mut self) -> NodeId {
+        let id = self.next_node_id;
+        self.next_node_id += 1;
+        id
+    }
+}
+
+impl<M> DomBackend<M> for PatchRecorder<M> {
+    type Element = NodeId;
+    type ListenerHandle = ListenerId;
+
+    fn create_element(&mut self, tag: &'static str) -> NodeId {
+        let id = self.new_node();
+        self.patches.push(Patch::CreateElement { id, tag: Cow::Borrowed(tag) });
+        id
+    }
+
+    fn create_text_node(&mut self, text: &str) -> NodeId {
+        let id = self.new_node();
+        self.patches.push(Patch::CreateText { id, text: text.to_string() });
+        id
+    }
+
+    fn insert(&mut self, parent: &NodeId, index: usize, child: &NodeId) {
+        self.tree.insert(*parent, *child);
+        self.patches.push(Patch::Insert { parent: *parent, index, child: *child });
+    }
+
+    fn move_child(&mut self, parent: &NodeId, old_index: usize, new_index: usize) {
+        self.patches.push(Patch::Move { parent: *parent, old_index, new_index });
+    }
+
+    fn set_attribute(&mut self, element: &NodeId, attribute: &KeyValue) {
+        self.patches.push(Patch::SetAttribute {
+            element: *element,
+            key: Cow::Borrowed(attribute.0),
+            value: attribute.1.clone(),
+        });
+    }
+
+    fn remove_attribute(&mut self, element: &NodeId, key: &str) {
+        self.patches.push(Patch::RemoveAttribute { element: *element, key: key.to_string() });
+    }
+
+    fn add_listener(
+        &mut self,
+        element: &NodeId,
+        event_type: &'static str,
+        listener: *const dyn Listener<M>,
+        keys: Keys,
+    ) -> ListenerId {
+        let id = self.next_listener_id;
+        self.next_listener_id += 1;
+        self.listeners.insert(id, RecordedListener { element: *element, listener, keys });
+        self.patches.push(Patch::AddListener {
+            element: *element,
+            event_type: Cow::Borrowed(event_type),
+            listener: id,
+        });
+        id
+    }
+
+    fn remove_listener(&mut self, element: &NodeId, event_type: &'static str, listener: ListenerId) {
+        self.listeners.remove(&listener);
+        self.patches.push(Patch::RemoveListener {
+            element: *element,
+            event_type: Cow::Borrowed(event_type),
+            listener,
+        });
+    }
+
+    fn remove_self(&mut self, element: &NodeId) {
+        let removed = self.tree.remove(*element);
+        self.listeners.retain(|_, recorded| !removed.contains(&recorded.element));
+        self.patches.push(Patch::Remove { element: *element });
+    }
+}
+
+/// Applies `Patch`es recorded by a `PatchRecorder` to another `DomBackend`, keeping track of the
+/// backend's handles for each node and listener identifier.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::patch::{PatchApplier, PatchRecorder};
+/// use domafic::tags::{b, div};
+/// use domafic::vdom::VDom;
+/// use domafic::virtual_document::VirtualDocument;
+///
+/// let mut recorder = PatchRecorder::<()>::new();
+/// let mut vdom = VDom::new(recorder.root());
+/// let rendered = div(("Hello, ", b("world")));
+/// unsafe { vdom.update(&mut recorder, &rendered); }
+///
+/// let document = VirtualDocument::new();
+/// let root = document.root();
+/// let mut applier = PatchApplier::new(document, root);
+/// applier.apply(&recorder.take_patches(), &recorder);
+/// assert_eq!("<div>Hello, <b>world</b></div>", applier.backend().to_string());
+/// ```
+pub struct PatchApplier<Message, B: DomBackend<Message>> {
+    backend: B,
+    elements: HashMap<NodeId, B::Element>,
+    tree: PatchTree,
+    listeners: HashMap<ListenerId, (NodeId, B::ListenerHandle)>,
+}
+
+impl<M, B: DomBackend<M>> PatchApplier<M, B> {
+    /// Creates an applier drawing into `root_element` of `backend`, which corresponds to the root
+    /// element of the `PatchRecorder` producing the patches.
+    pub fn new(backend: B, root_element: B::Element) -> PatchApplier<M, B> {
+        let mut elements = HashMap::new();
+        elements.insert(0, root_element);
+        PatchApplier { backend, elements, tree: PatchTree::default(), listeners: HashMap::new() }
+    }
+
+    /// Returns a reference to the backend the patches are applied to.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend the patches are applied to.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Returns the backend the patches are applied to.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    /// Returns the backend's handle for the node with the identifier `id`, if it hasn't been
+    /// removed.
+    pub fn element(&self, id: NodeId) -> Option<&B::Element> {
+        self.elements.get(&id)
+    }
+
+    /// Applies `patches` to the backend in order.
+    ///
+    /// Listeners are looked up in `recorder`, so `patches` must be applied before the next call
+    /// to `VDom::update` with `recorder`. Patches referring to unknown nodes or listeners are
+    /// skipped.
+    pub fn apply(&mut self, patches: &[Patch], recorder: &PatchRecorder<M>) {
+        for patch in patches {
+            self.apply_patch(patch, recorder);
+        }
+    }
+
+    fn apply_patch(&mut self, patch: &Patch, recorder: &PatchRecorder<M>) {
+        match *patch {
+            Patch::CreateElement { id, ref tag } => {
+                let element = self.backend.create_element(intern(tag.clone()));
+                self.elements.insert(id, element);
+            }
+            Patch::CreateText { id, ref text } => {
+                let element = self.backend.create_text_node(text);
+                self.elements.insert(id, element);
+            }
+            Patch::Insert { parent, index, child } => {
+                if let (Some(parent_element), Some(child_element)) =
+                    (self.elements.get(&parent), self.elements.get(&child))
+                {
+                    self.backend.insert(parent_element, index, child_element);
+                    self.tree.insert(parent, child);
+                }
+            }
+            Patch::Move { parent, old_index, new_index } => {
+                if let Some(parent) = self.elements.get(&parent) {
+                    self.backend.move_child(parent, old_index, new_index);
+                }
+            }
+            Patch::SetAttribute { element, ref key, ref value } => {
+                if let Some(element) = self.elements.get(&element) {
+                    self.backend.set_attribute(element, &(intern(key.clone()), value.clone()));
+                }
+            }
+            Patch::RemoveAttribute { element, ref key } => {
+                if let Some(element) = self.elements.get(&element) {
+                    self.backend.remove_attribute(element, key);
+                }
+            }
+            Patch::AddListener { element, ref event_type, listener } => {
+                if let (Some(backend_element), Some(recorded)) =
+                    (self.elements.get(&element), recorder.listeners.get(&listener))
+                {
+                    let handle = self.backend.add_listener(
+                        backend_element,
+                        intern(event_type.clone()),
+                        recorded.listener,
+                        recorded.keys.clone());
+                    self.listeners.insert(listener, (element, handle));
+                }
+            }
+            Patch::RemoveListener { element, ref event_type, listener } => {
+                if let (Some(element), Some((_, handle))) =
+                    (self.elements.get(&element), self.listeners.remove(&listener))
+                {
+                    self.backend.remove_listener(element, intern(event_type.clone()), handle);
+                }
+            }
+            Patch::Remove { element } => {
+                if let Some(backend_element) = self.elements.get(&element) {
+                    self.backend.remove_self(backend_element);
+                }
+                let removed = self.tree.remove(element);
+                for id in &removed {
+                    self.elements.remove(id);
+                }
+                self.listeners.retain(|_, &mut (element, _)| !removed.contains(&element));
+            }
+        }
+    }
+}
+
+thread_local! {
+    static INTERNED_NAMES: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+/// Returns `name` as a `&'static str`, as required by `DomBackend`.
+///
+/// Owned names, such as those of deserialized patches, are allocated once for the rest of the
+/// program and shared by every patch using the same name. Tag names, attribute keys and event
+/// types come from a small vocabulary, so this doesn't grow without bound.
+fn intern(name: Cow<'static, str>) -> &'static str {
+    match name {
+        Cow::Borrowed(name) => name,
+        Cow::Owned(name) => INTERNED_NAMES.with(|names| {
+            let mut names = names.borrow_mut();
+            if let Some(&interned) = names.get(name.as_str()) {
+                return interned;
+            }
+            let interned: &'static str = Box::leak(name.into_boxed_str());
+            names.insert(interned);
+            interned
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Patch, PatchApplier, PatchRecorder};
+    use {DomNode, Event};
+    use AttributeValue::Str;
+    use listener::on;
+    use tags::{attributes, button, div, li, ul};
+    use vdom::VDom;
+    use virtual_document::VirtualDocument;
+
+    fn to_strings(patches: &[Patch]) -> Vec<String> {
+        patches.iter().map(|patch| patch.to_string()).collect()
+    }
+
+    #[test]
+    fn records_diffs_as_patches() {
+        let render = |order: &[usize], class: &'static str| ul(
+            order.iter().map(|&key| li(key.to_string()).with_key(key)).collect::<Vec<_>>()
+        ).with_attributes([("class", Str(class))]);
+
+        let mut recorder = PatchRecorder::<()>::new();
+        let mut vdom = VDom::new(recorder.root());
+        let first = render(&[1, 2, 3], "list");
+        unsafe { vdom.update(&mut recorder, &first); }
+        assert_eq!(vec![
+            "create 1 <ul>", "set 1 class=\"list\"",
+            "create 2 <li>", "create 3 \"1\"", "insert 2 0 3",
+            "create 4 <li>", "create 5 \"2\"", "insert 4 0 5",
+            "create 6 <li>", "create 7 \"3\"", "insert 6 0 7",
+            "insert 1 0 2", "insert 1 1 4", "insert 1 2 6", "insert 0 0 1",
+        ], to_strings(&recorder.take_patches()));
+
+        let second = render(&[3, 1], "reversed");
+        unsafe { vdom.update(&mut recorder, &second); }
+        assert_eq!(vec![
+            "set 1 class=\"reversed\"", "move 1 2 0", "remove 4",
+        ], to_strings(&recorder.take_patches()));
+
+        let third = div(());
+        unsafe { vdom.update(&mut recorder, &third); }
+        assert_eq!(vec!["create 8 <div>", "insert 0 0 8", "remove 1"],
+            to_strings(recorder.patches()));
+    }
+
+    #[test]
+    fn applies_patches_to_another_backend() {
+        #[derive(Debug, PartialEq)]
+        enum Msg { Clicked(usize) }
+
+        let render = |buttons: &[usize]| div(
+            buttons.iter().map(|&key|
+                button((
+                    attributes([("class", Str("button"))]),
+                    on("click", move |_| Msg::Clicked(key)),
+                    key.to_string(),
+                )).with_key(key)
+            ).collect::<Vec<_>>()
+        );
+
+        let mut recorder = PatchRecorder::<Msg>::new();
+        let mut vdom = VDom::new(recorder.root());
+        let document = VirtualDocument::new();
+        let root = document.root();
+        let mut applier = PatchApplier::new(document, root);
+
+        let first = render(&[1, 2, 3]);
+        unsafe { vdom.update(&mut recorder, &first); }
+        applier.apply(&recorder.take_patches(), &recorder);
+        let second = render(&[3, 2]);
+        unsafe { vdom.update(&mut recorder, &second); }
+        applier.apply(&recorder.take_patches(), &recorder);
+
+        let mut direct = VirtualDocument::new();
+        let mut direct_vdom = VDom::new(direct.root());
+        unsafe { direct_vdom.update(&mut direct, &second); }
+        assert_eq!(direct.to_string(), applier.backend().to_string());
+
+        // Events can be handled either through the applied backend or by listener identifier
+        let click = Event { type_str: Some("click"), ..Event::default() };
+        let document = applier.backend();
+        let first_button = document.query_selector("button").unwrap();
+        let (message, keys) = unsafe { document.handle_event(first_button, click) }.remove(0);
+        assert_eq!(Msg::Clicked(3), message);
+        assert_eq!(Some(3), keys.into_iter().next_index());
+
+        // Listeners are replaced when re-rendered, and forgotten along with removed elements
+        assert_eq!(None, unsafe { recorder.handle_event(0, click) });
+        assert_eq!(None, unsafe { recorder.handle_event(2, click) });
+        let (message, keys) = unsafe { recorder.handle_event(3, click) }.unwrap();
+        assert_eq!(Msg::Clicked(3), message);
+        assert_eq!(Some(3), keys.into_iter().next_index());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn applies_deserialized_patches() {
+        use AttributeValue::Bool;
+        use tags::input;
+
+        #[derive(Debug, PartialEq)]
+        enum Msg { Toggled, Clicked }
+
+        let render = |checked: bool| div((
+            input((attributes([("type", Str("checkbox")), ("checked", Bool(checked))]),
+                on("change", |_| Msg::Toggled))),
+            button((attributes([("class", Str(if checked { "on" } else { "off" }))]),
+                on("click", |_| Msg::Clicked), "Go")),
+        ));
+
+        let mut recorder = PatchRecorder::<Msg>::new();
+        let mut vdom = VDom::new(recorder.root());
+        let document = VirtualDocument::new();
+        let root = document.root();
+        let mut applier = PatchApplier::new(document, root);
+
+        let first = render(false);
+        let second = render(true);
+        for rendered in &[&first, &second] {
+            unsafe { vdom.update(&mut recorder, *rendered); }
+            let patches = recorder.take_patches();
+            let json = ::serde_json::to_string(&patches).unwrap();
+            let deserialized: Vec<Patch> = ::serde_json::from_str(&json).unwrap();
+            assert_eq!(to_strings(&patches), to_strings(&deserialized));
+            applier.apply(&deserialized, &recorder);
+        }
+        assert_eq!(
+            ::serde_json::to_string(&Patch::CreateElement { id: 1, tag: "input".into() }).unwrap(),
+            r#"{"CreateElement":{"id":1,"tag":"input"}}"#);
+
+        let mut direct = VirtualDocument::new();
+        let mut direct_vdom = VDom::new(direct.root());
+        unsafe { direct_vdom.update(&mut direct, &second); }
+        assert_eq!(direct.to_string(), applier.backend().to_string());
+
+        // Listeners added from deserialized patches still receive events
+        let document = applier.backend();
+        let checkbox = document.query_selector("input").unwrap();
+        let change = Event { type_str: Some("change"), ..Event::default() };
+        assert_eq!(Msg::Toggled, unsafe { document.handle_event(checkbox, change) }.remove(0).0);
+        let button = document.query_selector("button").unwrap();
+        let click = Event { type_str: Some("click"), ..Event::default() };
+        assert_eq!(Msg::Clicked, unsafe { document.handle_event(button, click) }.remove(0).0);
+    }
+}
@@ -0,0 +1,237 @@
+use storage::{Storage, StorageArea, StorageError};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+type LoadFn<S> = Box<dyn Fn(&str, u32) -> Option<S>>;
+type SaveErrorFn = Box<dyn Fn(&StorageError)>;
+
+/// Describes how to save the application state to storage and restore it after a page reload.
+///
+/// Used by `web_render::run_persisted` and `TestApp::persisted`, which restore the state on
+/// startup and save it a short while after each update, so that a burst of updates (such as
+/// typing into a text field) only writes to storage once.
+///
+/// The state is stored under `key` together with `version`. When the stored version differs from
+/// the current one, `load` receives the old version so that it can migrate the saved data, or
+/// discard it by returning `None`.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::persist::Persistence;
+/// use domafic::storage::MemoryStorage;
+///
+/// // Version 1 saved a single todo, version 2 saves a list separated by newlines
+/// let persistence = Persistence::new("todos", 2,
+///     |todos: &Vec<String>| todos.join("\n"),
+///     |data, version| match version {
+///         1 => Some(vec![data.to_string()]),
+///         2 => Some(data.lines().map(String::from).collect()),
+///         _ => None,
+///     });
+///
+/// let mut storage = MemoryStorage::new();
+/// persistence.save(&vec!["milk".to_string(), "eggs".to_string()], &mut storage).unwrap();
+/// assert_eq!(Some(vec!["milk".to_string(), "eggs".to_string()]), persistence.restore(&storage));
+/// ```
+pub struct Persistence<S> {
+    key: String,
+    version: u32,
+    area: StorageArea,
+    debounce_millis: u32,
+    save: Box<dyn Fn(&S) -> String>,
+    load: LoadFn<S>,
+    save_error: Option<SaveErrorFn>,
+}
+
+impl<S> Persistence<S> {
+    /// Creates a `Persistence` storing the state under `key` in `localStorage`, converting it to
+    /// a string using `save` and back using `load`.
+    ///
+    /// `load` is called with the saved string and the `version` it was saved with.
+    pub fn new<K, Save, Load>(key: K, version: u32, save: Save, load: Load) -> Persistence<S>
+        where
+        K: Into<String>,
+        Save: Fn(&S) -> String + 'static,
+        Load: Fn(&str, u32) -> Option<S> + 'static
+    {
+        Persistence {
+            key: key.into(),
+            version,
+            area: StorageArea::Local,
+            debounce_millis: 500,
+            save: Box::new(save),
+            load: Box::new(load),
+            save_error: None,
+        }
+    }
+
+    /// Stores the state in `area` instead of `localStorage`.
+    pub fn area(mut self, area: StorageArea) -> Persistence<S> {
+        self.area = area;
+        self
+    }
+
+    /// Waits until no updates have happened for `millis` milliseconds before saving the state,
+    /// instead of the default of 500 milliseconds.
+    pub fn debounce(mut self, millis: u32) -> Persistence<S> {
+        self.debounce_millis = millis;
+        self
+    }
+
+    /// Calls `handler` when saving the state fails, such as when the storage area is full.
+    ///
+    /// Without a handler, `web_render::run_persisted` logs the failure to the browser console.
+    pub fn on_save_error<F: Fn(&StorageError) + 'static>(mut self, handler: F) -> Persistence<S> {
+        self.save_error = Some(Box::new(handler));
+        self
+    }
+
+    /// Returns the handler passed to `on_save_error`, if any.
+    pub fn save_error_handler(&self) -> Option<&dyn Fn(&StorageError)> {
+        self.save_error.as_deref()
+    }
+
+    /// Returns the storage key the state is saved under.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the current version of the saved state.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the storage area the state is saved in.
+    pub fn storage_area(&self) -> StorageArea {
+        self.area
+    }
+
+    /// Returns the number of milliseconds to wait after an update before saving the state.
+    pub fn debounce_millis(&self) -> u32 {
+        self.debounce_millis
+    }
+
+    /// Saves `state` with the current version to `storage`.
+    pub fn save(&self, state: &S, storage: &mut dyn Storage) -> Result<(), StorageError> {
+        storage.set(&self.key, &format!("{}:{}", self.version, (self.save)(state)))
+    }
+
+    /// Restores the state saved in `storage`, if any, migrating it from older versions.
+    ///
+    /// Returns `None` if nothing was saved, the saved value is malformed, or `load` rejects it.
+    pub fn restore(&self, storage: &dyn Storage) -> Option<S> {
+        let saved = storage.get(&self.key)?;
+        let separator = saved.find(':')?;
+        let version = saved[..separator].parse().ok()?;
+        (self.load)(&saved[separator + 1..], version)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<S: Serialize + DeserializeOwned + 'static> Persistence<S> {
+    /// Creates a `Persistence` saving the state as JSON, which discards state saved with any
+    /// other version.
+    ///
+    /// Requires the `serde` feature.
+    pub fn json<K: Into<String>>(key: K, version: u32) -> Persistence<S> {
+        Persistence::json_with_migration(key, version, |_, _| None)
+    }
+
+    /// Creates a `Persistence` saving the state as JSON, which calls `migrate` with the JSON
+    /// and version of state saved with any other version.
+    ///
+    /// Requires the `serde` feature.
+    pub fn json_with_migration<K, F>(key: K, version: u32, migrate: F) -> Persistence<S>
+        where K: Into<String>, F: Fn(serde_json::Value, u32) -> Option<S> + 'static
+    {
+        Persistence::new(key, version,
+            |state: &S| serde_json::to_string(state).expect("Failed to serialize state"),
+            move |data, saved_version| if saved_version == version {
+                serde_json::from_str(data).ok()
+            } else {
+                serde_json::from_str(data).ok().and_then(|value| migrate(value, saved_version))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Persistence;
+    use KeyIter;
+    use app::JsIo;
+    use storage::{MemoryStorage, Storage, StorageArea, StorageError};
+    use tags::div;
+    use test_app::TestApp;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn counter_persistence() -> Persistence<u32> {
+        Persistence::new("count", 1,
+            |count: &u32| count.to_string(),
+            |data, version| if version == 1 { data.parse().ok() } else { None })
+            .debounce(100)
+    }
+
+    #[test]
+    fn ignores_missing_and_malformed_state() {
+        let persistence = counter_persistence();
+        let mut storage = MemoryStorage::new();
+        assert_eq!(None, persistence.restore(&storage));
+        storage.set("count", "no version").unwrap();
+        assert_eq!(None, persistence.restore(&storage));
+        storage.set("count", "2:5").unwrap();
+        assert_eq!(None, persistence.restore(&storage));
+        storage.set("count", "1:5").unwrap();
+        assert_eq!(Some(5), persistence.restore(&storage));
+    }
+
+    #[test]
+    fn restores_state_saved_before_reload() {
+        fn update(count: &mut u32, _: (), _: KeyIter, _: &dyn JsIo<()>) {
+            *count += 1;
+        }
+        let render = |count: &u32| div(count.to_string());
+
+        let mut app = TestApp::persisted(
+            update, render, 0, counter_persistence(), MemoryStorage::new());
+        app.send(());
+        app.advance_time(50);
+        app.send(());
+        // Saving is debounced until no updates have happened for 100 milliseconds
+        app.advance_time(99);
+        assert_eq!(None, app.storage(StorageArea::Local).get("count"));
+        app.advance_time(1);
+        assert_eq!(Some("1:2".to_string()), app.storage(StorageArea::Local).get("count"));
+
+        let storage = app.storage(StorageArea::Local).clone();
+        let reloaded = TestApp::persisted(update, render, 0, counter_persistence(), storage);
+        assert_eq!(2, *reloaded.state());
+        assert_eq!("<div>2</div>", reloaded.document().to_string());
+    }
+
+    #[test]
+    fn reports_failed_saves() {
+        fn update(count: &mut u32, _: (), _: KeyIter, _: &dyn JsIo<()>) {
+            *count *= 10;
+        }
+        let render = |count: &u32| div(count.to_string());
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let persistence = {
+            let errors = errors.clone();
+            counter_persistence().on_save_error(move |error| errors.borrow_mut().push(*error))
+        };
+
+        // "count" and "1:10" fit, "1:100" doesn't
+        let mut app = TestApp::persisted(
+            update, render, 1, persistence, MemoryStorage::with_quota(9));
+        app.send(());
+        app.advance_time(100);
+        assert!(errors.borrow().is_empty());
+        app.send(());
+        app.advance_time(100);
+        assert_eq!(vec![StorageError::QuotaExceeded], *errors.borrow());
+        assert_eq!(Some("1:10".to_string()), app.storage(StorageArea::Local).get("count"));
+    }
+}
@@ -0,0 +1,273 @@
+use {DomNode, DomNodes, Event};
+use AttributeValue::{Bool, OwnedStr};
+use cmd::Cmd;
+use listener::on;
+use sub::Sub;
+use tags::{a, attributes};
+
+/// Name of the attribute marking the links created by `link`, whose clicks are handled by the
+/// application instead of the browser.
+pub const LINK_ATTRIBUTE: &str = "data-domafic-link";
+
+/// A URL split into its path, query and fragment, as used by `Route::parse`.
+///
+/// Parsing is purely textual, so it behaves the same on the server and in the browser.
+/// Components are not percent-decoded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Url<'a> {
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+}
+
+impl<'a> Url<'a> {
+    /// Splits `url` (such as `/todos/3?filter=done#top`) into its components.
+    ///
+    /// Any scheme and host (as in `https://example.com/todos`) are ignored.
+    pub fn parse(url: &'a str) -> Url<'a> {
+        let url = match url.find("://") {
+            Some(scheme_end) => {
+                let after_scheme = &url[scheme_end + 3..];
+                &after_scheme[after_scheme.find('/').unwrap_or(after_scheme.len())..]
+            }
+            None => url,
+        };
+        let (url, fragment) = match url.find('#') {
+            Some(index) => (&url[..index], Some(&url[index + 1..])),
+            None => (url, None),
+        };
+        let (path, query) = match url.find('?') {
+            Some(index) => (&url[..index], Some(&url[index + 1..])),
+            None => (url, None),
+        };
+        Url { path, query, fragment }
+    }
+
+    /// Returns the path, such as `/todos/3`.
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    /// Returns the non-empty segments of the path, such as `["todos", "3"]`.
+    pub fn segments(&self) -> Vec<&'a str> {
+        self.path.split('/').filter(|segment| !segment.is_empty()).collect()
+    }
+
+    /// Returns the query string without the leading `?`, if any.
+    pub fn query(&self) -> Option<&'a str> {
+        self.query
+    }
+
+    /// Returns the value of the first query parameter named `name`, if any.
+    ///
+    /// Parameters without a value (such as `?all`) have an empty value.
+    pub fn query_param(&self, name: &str) -> Option<&'a str> {
+        self.query?.split('&').find_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            if parts.next() == Some(name) {
+                Some(parts.next().unwrap_or(""))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the fragment without the leading `#`, if any.
+    pub fn fragment(&self) -> Option<&'a str> {
+        self.fragment
+    }
+}
+
+/// A page of an application which can be parsed from and formatted to a URL.
+///
+/// Routes are plain Rust, so the same `Route` can pick the page to render for a request path on
+/// the server and keep track of the current page in the browser.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::router::{Route, Url};
+///
+/// #[derive(Debug, Clone, PartialEq)]
+/// enum Page {
+///     Home,
+///     Todo(u32),
+/// }
+///
+/// impl Route for Page {
+///     fn parse(url: &Url) -> Option<Page> {
+///         match url.segments().as_slice() {
+///             [] => Some(Page::Home),
+///             ["todos", id] => id.parse().ok().map(Page::Todo),
+///             _ => None,
+///         }
+///     }
+///
+///     fn format(&self) -> String {
+///         match *self {
+///             Page::Home => "/".to_string(),
+///             Page::Todo(id) => format!("/todos/{}", id),
+///         }
+///     }
+/// }
+///
+/// assert_eq!(Some(Page::Todo(3)), Page::from_url("/todos/3?filter=done"));
+/// assert_eq!(None, Page::from_url("/missing"));
+/// assert_eq!("/todos/3", Page::Todo(3).format());
+/// ```
+pub trait Route: Sized {
+    /// Parses a route from `url`, returning `None` if it doesn't match any route.
+    fn parse(url: &Url) -> Option<Self>;
+
+    /// Formats the route as a URL.
+    fn format(&self) -> String;
+
+    /// Parses a route from the URL string `url`. See `Url::parse`.
+    fn from_url(url: &str) -> Option<Self> {
+        Self::parse(&Url::parse(url))
+    }
+}
+
+/// Navigates to `route`, adding an entry to the browser's history.
+///
+/// Subscriptions created by `on_route_change` are notified of the new route.
+pub fn navigate<R: Route, M>(route: &R) -> Cmd<M> {
+    Cmd::navigate(route.format())
+}
+
+/// Navigates to `route`, replacing the current entry of the browser's history.
+///
+/// Subscriptions created by `on_route_change` are notified of the new route.
+pub fn redirect<R: Route, M>(route: &R) -> Cmd<M> {
+    Cmd::replace_url(route.format())
+}
+
+/// Calls `handler` with the new route whenever the URL changes, either through `navigate` and
+/// `redirect` or through the browser's back and forward buttons.
+///
+/// `handler` receives `None` if the new URL doesn't match any route.
+pub fn on_route_change<R, M, F>(handler: F) -> Sub<M>
+    where R: Route, F: Fn(Option<R>) -> M + 'static
+{
+    Sub::on_popstate(move |url| handler(R::from_url(url)))
+}
+
+/// Creates an `a` element linking to `route`.
+///
+/// The link has a real `href`, so it works when rendered on the server and can be opened in a
+/// new tab. Plain left clicks are handled by the application instead: the browser doesn't follow
+/// the link, and `on_click` is called with the route to produce a message. The updater would
+/// usually respond to that message with `navigate`.
+pub fn link<R, M, F, C>(route: R, on_click: F, children: C) -> impl DomNode<M>
+    where R: Route + Clone, F: Fn(R) -> M, C: DomNodes<M>
+{
+    a((
+        attributes([("href", OwnedStr(route.format())), (LINK_ATTRIBUTE, Bool(true))]),
+        on("click", move |_: Event| on_click(route.clone())),
+        children,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{link, navigate, on_route_change, Route, Url};
+    use {DomNode, KeyIter};
+    use app::{with_subscriptions, JsIo};
+    use cmd::Cmd;
+    use tags::{div, h1};
+    use test_app::TestApp;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Page {
+        Home,
+        Todo(u32),
+        Search(String),
+    }
+
+    impl Route for Page {
+        fn parse(url: &Url) -> Option<Page> {
+            match url.segments().as_slice() {
+                [] => Some(Page::Home),
+                ["todos", id] => id.parse().ok().map(Page::Todo),
+                ["search"] => url.query_param("q").map(|q| Page::Search(q.to_string())),
+                _ => None,
+            }
+        }
+
+        fn format(&self) -> String {
+            match *self {
+                Page::Home => "/".to_string(),
+                Page::Todo(id) => format!("/todos/{}", id),
+                Page::Search(ref q) => format!("/search?q={}", q),
+            }
+        }
+    }
+
+    #[test]
+    fn splits_urls() {
+        let url = Url::parse("https://example.com/todos/3/?filter=done&all#top");
+        assert_eq!("/todos/3/", url.path());
+        assert_eq!(vec!["todos", "3"], url.segments());
+        assert_eq!(Some("done"), url.query_param("filter"));
+        assert_eq!(Some(""), url.query_param("all"));
+        assert_eq!(None, url.query_param("missing"));
+        assert_eq!(Some("top"), url.fragment());
+
+        let url = Url::parse("/");
+        assert_eq!((None, None), (url.query(), url.fragment()));
+        assert!(url.segments().is_empty());
+    }
+
+    #[test]
+    fn round_trips_routes() {
+        for page in [Page::Home, Page::Todo(7), Page::Search("milk".to_string())] {
+            assert_eq!(Some(page.clone()), Page::from_url(&page.format()));
+        }
+        assert_eq!(None, Page::from_url("/todos/x"));
+    }
+
+    #[test]
+    fn navigates_between_pages() {
+        enum Msg {
+            Go(Page),
+            Changed(Option<Page>),
+        }
+
+        fn update(state: &mut Option<Page>, msg: Msg, _: KeyIter, _: &dyn JsIo<Msg>) -> Cmd<Msg> {
+            match msg {
+                Msg::Go(page) => navigate(&page),
+                Msg::Changed(page) => {
+                    *state = page;
+                    Cmd::none()
+                }
+            }
+        }
+
+        let render = |state: &Option<Page>| div((
+            h1(format!("{:?}", state)),
+            link(Page::Todo(1), Msg::Go, "First todo"),
+        ));
+        let subscriptions = |_: &Option<Page>| on_route_change(Msg::Changed);
+        let mut app = TestApp::new(
+            with_subscriptions(update, subscriptions), render, Some(Page::Home));
+        assert_eq!(
+            "<div><h1>Some(Home)</h1>\
+            <a href=\"/todos/1\" data-domafic-link>First todo</a></div>",
+            app.rendered().displayable().to_string());
+
+        app.click("a");
+        assert_eq!("/todos/1", app.location());
+        assert_eq!(Some(Page::Todo(1)), *app.state());
+
+        app.send(Msg::Go(Page::Search("milk".to_string())));
+        assert_eq!(Some(Page::Search("milk".to_string())), *app.state());
+
+        app.back();
+        assert_eq!("/todos/1", app.location());
+        assert_eq!(Some(Page::Todo(1)), *app.state());
+        app.back();
+        assert_eq!(Some(Page::Home), *app.state());
+        app.forward();
+        assert_eq!(Some(Page::Todo(1)), *app.state());
+    }
+}
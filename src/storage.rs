@@ -0,0 +1,348 @@
+use cmd::{Cmd, Effect};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// One of the browser's storage areas
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum StorageArea {
+    /// `localStorage`, which persists across browser sessions
+    Local,
+    /// `sessionStorage`, which is cleared when the page's tab is closed
+    Session,
+}
+
+/// Error writing to a storage area
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum StorageError {
+    /// The storage area is full
+    QuotaExceeded,
+    /// The storage area can't be used, such as when disabled by the user's privacy settings
+    Unavailable,
+}
+
+/// A key-value store with the operations of the browser's `Storage` interface.
+///
+/// Implemented by `web_render::WebStorage` in the browser and by `MemoryStorage` natively.
+pub trait Storage {
+    /// Returns the value stored under `key`, if any.
+    fn get(&self, key: &str) -> Option<String>;
+    /// Stores `value` under `key`, replacing any previous value.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError>;
+    /// Removes the value stored under `key`, if any.
+    fn remove(&mut self, key: &str);
+    /// Removes all values.
+    fn clear(&mut self);
+    /// Returns the keys of all stored values.
+    fn keys(&self) -> Vec<String>;
+}
+
+/// A `Storage` which keeps its values in memory, used by `TestApp` in place of the browser's
+/// storage areas.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::storage::{MemoryStorage, Storage, StorageError};
+///
+/// let mut storage = MemoryStorage::with_quota(12);
+/// storage.set("theme", "dark").unwrap();
+/// assert_eq!(Some("dark".to_string()), storage.get("theme"));
+/// assert_eq!(Err(StorageError::QuotaExceeded), storage.set("theme", "solarized"));
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MemoryStorage {
+    items: BTreeMap<String, String>,
+    quota: Option<usize>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty `MemoryStorage` without a quota.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Creates an empty `MemoryStorage` which fails with `StorageError::QuotaExceeded` when the
+    /// total length of its keys and values would exceed `bytes`.
+    pub fn with_quota(bytes: usize) -> MemoryStorage {
+        MemoryStorage { items: BTreeMap::new(), quota: Some(bytes) }
+    }
+
+    /// Returns the number of stored values.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns whether no values are stored.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn size(&self) -> usize {
+        self.items.iter().map(|(key, value)| key.len() + value.len()).sum()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        self.items.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        if let Some(quota) = self.quota {
+            let replaced = self.items.get(key).map_or(0, |old| key.len() + old.len());
+            if self.size() - replaced + key.len() + value.len() > quota {
+                return Err(StorageError::QuotaExceeded);
+            }
+        }
+        self.items.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.items.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.items.keys().cloned().collect()
+    }
+}
+
+type SetHandler<Message> = Box<dyn FnOnce(Result<(), StorageError>) -> Message>;
+
+/// An operation on a storage area requested by a `Cmd`, along with the handler converting its
+/// result into a message.
+pub enum StorageOperation<Message> {
+    /// Read the value stored under `key`
+    Get {
+        /// Storage key
+        key: String,
+        /// Handler converting the value, if any, into a message
+        handler: Box<dyn FnOnce(Option<String>) -> Message>,
+    },
+    /// Store `value` under `key`
+    Set {
+        /// Storage key
+        key: String,
+        /// Value to store
+        value: String,
+        /// Optional handler converting the result into a message
+        handler: Option<SetHandler<Message>>,
+    },
+    /// Remove the value stored under `key`
+    Remove {
+        /// Storage key
+        key: String,
+    },
+    /// Remove all values
+    Clear,
+    /// List the keys of all stored values
+    Keys {
+        /// Handler converting the keys into a message
+        handler: Box<dyn FnOnce(Vec<String>) -> Message>,
+    },
+}
+
+impl<M> StorageOperation<M> {
+    /// Performs the operation on `storage`, returning the message produced by its handler, if any.
+    pub fn perform(self, storage: &mut dyn Storage) -> Option<M> {
+        match self {
+            StorageOperation::Get { key, handler } => Some(handler(storage.get(&key))),
+            StorageOperation::Set { key, value, handler } => {
+                let result = storage.set(&key, &value);
+                handler.map(|handler| handler(result))
+            }
+            StorageOperation::Remove { key } => {
+                storage.remove(&key);
+                None
+            }
+            StorageOperation::Clear => {
+                storage.clear();
+                None
+            }
+            StorageOperation::Keys { handler } => Some(handler(storage.keys())),
+        }
+    }
+}
+
+impl<M> fmt::Debug for StorageOperation<M> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageOperation::Get { ref key, .. } =>
+                formatter.debug_struct("Get").field("key", key).finish(),
+            StorageOperation::Set { ref key, ref value, .. } => formatter.debug_struct("Set")
+                .field("key", key)
+                .field("value", value)
+                .finish(),
+            StorageOperation::Remove { ref key } =>
+                formatter.debug_struct("Remove").field("key", key).finish(),
+            StorageOperation::Clear => formatter.write_str("Clear"),
+            StorageOperation::Keys { .. } => formatter.write_str("Keys"),
+        }
+    }
+}
+
+fn storage_cmd<M>(area: StorageArea, operation: StorageOperation<M>) -> Cmd<M> {
+    Cmd::from_effect(Effect::Storage { area, operation })
+}
+
+/// Reads the value stored under `key` in `area`, passing the message produced by `handler` to
+/// the updater.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::KeyIter;
+/// use domafic::app::JsIo;
+/// use domafic::cmd::Cmd;
+/// use domafic::storage::{self, StorageArea};
+/// use domafic::tags::div;
+/// use domafic::test_app::TestApp;
+///
+/// enum Msg {
+///     Load,
+///     Loaded(Option<String>),
+/// }
+///
+/// let update = |theme: &mut String, msg: Msg, _: KeyIter, _: &JsIo<Msg>| match msg {
+///     Msg::Load => storage::get(StorageArea::Local, "theme", Msg::Loaded),
+///     Msg::Loaded(stored) => {
+///         *theme = stored.unwrap_or_else(|| "light".to_string());
+///         Cmd::none()
+///     }
+/// };
+///
+/// let mut app = TestApp::new(update, |theme: &String| div(theme.clone()), String::new());
+/// app.send(Msg::Load);
+/// assert_eq!("light", app.state());
+/// ```
+pub fn get<M, K, F>(area: StorageArea, key: K, handler: F) -> Cmd<M>
+    where K: Into<String>, F: FnOnce(Option<String>) -> M + 'static
+{
+    storage_cmd(area, StorageOperation::Get { key: key.into(), handler: Box::new(handler) })
+}
+
+/// Stores `value` under `key` in `area`, ignoring failures.
+pub fn set<M, K, V>(area: StorageArea, key: K, value: V) -> Cmd<M>
+    where K: Into<String>, V: Into<String>
+{
+    storage_cmd(area, StorageOperation::Set { key: key.into(), value: value.into(), handler: None })
+}
+
+/// Stores `value` under `key` in `area`, passing the message produced by `handler` from the
+/// result to the updater.
+pub fn try_set<M, K, V, F>(area: StorageArea, key: K, value: V, handler: F) -> Cmd<M>
+    where K: Into<String>, V: Into<String>, F: FnOnce(Result<(), StorageError>) -> M + 'static
+{
+    storage_cmd(area, StorageOperation::Set {
+        key: key.into(),
+        value: value.into(),
+        handler: Some(Box::new(handler)),
+    })
+}
+
+/// Removes the value stored under `key` in `area`.
+pub fn remove<M, K: Into<String>>(area: StorageArea, key: K) -> Cmd<M> {
+    storage_cmd(area, StorageOperation::Remove { key: key.into() })
+}
+
+/// Removes all values stored in `area`.
+pub fn clear<M>(area: StorageArea) -> Cmd<M> {
+    storage_cmd(area, StorageOperation::Clear)
+}
+
+/// Lists the keys of all values stored in `area`, passing the message produced by `handler` to
+/// the updater.
+pub fn keys<M, F>(area: StorageArea, handler: F) -> Cmd<M>
+    where F: FnOnce(Vec<String>) -> M + 'static
+{
+    storage_cmd(area, StorageOperation::Keys { handler: Box::new(handler) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStorage, Storage, StorageArea, StorageError};
+    use KeyIter;
+    use app::JsIo;
+    use cmd::Cmd;
+    use tags::div;
+    use test_app::TestApp;
+
+    #[test]
+    fn enforces_memory_quota() {
+        let mut storage = MemoryStorage::with_quota(10);
+        storage.set("a", "1234").unwrap();
+        storage.set("b", "1234").unwrap();
+        assert_eq!(Err(StorageError::QuotaExceeded), storage.set("c", "1"));
+        // Replacing a value only counts the difference in size
+        storage.set("a", "4321").unwrap();
+        storage.remove("b");
+        storage.set("c", "1").unwrap();
+        assert_eq!(vec!["a", "c"], storage.keys());
+        storage.clear();
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn performs_storage_commands() {
+        enum Msg {
+            Save(String),
+            Saved(Result<(), StorageError>),
+            List,
+            Listed(Vec<String>),
+            Forget,
+        }
+
+        #[derive(Default)]
+        struct State {
+            results: Vec<Result<(), StorageError>>,
+            keys: Vec<String>,
+        }
+
+        fn update(state: &mut State, msg: Msg, _: KeyIter, _: &dyn JsIo<Msg>) -> Cmd<Msg> {
+            match msg {
+                Msg::Save(draft) => Cmd::batch(vec![
+                    super::try_set(StorageArea::Local, "draft", draft, Msg::Saved),
+                    super::set(StorageArea::Session, "tab", "1"),
+                ]),
+                Msg::Saved(result) => {
+                    state.results.push(result);
+                    Cmd::none()
+                }
+                Msg::List => super::keys(StorageArea::Local, Msg::Listed),
+                Msg::Listed(keys) => {
+                    state.keys = keys;
+                    Cmd::none()
+                }
+                Msg::Forget => Cmd::batch(vec![
+                    super::remove(StorageArea::Local, "draft"),
+                    super::clear(StorageArea::Session),
+                ]),
+            }
+        }
+
+        let mut app = TestApp::new(update, |_: &State| div(()), State::default());
+        app.storage_mut(StorageArea::Local).set("theme", "dark").unwrap();
+
+        app.send(Msg::Save("hello".to_string()));
+        assert_eq!(Some("hello".to_string()), app.storage(StorageArea::Local).get("draft"));
+        assert_eq!(Some("1".to_string()), app.storage(StorageArea::Session).get("tab"));
+        assert_eq!(vec![Ok(())], app.state().results);
+
+        app.send(Msg::List);
+        assert_eq!(vec!["draft", "theme"], app.state().keys);
+
+        app.send(Msg::Forget);
+        assert_eq!(vec!["theme"], app.storage(StorageArea::Local).keys());
+        assert!(app.storage(StorageArea::Session).is_empty());
+
+        *app.storage_mut(StorageArea::Local) = MemoryStorage::with_quota(4);
+        app.send(Msg::Save("too long".to_string()));
+        assert_eq!(Some(&Err(StorageError::QuotaExceeded)), app.state().results.last());
+    }
+}
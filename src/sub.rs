@@ -0,0 +1,408 @@
+use listener::Event;
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::fmt;
+use std::mem;
+
+/// A description of the time-based sources and global events that an application is listening to.
+///
+/// Applications declare their subscriptions as a function of their state using
+/// `Updater::subscriptions` (or `app::with_subscriptions`). After every update, the runtime
+/// compares the new subscriptions with the running ones: subscriptions with the same kind and key
+/// keep running (using their new handlers), new ones are started and missing ones are stopped.
+///
+/// Timer handlers receive the current time in milliseconds (`performance.now()` in the browser),
+/// and global event handlers receive the event's payload, such as the new size of the window.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::sub::{Sub, SubscriptionKind};
+///
+/// enum Msg {
+///     Tick,
+///     Frame(f64),
+/// }
+///
+/// struct State {
+///     running: bool,
+///     animating: bool,
+/// }
+///
+/// let subscriptions = |state: &State| Sub::batch(vec![
+///     if state.running { Sub::interval(1000, |_| Msg::Tick) } else { Sub::none() },
+///     if state.animating { Sub::animation_frame(Msg::Frame) } else { Sub::none() },
+/// ]);
+///
+/// let sub = subscriptions(&State { running: true, animating: false });
+/// let kinds: Vec<_> = sub.subscriptions().iter().map(|sub| sub.kind()).collect();
+/// assert_eq!(vec![SubscriptionKind::Interval(1000)], kinds);
+/// ```
+pub struct Sub<Message> {
+    subscriptions: Vec<Subscription<Message>>,
+}
+
+/// The kind of time-based source of a `Subscription`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SubscriptionKind {
+    /// Fires every given number of milliseconds
+    Interval(u32),
+    /// Fires once, the given number of milliseconds after the subscription starts
+    Timeout(u32),
+    /// Fires before every repaint of the page, via `requestAnimationFrame`
+    AnimationFrame,
+    /// Fires on a global `window` or `document` event
+    Event(GlobalEventKind),
+}
+
+/// A global event which can be subscribed to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum GlobalEventKind {
+    /// `resize` events of the `window`
+    Resize,
+    /// `scroll` events of the `window`
+    Scroll,
+    /// `popstate` events of the `window`, fired when the user navigates through the history
+    PopState,
+    /// `online` and `offline` events of the `window`
+    Online,
+    /// `visibilitychange` events of the `document`
+    VisibilityChange,
+    /// `keydown` events reaching the `document`, such as for global keyboard shortcuts
+    KeyDown,
+}
+
+/// The payload delivered to a `Subscription` when it fires.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubscriptionEvent<'a> {
+    /// A timer or animation frame fired at the given time, in milliseconds
+    Time(f64),
+    /// The window was resized to the given inner size, in pixels
+    Resize {
+        /// New inner width of the window
+        width: i32,
+        /// New inner height of the window
+        height: i32,
+    },
+    /// The window was scrolled to the given position, in pixels
+    Scroll {
+        /// Horizontal scroll position
+        x: i32,
+        /// Vertical scroll position
+        y: i32,
+    },
+    /// The user navigated through the history to `url` (path, query and fragment)
+    PopState {
+        /// The new URL
+        url: &'a str,
+    },
+    /// The browser went online (`true`) or offline (`false`)
+    Online(bool),
+    /// The page became visible (`true`) or hidden (`false`)
+    VisibilityChange(bool),
+    /// A key was pressed. The event's `which_keycode` and modifier keys are set.
+    KeyDown(Event<'a>),
+}
+
+impl<'a> SubscriptionEvent<'a> {
+    /// Returns the kind of subscription that receives this event.
+    ///
+    /// Timer events are received by `Interval`, `Timeout` and `AnimationFrame` subscriptions
+    /// alike, so `Time` events return `None`.
+    pub fn kind(&self) -> Option<GlobalEventKind> {
+        match *self {
+            SubscriptionEvent::Time(_) => None,
+            SubscriptionEvent::Resize { .. } => Some(GlobalEventKind::Resize),
+            SubscriptionEvent::Scroll { .. } => Some(GlobalEventKind::Scroll),
+            SubscriptionEvent::PopState { .. } => Some(GlobalEventKind::PopState),
+            SubscriptionEvent::Online(_) => Some(GlobalEventKind::Online),
+            SubscriptionEvent::VisibilityChange(_) => Some(GlobalEventKind::VisibilityChange),
+            SubscriptionEvent::KeyDown(_) => Some(GlobalEventKind::KeyDown),
+        }
+    }
+}
+
+/// A single time-based source of messages.
+pub struct Subscription<Message> {
+    kind: SubscriptionKind,
+    key: Option<u32>,
+    handler: Box<dyn Fn(SubscriptionEvent) -> Message>,
+}
+
+impl<M> Subscription<M> {
+    /// Returns the kind of source that this subscription listens to.
+    pub fn kind(&self) -> SubscriptionKind {
+        self.kind
+    }
+
+    /// Returns the key of this subscription, if any.
+    pub fn key(&self) -> Option<u32> {
+        self.key
+    }
+
+    /// Creates the message for this subscription firing with `event`.
+    ///
+    /// Panics if `event` is of a different kind than the subscription: timer subscriptions
+    /// expect `SubscriptionEvent::Time`, and global event subscriptions expect the matching
+    /// payload.
+    pub fn handle(&self, event: SubscriptionEvent) -> M {
+        (self.handler)(event)
+    }
+
+    fn is_same_source(&self, other: &Subscription<M>) -> bool {
+        self.kind == other.kind && self.key == other.key
+    }
+}
+
+impl<M> fmt::Debug for Subscription<M> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("Subscription")
+            .field("kind", &self.kind)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl<M> Sub<M> {
+    /// No subscriptions.
+    pub fn none() -> Sub<M> {
+        Sub { subscriptions: Vec::new() }
+    }
+
+    /// Combines several `Sub`s into one.
+    pub fn batch<I: IntoIterator<Item=Sub<M>>>(subs: I) -> Sub<M> {
+        Sub { subscriptions: subs.into_iter().flat_map(|sub| sub.subscriptions).collect() }
+    }
+
+    /// Calls `handler` every `millis` milliseconds.
+    pub fn interval<F: Fn(f64) -> M + 'static>(millis: u32, handler: F) -> Sub<M> {
+        Sub::timer(SubscriptionKind::Interval(millis), handler)
+    }
+
+    /// Calls `handler` once, `millis` milliseconds after the subscription starts.
+    ///
+    /// The timeout doesn't fire again while it remains subscribed. Unsubscribing and subscribing
+    /// again restarts it.
+    pub fn timeout<F: Fn(f64) -> M + 'static>(millis: u32, handler: F) -> Sub<M> {
+        Sub::timer(SubscriptionKind::Timeout(millis), handler)
+    }
+
+    /// Calls `handler` with the frame's timestamp before every repaint of the page.
+    pub fn animation_frame<F: Fn(f64) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::timer(SubscriptionKind::AnimationFrame, handler)
+    }
+
+    /// Calls `handler` with the new inner width and height of the window whenever it's resized.
+    pub fn on_resize<F: Fn(i32, i32) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::single(SubscriptionKind::Event(GlobalEventKind::Resize), move |event| match event {
+            SubscriptionEvent::Resize { width, height } => handler(width, height),
+            event => panic!("Resize subscription received {:?}", event),
+        })
+    }
+
+    /// Calls `handler` with the horizontal and vertical scroll position of the window whenever
+    /// it's scrolled.
+    pub fn on_scroll<F: Fn(i32, i32) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::single(SubscriptionKind::Event(GlobalEventKind::Scroll), move |event| match event {
+            SubscriptionEvent::Scroll { x, y } => handler(x, y),
+            event => panic!("Scroll subscription received {:?}", event),
+        })
+    }
+
+    /// Calls `handler` with the new URL (path, query and fragment) whenever the user navigates
+    /// through the history, such as with the browser's back button.
+    pub fn on_popstate<F: Fn(&str) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::single(SubscriptionKind::Event(GlobalEventKind::PopState), move |event| match event {
+            SubscriptionEvent::PopState { url } => handler(url),
+            event => panic!("PopState subscription received {:?}", event),
+        })
+    }
+
+    /// Calls `handler` with `true` when the browser goes online and `false` when it goes offline.
+    pub fn on_online_change<F: Fn(bool) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::single(SubscriptionKind::Event(GlobalEventKind::Online), move |event| match event {
+            SubscriptionEvent::Online(online) => handler(online),
+            event => panic!("Online subscription received {:?}", event),
+        })
+    }
+
+    /// Calls `handler` with `true` when the page becomes visible and `false` when it's hidden,
+    /// such as when the user switches tabs.
+    pub fn on_visibility_change<F: Fn(bool) -> M + 'static>(handler: F) -> Sub<M> {
+        let kind = SubscriptionKind::Event(GlobalEventKind::VisibilityChange);
+        Sub::single(kind, move |event| match event {
+            SubscriptionEvent::VisibilityChange(visible) => handler(visible),
+            event => panic!("VisibilityChange subscription received {:?}", event),
+        })
+    }
+
+    /// Calls `handler` for every key pressed anywhere in the document, such as for global
+    /// keyboard shortcuts.
+    pub fn on_keydown<F: Fn(Event) -> M + 'static>(handler: F) -> Sub<M> {
+        Sub::single(SubscriptionKind::Event(GlobalEventKind::KeyDown), move |event| match event {
+            SubscriptionEvent::KeyDown(event) => handler(event),
+            event => panic!("KeyDown subscription received {:?}", event),
+        })
+    }
+
+    /// Sets the key of every subscription in this `Sub`.
+    ///
+    /// Subscriptions of the same kind are told apart by their keys, so a keyed subscription
+    /// keeps running even if other subscriptions of the same kind come and go.
+    pub fn with_key(mut self, key: u32) -> Sub<M> {
+        for subscription in &mut self.subscriptions {
+            subscription.key = Some(key);
+        }
+        self
+    }
+
+    /// Returns the individual subscriptions.
+    pub fn subscriptions(&self) -> &[Subscription<M>] {
+        &self.subscriptions
+    }
+
+    fn timer<F: Fn(f64) -> M + 'static>(kind: SubscriptionKind, handler: F) -> Sub<M> {
+        Sub::single(kind, move |event| match event {
+            SubscriptionEvent::Time(time) => handler(time),
+            event => panic!("Timer subscription received {:?}", event),
+        })
+    }
+
+    fn single<F>(kind: SubscriptionKind, handler: F) -> Sub<M>
+        where F: Fn(SubscriptionEvent) -> M + 'static
+    {
+        Sub { subscriptions: vec![Subscription { kind, key: None, handler: Box::new(handler) }] }
+    }
+}
+
+impl<M> Default for Sub<M> {
+    fn default() -> Sub<M> {
+        Sub::none()
+    }
+}
+
+impl<M> fmt::Debug for Sub<M> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_tuple("Sub").field(&self.subscriptions).finish()
+    }
+}
+
+/// Ids of the subscriptions stopped and started by `RunningSubscriptions::update`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct SubscriptionChanges {
+    /// Ids of the subscriptions that are no longer present
+    pub stopped: Vec<u32>,
+    /// Ids of the new subscriptions
+    pub started: Vec<u32>,
+}
+
+/// The subscriptions currently running in a runtime, each identified by a unique id.
+///
+/// This is used by the runtimes (`web_render` and `TestApp`) to start and stop subscriptions as
+/// they change across updates.
+#[derive(Debug)]
+pub struct RunningSubscriptions<Message> {
+    running: Vec<(u32, Subscription<Message>)>,
+    next_id: u32,
+}
+
+impl<M> RunningSubscriptions<M> {
+    /// Creates an empty set of running subscriptions.
+    pub fn new() -> RunningSubscriptions<M> {
+        RunningSubscriptions { running: Vec::new(), next_id: 0 }
+    }
+
+    /// Replaces the running subscriptions with those in `sub`, returning the ids of the
+    /// subscriptions that the runtime needs to stop and start.
+    ///
+    /// Running subscriptions with the same kind and key as a new subscription keep their ids and
+    /// switch to the new handler.
+    pub fn update(&mut self, sub: Sub<M>) -> SubscriptionChanges {
+        let mut old = mem::take(&mut self.running);
+        let mut started = Vec::new();
+        for subscription in sub.subscriptions {
+            let id = match old.iter().position(|(_, old)| old.is_same_source(&subscription)) {
+                Some(index) => old.remove(index).0,
+                None => {
+                    let id = self.next_id;
+                    self.next_id = self.next_id.wrapping_add(1);
+                    started.push(id);
+                    id
+                }
+            };
+            self.running.push((id, subscription));
+        }
+        SubscriptionChanges {
+            stopped: old.into_iter().map(|(id, _)| id).collect(),
+            started,
+        }
+    }
+
+    /// Returns the running subscription with the given id.
+    pub fn get(&self, id: u32) -> Option<&Subscription<M>> {
+        self.running.iter().find(|running| running.0 == id).map(|running| &running.1)
+    }
+
+    /// Returns the ids and subscriptions of all running subscriptions.
+    pub fn iter(&self) -> impl Iterator<Item=(u32, &Subscription<M>)> {
+        self.running.iter().map(|(id, subscription)| (*id, subscription))
+    }
+}
+
+impl<M> Default for RunningSubscriptions<M> {
+    fn default() -> RunningSubscriptions<M> {
+        RunningSubscriptions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RunningSubscriptions, Sub, SubscriptionEvent, SubscriptionKind};
+
+    fn update(running: &mut RunningSubscriptions<u32>, sub: Sub<u32>) -> Vec<String> {
+        let changes = running.update(sub);
+        changes.stopped.iter().map(|id| format!("stop {}", id))
+            .chain(changes.started.iter().map(|&id|
+                format!("start {} {:?}", id, running.get(id).unwrap().kind())))
+            .collect()
+    }
+
+    #[test]
+    fn starts_and_stops_changed_subscriptions() {
+        let mut running = RunningSubscriptions::new();
+        assert_eq!(update(&mut running, Sub::batch(vec![
+            Sub::interval(100, |_| 1),
+            Sub::animation_frame(|time| time as u32),
+        ])), vec!["start 0 Interval(100)", "start 1 AnimationFrame"]);
+
+        // Unchanged subscriptions keep running with their new handlers
+        assert!(update(&mut running, Sub::batch(vec![
+            Sub::animation_frame(|_| 7),
+            Sub::interval(100, |_| 2),
+        ])).is_empty());
+        assert_eq!(2, running.get(0).unwrap().handle(SubscriptionEvent::Time(0.0)));
+        assert_eq!(7, running.get(1).unwrap().handle(SubscriptionEvent::Time(0.0)));
+
+        assert_eq!(update(&mut running, Sub::batch(vec![
+            Sub::interval(200, |_| 3),
+            Sub::animation_frame(|_| 7),
+        ])), vec!["stop 0", "start 2 Interval(200)"]);
+        assert_eq!(vec![SubscriptionKind::Interval(200), SubscriptionKind::AnimationFrame],
+            running.iter().map(|(_, subscription)| subscription.kind()).collect::<Vec<_>>());
+
+        assert_eq!(update(&mut running, Sub::none()), vec!["stop 2", "stop 1"]);
+    }
+
+    #[test]
+    fn keys_distinguish_subscriptions() {
+        let mut running = RunningSubscriptions::new();
+        update(&mut running, Sub::batch(vec![
+            Sub::timeout(50, |_| 1).with_key(1),
+            Sub::timeout(50, |_| 2).with_key(2),
+        ]));
+        assert_eq!(
+            update(&mut running, Sub::timeout(50, |_| 2).with_key(2)),
+            vec!["stop 0"]
+        );
+    }
+}
@@ -7,7 +7,7 @@
 ///
 /// TODO
 
-use {DomNode, DomNodes, DomValue, KeyValue, Listeners};
+use {DomNode, DomNodes, DomValue, Key, KeyValue, Listeners};
 use processors::{DomNodeProcessor, EmptyListeners};
 
 use opt_std::marker::PhantomData;
@@ -27,7 +27,7 @@ pub struct TagProperties<
     Listens: Listeners<Message>>
 {
     children: Children,
-    key: Option<u32>,
+    key: Option<Key>,
     attributes: Attributes,
     listeners: Listens,
     msg_marker: PhantomData<Message>,
@@ -165,7 +165,7 @@ impl<M, C: DomNodes<M>, A: AsRef<[KeyValue]>, L: Listeners<M>>
 }
 
 /// A tag element, such as `div` or `span`.
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Tag<
     Message,
     Children: DomNodes<Message>,
@@ -174,7 +174,7 @@ pub struct Tag<
 {
     tagname: &'static str,
     children: Children,
-    key: Option<u32>,
+    key: Option<Key>,
     attributes: Attributes,
     listeners: L,
     msg_marker: PhantomData<Message>,
@@ -194,7 +194,7 @@ impl<M, C: DomNodes<M>, A: AsRef<[KeyValue]>, L: Listeners<M>> DomNode<M> for Ta
     type Children = C;
     type Listeners = L;
     type WithoutListeners = Tag<M, C, A, EmptyListeners>;
-    fn key(&self) -> Option<u32> { self.key }
+    fn key(&self) -> Option<&Key> { self.key.as_ref() }
     fn get_attribute(&self, index: usize) -> Option<&KeyValue> {
         self.attributes.as_ref().get(index)
     }
@@ -285,9 +285,36 @@ impl_tags!(
     bdo, big, blockquote, body, br, button, canvas, caption, center, cite, code, col, colgroup,
     datalist, dd, del, details, dfn, dialog, dir, div, dl, dt, em, embed, fieldset,
     figcaption, figure, font, footer, form, frame, framset, h1, h2, h3, h4, h5, h6, head,
-    header, hr, i, iframe, img, input, ins, kbd, keygen, label, legend, li, link, main, map,
+    header, hr, html, i, iframe, img, input, ins, kbd, keygen, label, legend, li, link, main, map,
     mark, menu, menuitem, meta, meter, nav, noframes, noscript, object, ol, optgroup, option,
     output, p, param, pre, progress, q, rp, rt, ruby, s, samp, script, section, select, small,
     source, span, strike, strong, style, sub, summary, sup, table, tbody, td, textarea, tfoot,
     th, thead, time, title, tr, track, tt, u, ul, var, video, wbr
 );
+
+/// Returns whether `tagname` is an HTML5 void element, such as `br` or `img`.
+///
+/// Void elements cannot have any contents, and are written to HTML without a closing tag.
+pub fn is_void_element(tagname: &str) -> bool {
+    matches!(tagname,
+        "area" | "base" | "br" | "col" | "embed" | "hr" | "img" | "input" | "keygen" | "link" |
+        "meta" | "param" | "source" | "track" | "wbr")
+}
+
+/// Returns whether `tagname` is an HTML5 raw text element (`script` or `style`).
+///
+/// The text inside of raw text elements isn't escaped when written to HTML. See `RawText`.
+pub fn is_raw_text_element(tagname: &str) -> bool {
+    matches!(tagname, "script" | "style")
+}
+
+/// Returns whether `tagname` is an inline element, such as `span` or `b`, whose contents flow
+/// within a line of text.
+pub fn is_inline_element(tagname: &str) -> bool {
+    matches!(tagname,
+        "a" | "abbr" | "acronym" | "b" | "bdi" | "bdo" | "big" | "br" | "button" | "cite" |
+        "code" | "dfn" | "em" | "font" | "i" | "img" | "input" | "kbd" | "label" | "mark" |
+        "meter" | "output" | "progress" | "q" | "rp" | "rt" | "ruby" | "s" | "samp" | "select" |
+        "small" | "span" | "strike" | "strong" | "sub" | "sup" | "textarea" | "time" | "tt" |
+        "u" | "var" | "wbr")
+}
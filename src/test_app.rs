@@ -0,0 +1,1191 @@
+use {DomNode, Event};
+use app::{Updater, Renderer, JsIo, HttpRequest, HttpResult, HttpResponseHandler};
+use app::{HttpError, HttpProgress, HttpRequestHandle, RenderMode};
+use app::{
+    OwnedWebSocketFrame, WebSocket, WebSocketConnection, WebSocketEvent, WebSocketFrame,
+    WebSocketHandler,
+};
+use cmd::{Cmd, Effect};
+use html_document::Head;
+use keys::Keys;
+use persist::Persistence;
+use storage::{MemoryStorage, StorageArea};
+use sub::{GlobalEventKind, RunningSubscriptions, SubscriptionEvent, SubscriptionKind};
+use vdom::{HydrationMismatch, VDom};
+use virtual_document::{NodeId, VirtualDocument};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::cell::{Cell, Ref, RefCell};
+use std::rc::Rc;
+
+/// An HTTP request issued by the updater of a `TestApp` that has not yet been answered.
+pub struct TestHttpRequest<Message> {
+    /// HTTP Method ("GET", "POST", etc.)
+    pub method: String,
+    /// A list of HTTP header (key, value) pairs
+    pub headers: Vec<(String, String)>,
+    /// Request URL
+    pub url: String,
+    /// Request body
+    pub body: Vec<u8>,
+    /// Optional request timeout in milliseconds
+    pub timeout_millis: Option<u32>,
+    aborted: Rc<Cell<bool>>,
+    handler: Box<dyn HttpResponseHandler<Message=Message>>,
+}
+
+impl<M: 'static> TestHttpRequest<M> {
+    /// Returns whether the request's handler listens for progress using `http_progress`.
+    pub fn reports_progress(&self) -> bool {
+        self.handler.reports_progress()
+    }
+}
+
+/// A WebSocket connection opened by the updater of a `TestApp` that has not yet been closed.
+///
+/// The test plays the part of the server, delivering events to the application using
+/// `TestApp::websocket_event` and inspecting the frames it sent using `sent`.
+pub struct TestWebSocket<Message> {
+    /// URL the connection was opened to
+    pub url: String,
+    /// Subprotocols requested when opening the connection
+    pub protocols: Vec<String>,
+    connection: Rc<RefCell<TestConnectionState>>,
+    handler: Box<dyn WebSocketHandler<Message=Message>>,
+}
+
+impl<M> TestWebSocket<M> {
+    /// Returns the frames sent by the application, in the order they were sent.
+    ///
+    /// As in the browser, frames sent before the `Open` event are dropped.
+    pub fn sent(&self) -> Ref<'_, [OwnedWebSocketFrame]> {
+        Ref::map(self.connection.borrow(), |connection| &connection.sent[..])
+    }
+
+    /// Returns whether the application has started closing the connection, either using
+    /// `WebSocket::close` or by dropping its handle.
+    pub fn is_closed(&self) -> bool {
+        self.connection.borrow().closed
+    }
+}
+
+#[derive(Default)]
+struct TestConnectionState {
+    open: bool,
+    closed: bool,
+    sent: Vec<OwnedWebSocketFrame>,
+}
+
+/// The connection behind the `WebSocket` handles given out by `TestJsIo`
+struct TestConnection(Rc<RefCell<TestConnectionState>>);
+
+impl WebSocketConnection for TestConnection {
+    fn send(&self, frame: WebSocketFrame) {
+        let mut connection = self.0.borrow_mut();
+        if connection.open && !connection.closed {
+            connection.sent.push(frame.into());
+        }
+    }
+
+    fn close(&self) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+/// `JsIo` implementation which records requests instead of performing them
+struct TestJsIo<Message> {
+    http_requests: RefCell<Vec<TestHttpRequest<Message>>>,
+    websockets: RefCell<Vec<TestWebSocket<Message>>>,
+}
+
+impl<M> JsIo<M> for TestJsIo<M> {
+    fn http(
+        &self,
+        http_request: HttpRequest,
+        handler: Box<dyn HttpResponseHandler<Message=M>>,
+    ) -> HttpRequestHandle {
+        let aborted = Rc::new(Cell::new(false));
+        self.http_requests.borrow_mut().push(TestHttpRequest {
+            method: http_request.method.to_string(),
+            headers: http_request.headers.iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            url: http_request.url.to_string(),
+            body: http_request.body.to_vec(),
+            timeout_millis: http_request.timeout_millis,
+            aborted: aborted.clone(),
+            handler,
+        });
+        HttpRequestHandle::new(move || aborted.set(true))
+    }
+
+    fn websocket(
+        &self,
+        url: &str,
+        protocols: &[&str],
+        handler: Box<dyn WebSocketHandler<Message=M>>,
+    ) -> WebSocket {
+        let connection = Rc::new(RefCell::new(TestConnectionState::default()));
+        self.websockets.borrow_mut().push(TestWebSocket {
+            url: url.to_string(),
+            protocols: protocols.iter().map(|protocol| protocol.to_string()).collect(),
+            connection: connection.clone(),
+            handler,
+        });
+        WebSocket::new(TestConnection(connection))
+    }
+}
+
+/// Runs an application (`updater`, `renderer`, `initial_state`) natively against a
+/// `VirtualDocument`, the same way that `web_render::run` runs it in the browser.
+///
+/// Tests can dispatch synthetic events to elements found by selector and then inspect the
+/// resulting application state and document.
+///
+/// HTTP requests (issued through `JsIo` or `Cmd::http`) are recorded until answered with
+/// `respond_http` or aborted, and `Cmd::custom` effects are run against the same recording `JsIo`.
+/// WebSocket connections are recorded in `websockets`, with the test acting as the server.
+/// Navigation is simulated using a history which starts at `/`, and can be moved through using
+/// `back` and `forward`, and storage operations are performed on a `MemoryStorage` for each
+/// storage area. Delays returned by the updater are collected in `commands`.
+///
+/// Subscriptions are driven by a simulated clock which starts at zero and only moves forward
+/// when `advance_time` is called. Animation frames are triggered using `animation_frame`, and
+/// global events such as window resizes using `trigger`.
+///
+/// As in the browser, the application is rendered after every message by default, so that
+/// tests can inspect the document right away. Use `set_render_mode` with
+/// `RenderMode::AnimationFrame` to defer rendering until `animation_frame` is called instead.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::KeyIter;
+/// use domafic::app::JsIo;
+/// use domafic::listener::on;
+/// use domafic::tags::{button, div};
+/// use domafic::test_app::TestApp;
+///
+/// let update = |state: &mut u32, _msg: (), _: KeyIter, _: &JsIo<()>| *state += 1;
+/// let render = |state: &u32| div((
+///     button((on("click", |_| ()), "Increment")),
+///     state.to_string(),
+/// ));
+///
+/// let mut app = TestApp::new(update, render, 0);
+/// app.click("button");
+/// app.click("button");
+///
+/// assert_eq!(2, *app.state());
+/// assert_eq!("<div><button>Increment</button>2</div>", app.document().to_string());
+/// ```
+pub struct TestApp<D, M, U, R, S>
+    where
+    D: DomNode<M>,
+    M: 'static,
+    U: Updater<S, M>,
+    R: Renderer<S, M, Rendered=D>
+{
+    updater: U,
+    renderer: R,
+    state: S,
+    // Boxed so that the listeners referenced by `document` stay at a fixed address
+    rendered: Box<D>,
+    vdom: VDom<M, VirtualDocument<M>>,
+    document: VirtualDocument<M>,
+    head: Option<Head>,
+    js_io: TestJsIo<M>,
+    commands: Vec<Effect<M>>,
+    subscriptions: RunningSubscriptions<M>,
+    // Ids of running interval and timeout subscriptions, and the times they next fire at
+    timers: Vec<(u32, f64)>,
+    now: f64,
+    // Simulated browser history, and the position of the current entry in it
+    history: Vec<String>,
+    history_index: usize,
+    local_storage: MemoryStorage,
+    session_storage: MemoryStorage,
+    persistence: Option<Persistence<S>>,
+    // Time at which the state is next saved, pushed back by every update
+    save_due: Option<f64>,
+    render_mode: RenderMode,
+    // Whether the state changed since the last render, in `RenderMode::AnimationFrame`
+    render_pending: bool,
+}
+
+impl<D, M, U, R, S> TestApp<D, M, U, R, S>
+    where
+    D: DomNode<M>,
+    M: 'static,
+    U: Updater<S, M>,
+    R: Renderer<S, M, Rendered=D>
+{
+    /// Creates a new `TestApp` and renders `initial_state` into an empty `VirtualDocument`.
+    pub fn new(updater: U, renderer: R, initial_state: S) -> TestApp<D, M, U, R, S> {
+        let mut app = TestApp::unrendered(updater, renderer, initial_state, VirtualDocument::new());
+        unsafe { app.vdom.update(&mut app.document, &*app.rendered); }
+        app.update_subscriptions();
+        app
+    }
+
+    /// Creates a new `TestApp` which takes over the existing contents of `document` (as created
+    /// by `VirtualDocument::prerendered`) using `VDom::hydrate`, the same way that
+    /// `web_render::hydrate` takes over server-rendered markup in the browser.
+    ///
+    /// Returns the differences found between the document and the first render.
+    pub fn hydrate(updater: U, renderer: R, initial_state: S, document: VirtualDocument<M>)
+        -> (TestApp<D, M, U, R, S>, Vec<HydrationMismatch>)
+    {
+        let mut app = TestApp::unrendered(updater, renderer, initial_state, document);
+        let mismatches = unsafe { app.vdom.hydrate(&mut app.document, &*app.rendered) };
+        app.update_subscriptions();
+        (app, mismatches)
+    }
+
+    /// Creates a new `TestApp` with `storage` as the storage area of `persistence`, restoring the
+    /// state saved there (or starting from `initial_state` if there is none), the same way that
+    /// `web_render::run_persisted` restores the state after a page reload.
+    ///
+    /// The state is saved on the simulated clock once no updates have happened for
+    /// `persistence.debounce_millis()` milliseconds.
+    pub fn persisted(
+        updater: U,
+        renderer: R,
+        initial_state: S,
+        persistence: Persistence<S>,
+        storage: MemoryStorage,
+    ) -> TestApp<D, M, U, R, S> {
+        let state = persistence.restore(&storage).unwrap_or(initial_state);
+        let mut app = TestApp::new(updater, renderer, state);
+        *app.storage_mut(persistence.storage_area()) = storage;
+        app.persistence = Some(persistence);
+        app
+    }
+
+    fn unrendered(updater: U, renderer: R, initial_state: S, document: VirtualDocument<M>)
+        -> TestApp<D, M, U, R, S>
+    {
+        TestApp {
+            rendered: Box::new(renderer.render(&initial_state)),
+            head: renderer.head(&initial_state),
+            updater,
+            renderer,
+            state: initial_state,
+            vdom: VDom::new(document.root()),
+            document,
+            js_io: TestJsIo {
+                http_requests: RefCell::new(Vec::new()),
+                websockets: RefCell::new(Vec::new()),
+            },
+            commands: Vec::new(),
+            subscriptions: RunningSubscriptions::new(),
+            timers: Vec::new(),
+            now: 0.0,
+            history: vec!["/".to_string()],
+            history_index: 0,
+            local_storage: MemoryStorage::new(),
+            session_storage: MemoryStorage::new(),
+            persistence: None,
+            save_due: None,
+            render_mode: RenderMode::Sync,
+            render_pending: false,
+        }
+    }
+
+    /// Returns the current application state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Returns the document that the application is rendered into.
+    pub fn document(&self) -> &VirtualDocument<M> {
+        &self.document
+    }
+
+    /// Returns the most recently rendered `Head`, if the renderer describes one.
+    pub fn head(&self) -> Option<&Head> {
+        self.head.as_ref()
+    }
+
+    /// Returns the most recently rendered `DomNode`.
+    pub fn rendered(&self) -> &D {
+        &self.rendered
+    }
+
+    /// Returns the HTTP requests issued by the updater which have not yet been answered,
+    /// in the order they were issued.
+    pub fn http_requests(&self) -> Ref<'_, [TestHttpRequest<M>]> {
+        Ref::map(self.js_io.http_requests.borrow(), |requests| &requests[..])
+    }
+
+    /// Answers the pending HTTP request at position `index` in `http_requests` with `result`,
+    /// passing the handler's message to the updater.
+    pub fn respond_http(&mut self, index: usize, result: HttpResult) {
+        let request = self.js_io.http_requests.borrow_mut().remove(index);
+        let message = request.handler.handle(result);
+        self.send(message);
+    }
+
+    /// Reports upload or download `progress` for the pending HTTP request at position `index`
+    /// in `http_requests`, passing the handler's message (if any) to the updater.
+    ///
+    /// Does nothing if the handler doesn't report progress.
+    pub fn http_progress(&mut self, index: usize, progress: HttpProgress) {
+        let message = {
+            let requests = self.js_io.http_requests.borrow();
+            let handler = &requests[index].handler;
+            if handler.reports_progress() { handler.progress(progress) } else { None }
+        };
+        if let Some(message) = message {
+            self.send(message);
+        }
+    }
+
+    /// Returns the WebSocket connections opened by the updater which have not yet been closed,
+    /// in the order they were opened.
+    pub fn websockets(&self) -> Ref<'_, [TestWebSocket<M>]> {
+        Ref::map(self.js_io.websockets.borrow(), |websockets| &websockets[..])
+    }
+
+    /// Delivers `event` to the WebSocket connection at position `index` in `websockets`,
+    /// passing the handler's message to the updater.
+    ///
+    /// `Open` allows the application to send frames. `Close` removes the connection from
+    /// `websockets`, and should also be delivered after the application closes the connection.
+    pub fn websocket_event(&mut self, index: usize, event: WebSocketEvent) {
+        let message = {
+            let mut websockets = self.js_io.websockets.borrow_mut();
+            match event {
+                WebSocketEvent::Open => {
+                    websockets[index].connection.borrow_mut().open = true;
+                    websockets[index].handler.handle(event)
+                }
+                WebSocketEvent::Close { .. } => {
+                    let websocket = websockets.remove(index);
+                    websocket.connection.borrow_mut().closed = true;
+                    websocket.handler.handle(event)
+                }
+                _ => websockets[index].handler.handle(event),
+            }
+        };
+        self.send(message);
+    }
+
+    /// Returns the effects returned by the updater which `TestApp` doesn't simulate (delays),
+    /// in the order they were returned.
+    pub fn commands(&self) -> &[Effect<M>] {
+        &self.commands
+    }
+
+    /// Removes and returns the effects in `commands`.
+    pub fn take_commands(&mut self) -> Vec<Effect<M>> {
+        ::std::mem::take(&mut self.commands)
+    }
+
+    /// Returns the simulated storage area `area`.
+    pub fn storage(&self, area: StorageArea) -> &MemoryStorage {
+        match area {
+            StorageArea::Local => &self.local_storage,
+            StorageArea::Session => &self.session_storage,
+        }
+    }
+
+    /// Returns the simulated storage area `area` for modification, such as to store values
+    /// before the application reads them or to replace it with one with a quota.
+    pub fn storage_mut(&mut self, area: StorageArea) -> &mut MemoryStorage {
+        match area {
+            StorageArea::Local => &mut self.local_storage,
+            StorageArea::Session => &mut self.session_storage,
+        }
+    }
+
+    /// Returns the current time of the simulated clock, in milliseconds.
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Moves the simulated clock forward by `millis` milliseconds, firing the interval and
+    /// timeout subscriptions and saving the state of a `persisted` app as they come due along the
+    /// way in order.
+    pub fn advance_time(&mut self, millis: u32) {
+        let end = self.now + f64::from(millis);
+        loop {
+            let next = self.timers.iter().enumerate()
+                .filter(|&(_, &(_, due))| due <= end)
+                .min_by(|&(_, a), &(_, b)| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)))
+                .map(|(index, &timer)| (index, timer));
+            if let Some(save_due) = self.save_due {
+                if save_due <= end && next.iter().all(|&(_, (_, due))| save_due <= due) {
+                    self.now = save_due;
+                    self.save_state();
+                    continue;
+                }
+            }
+            let (index, (id, due)) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            self.now = due;
+            let message = {
+                let subscription = self.subscriptions.get(id)
+                    .expect("Timers are removed along with their subscriptions");
+                match subscription.kind() {
+                    // Browsers don't run intervals more often than every few milliseconds
+                    SubscriptionKind::Interval(interval) =>
+                        self.timers[index].1 += f64::from(interval.max(1)),
+                    _ => { self.timers.remove(index); }
+                }
+                subscription.handle(SubscriptionEvent::Time(self.now))
+            };
+            self.update(message, Keys::new());
+        }
+        self.now = end;
+    }
+
+    /// Fires every animation frame subscription once, at the current time, and then renders the
+    /// application if rendering was deferred by `RenderMode::AnimationFrame`.
+    pub fn animation_frame(&mut self) {
+        let now = self.now;
+        self.fire(SubscriptionKind::AnimationFrame, SubscriptionEvent::Time(now));
+        if self.render_pending {
+            self.render();
+        }
+    }
+
+    /// Sets when the application is rendered after its state is updated. Defaults to
+    /// `RenderMode::Sync`.
+    ///
+    /// Switching to `RenderMode::Sync` renders any deferred changes immediately.
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        self.render_mode = mode;
+        if mode == RenderMode::Sync && self.render_pending {
+            self.render();
+        }
+    }
+
+    /// Delivers a global `window` or `document` event, such as
+    /// `SubscriptionEvent::Resize { width: 800, height: 600 }`, to the subscriptions listening
+    /// for it.
+    ///
+    /// Panics if `event` is a `SubscriptionEvent::Time`, since timers are driven by
+    /// `advance_time` and `animation_frame`.
+    pub fn trigger(&mut self, event: SubscriptionEvent) {
+        let kind = event.kind().expect("Timer events can't be triggered directly");
+        self.fire(SubscriptionKind::Event(kind), event);
+    }
+
+    fn fire(&mut self, kind: SubscriptionKind, event: SubscriptionEvent) {
+        let ids: Vec<u32> = self.subscriptions.iter()
+            .filter(|&(_, subscription)| subscription.kind() == kind)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            // Earlier messages may have caused this subscription to be stopped
+            let message = match self.subscriptions.get(id) {
+                Some(subscription) => subscription.handle(event),
+                None => continue,
+            };
+            self.update(message, Keys::new());
+        }
+    }
+
+    /// Returns the current URL of the simulated history.
+    pub fn location(&self) -> &str {
+        &self.history[self.history_index]
+    }
+
+    /// Moves back one entry in the simulated history, like the browser's back button, notifying
+    /// `popstate` subscriptions. Does nothing if already at the first entry.
+    pub fn back(&mut self) {
+        if self.history_index > 0 {
+            self.history_index -= 1;
+            self.pop_state();
+        }
+    }
+
+    /// Moves forward one entry in the simulated history, like the browser's forward button,
+    /// notifying `popstate` subscriptions. Does nothing if already at the last entry.
+    pub fn forward(&mut self) {
+        if self.history_index + 1 < self.history.len() {
+            self.history_index += 1;
+            self.pop_state();
+        }
+    }
+
+    fn navigate(&mut self, url: String, replace: bool) {
+        if !replace {
+            self.history.truncate(self.history_index + 1);
+            self.history_index += 1;
+            self.history.push(url);
+        } else {
+            self.history[self.history_index] = url;
+        }
+        self.pop_state();
+    }
+
+    fn pop_state(&mut self) {
+        let url = self.location().to_string();
+        self.fire(SubscriptionKind::Event(GlobalEventKind::PopState),
+            SubscriptionEvent::PopState { url: &url });
+    }
+
+    /// Passes `message` directly to the updater and re-renders the application.
+    pub fn send(&mut self, message: M) {
+        self.update(message, Keys::new());
+    }
+
+    /// Dispatches `event` to the first element matching `selector`.
+    ///
+    /// As in the browser, the event bubbles from the target element up through its parents,
+    /// and every listener for `event.type_str` along the way is called.
+    /// Panics if no element matches `selector` or if `event.type_str` is `None`.
+    pub fn dispatch(&mut self, selector: &str, event: Event) {
+        let target = self.document.query_selector(selector).unwrap_or_else(||
+            panic!("No element matched selector: {}", selector));
+        self.dispatch_to(target, event);
+    }
+
+    /// Dispatches `event` to the node `target`. See `dispatch` for details.
+    pub fn dispatch_to(&mut self, target: NodeId, event: Event) {
+        assert!(event.type_str.is_some(), "Dispatched events must have a type");
+
+        let mut path = vec![target];
+        while let Some(parent) = self.document.parent(*path.last().unwrap()) {
+            path.push(parent);
+        }
+
+        for node in path {
+            // Earlier listeners may have caused this node to be removed
+            if !self.document.is_attached(node) {
+                continue;
+            }
+            let messages = unsafe { self.document.handle_event(node, event) };
+            for (message, keys) in messages {
+                self.update(message, keys);
+            }
+        }
+    }
+
+    /// Dispatches a "click" event to the first element matching `selector`.
+    pub fn click(&mut self, selector: &str) {
+        self.dispatch(selector, Event { type_str: Some("click"), ..Event::default() });
+    }
+
+    /// Dispatches an "input" event with the given target value to the first element
+    /// matching `selector`.
+    pub fn input(&mut self, selector: &str, value: &str) {
+        self.dispatch(selector, Event {
+            type_str: Some("input"),
+            target_value: Some(value),
+            ..Event::default()
+        });
+    }
+
+    fn update(&mut self, message: M, keys: Keys) {
+        let cmd = self.updater.update(&mut self.state, message, keys.into_iter(), &self.js_io);
+
+        match self.render_mode {
+            RenderMode::Sync => self.render(),
+            RenderMode::AnimationFrame => self.render_pending = true,
+        }
+
+        self.update_subscriptions();
+        if let Some(ref persistence) = self.persistence {
+            self.save_due = Some(self.now + f64::from(persistence.debounce_millis()));
+        }
+        self.perform(cmd);
+        self.abort_requests();
+    }
+
+    fn render(&mut self) {
+        self.render_pending = false;
+        // Re-render in place so that unchanged listeners keep their addresses
+        *self.rendered = self.renderer.render(&self.state);
+        if let Some(head) = self.renderer.head(&self.state) {
+            self.head = Some(head);
+        }
+        unsafe { self.vdom.update(&mut self.document, &*self.rendered); }
+    }
+
+    fn save_state(&mut self) {
+        self.save_due = None;
+        if let Some(ref persistence) = self.persistence {
+            let storage = match persistence.storage_area() {
+                StorageArea::Local => &mut self.local_storage,
+                StorageArea::Session => &mut self.session_storage,
+            };
+            // Like the browser, a full storage area leaves the previously saved state in place
+            if let Err(error) = persistence.save(&self.state, storage) {
+                if let Some(handler) = persistence.save_error_handler() {
+                    handler(&error);
+                }
+            }
+        }
+    }
+
+    // As in the browser, aborted requests are answered after the update that aborted them
+    fn abort_requests(&mut self) {
+        loop {
+            let request = {
+                let mut requests = self.js_io.http_requests.borrow_mut();
+                match requests.iter().position(|request| request.aborted.get()) {
+                    Some(index) => requests.remove(index),
+                    None => return,
+                }
+            };
+            let message = request.handler.handle(Err(HttpError::Aborted));
+            self.update(message, Keys::new());
+        }
+    }
+
+    fn update_subscriptions(&mut self) {
+        let changes = self.subscriptions.update(self.updater.subscriptions(&self.state));
+        self.timers.retain(|timer| !changes.stopped.contains(&timer.0));
+        for id in changes.started {
+            match self.subscriptions.get(id).unwrap().kind() {
+                SubscriptionKind::Interval(millis) | SubscriptionKind::Timeout(millis) =>
+                    self.timers.push((id, self.now + f64::from(millis))),
+                SubscriptionKind::AnimationFrame | SubscriptionKind::Event(_) => {}
+            }
+        }
+    }
+
+    fn perform(&mut self, cmd: Cmd<M>) {
+        // Messages produced by effects are passed to the updater once all effects are performed
+        let mut messages = Vec::new();
+        for effect in cmd.into_effects() {
+            match effect {
+                Effect::Http { request, handler } => {
+                    request.with_borrowed(|request| self.js_io.http(request, handler));
+                }
+                Effect::Storage { area, operation } => {
+                    messages.extend(operation.perform(self.storage_mut(area)));
+                }
+                Effect::Navigate { url, replace } => self.navigate(url, replace),
+                Effect::Custom(f) => f(&self.js_io),
+                effect => self.commands.push(effect),
+            }
+        }
+        for message in messages {
+            self.update(message, Keys::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestApp;
+    use {DomNode, HydrationMismatch, KeyIter};
+    use AttributeValue::{Bool, OwnedStr, Str};
+    use app::{with_head, with_subscriptions, JsIo, HttpRequest, HttpResponse, HttpResult};
+    use app::{OwnedWebSocketFrame, WebSocket, WebSocketEvent};
+    use app::{with_progress, HttpError, HttpProgress, HttpRequestHandle, RenderMode};
+    use cmd::{Cmd, Effect};
+    use html_document::Head;
+    use listener::on;
+    use listener::Event;
+    use storage::{Storage, StorageArea};
+    use sub::{Sub, SubscriptionEvent};
+    use tags::{attributes, button, div, input, li, ul};
+    use vdom::DomBackend;
+    use virtual_document::VirtualDocument;
+
+    enum Msg {
+        UpdateField(String),
+        Add,
+        Remove,
+        Fetch,
+        Fetched(String),
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        entry: String,
+        todos: Vec<String>,
+    }
+
+    fn update(state: &mut State, msg: Msg, mut keys: KeyIter, js_io: &dyn JsIo<Msg>) {
+        match msg {
+            Msg::UpdateField(value) => state.entry = value,
+            Msg::Add => {
+                let entry = ::std::mem::take(&mut state.entry);
+                state.todos.push(entry);
+            }
+            Msg::Remove => {
+                state.todos.remove(keys.next_index().unwrap());
+            }
+            Msg::Fetch => {
+                js_io.http(HttpRequest {
+                    method: "GET",
+                    headers: &[("accept", "text/plain")],
+                    url: "/todo",
+                    body: b"",
+                    timeout_millis: None,
+                }, Box::new(|result: HttpResult| match result {
+                    Ok(response) => Msg::Fetched(response.text().unwrap().to_string()),
+                    Err(_) => Msg::Fetched("error".to_string()),
+                }));
+            }
+            Msg::Fetched(todo) => state.todos.push(todo),
+        }
+    }
+
+    fn render(state: &State) -> impl DomNode<Msg> {
+        div((
+            input((
+                attributes([("id", Str("entry")), ("value", OwnedStr(state.entry.clone()))]),
+                on("input", |event| Msg::UpdateField(event.target_value.unwrap().to_string())),
+            )),
+            button((attributes([("id", Str("add"))]), on("click", |_| Msg::Add), "Add")),
+            button((attributes([("id", Str("fetch"))]), on("click", |_| Msg::Fetch), "Fetch")),
+            ul(state.todos.iter().enumerate().map(|(index, todo)|
+                li((
+                    on("click", |_| Msg::Remove),
+                    (todo.clone(), button("x")),
+                )).with_key(index)
+            ).collect::<Vec<_>>()),
+        ))
+    }
+
+    #[test]
+    fn adds_and_removes_items() {
+        let mut app = TestApp::new(update, render, State::default());
+
+        app.input("#entry", "one");
+        assert_eq!(Some(&OwnedStr("one".to_string())),
+            app.document().attribute(app.document().query_selector("input").unwrap(), "value"));
+        app.click("#add");
+        app.input("#entry", "two");
+        app.click("#add");
+
+        assert_eq!(vec!["one", "two"], app.state().todos);
+        assert_eq!(2, app.document().query_selector_all("ul li").len());
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+
+        // Clicks on the nested button bubble up to the keyed `li`
+        app.click("li button");
+        assert_eq!(vec!["two"], app.state().todos);
+        let items = app.document().query_selector_all("li");
+        assert_eq!(1, items.len());
+        assert_eq!("twox", app.document().text_content(items[0]));
+    }
+
+    #[test]
+    fn answers_http_requests() {
+        let mut app = TestApp::new(update, render, State::default());
+        app.click("#fetch");
+        assert_eq!(1, app.http_requests().len());
+        assert_eq!("/todo", app.http_requests()[0].url);
+
+        app.respond_http(0, Ok(HttpResponse {
+            status_code: 200,
+            status_text: "OK",
+            headers: &[],
+            body: b"from server",
+        }));
+        assert!(app.http_requests().is_empty());
+        assert_eq!(vec!["from server"], app.state().todos);
+        assert_eq!(Some(app.document().query_selector("li").unwrap()),
+            app.document().query_selector("div ul li"));
+    }
+
+    #[test]
+    fn performs_returned_commands() {
+        fn update(state: &mut State, msg: Msg, _: KeyIter, _: &dyn JsIo<Msg>) -> Cmd<Msg> {
+            match msg {
+                Msg::Add => Cmd::batch(vec![
+                    Cmd::http(HttpRequest {
+                        method: "GET",
+                        headers: &[],
+                        url: "/todo",
+                        body: b"",
+                        timeout_millis: Some(100),
+                    }, |result: HttpResult| {
+                        Msg::Fetched(result.unwrap().text().unwrap().to_string())
+                    }),
+                    Cmd::set_storage("last", "add"),
+                    Cmd::custom(|js_io: &dyn JsIo<Msg>| {
+                        js_io.http(HttpRequest {
+                            method: "POST",
+                            headers: &[],
+                            url: "/log",
+                            body: b"add",
+                            timeout_millis: None,
+                        }, Box::new(|_: HttpResult| Msg::Remove));
+                    }),
+                    Cmd::delay(10, Msg::Remove),
+                ]),
+                Msg::Fetched(todo) => {
+                    state.todos.push(todo);
+                    Cmd::none()
+                }
+                _ => Cmd::none(),
+            }
+        }
+
+        let mut app = TestApp::new(update, render, State::default());
+        app.click("#add");
+        assert_eq!(vec!["/todo", "/log"],
+            app.http_requests().iter().map(|request| request.url.as_str()).collect::<Vec<_>>());
+        match app.commands() {
+            [Effect::Delay { millis: 10, .. }] => {}
+            _ => panic!("Unexpected commands"),
+        }
+        assert_eq!(Some("add".to_string()), app.storage(StorageArea::Local).get("last"));
+
+        app.respond_http(0, Ok(HttpResponse {
+            status_code: 200,
+            status_text: "OK",
+            headers: &[],
+            body: b"fetched",
+        }));
+        assert_eq!(vec!["fetched"], app.state().todos);
+        assert_eq!(1, app.take_commands().len());
+        assert!(app.commands().is_empty());
+    }
+
+    #[test]
+    fn aborts_and_reports_http_progress() {
+        enum Upload {
+            Start,
+            Progress(HttpProgress),
+            Cancel,
+            Done(Result<Vec<u8>, HttpError>),
+        }
+
+        #[derive(Default)]
+        struct Uploader {
+            request: Option<HttpRequestHandle>,
+            progress: Vec<HttpProgress>,
+            result: Option<Result<Vec<u8>, HttpError>>,
+        }
+
+        fn update(state: &mut Uploader, msg: Upload, _: KeyIter, js_io: &dyn JsIo<Upload>) {
+            match msg {
+                Upload::Start => state.request = Some(js_io.http(HttpRequest {
+                    method: "PUT",
+                    headers: &[],
+                    url: "/file",
+                    body: &[0, 159, 146, 150],
+                    timeout_millis: None,
+                }, Box::new(with_progress(
+                    |result: HttpResult| Upload::Done(result.map(|response| response.body.to_vec())),
+                    Upload::Progress,
+                )))),
+                Upload::Progress(progress) => state.progress.push(progress),
+                Upload::Cancel => state.request.as_ref().unwrap().abort(),
+                Upload::Done(result) => {
+                    state.request = None;
+                    state.result = Some(result);
+                }
+            }
+        }
+
+        let render = |_: &Uploader| button((on("click", |_| Upload::Start), "Upload"));
+        let mut app = TestApp::new(update, render, Uploader::default());
+        app.click("button");
+        assert_eq!(vec![0, 159, 146, 150], app.http_requests()[0].body);
+        assert!(app.http_requests()[0].reports_progress());
+
+        let progress = HttpProgress { upload: true, loaded: 2.0, total: Some(4.0) };
+        app.http_progress(0, progress);
+        assert_eq!(vec![progress], app.state().progress);
+
+        app.send(Upload::Cancel);
+        assert!(app.http_requests().is_empty());
+        assert_eq!(Some(Err(HttpError::Aborted)), app.state().result);
+        assert!(app.state().request.is_none());
+
+        // Binary responses are passed through unchanged
+        app.click("button");
+        app.respond_http(0, Ok(HttpResponse {
+            status_code: 201,
+            status_text: "Created",
+            headers: &[],
+            body: &[255, 0],
+        }));
+        assert_eq!(Some(Ok(vec![255, 0])), app.state().result);
+    }
+
+    #[test]
+    fn echoes_websocket_frames() {
+        enum Chat {
+            Connect,
+            Opened,
+            Received(OwnedWebSocketFrame),
+            Disconnect,
+            Closed(u16),
+        }
+
+        #[derive(Default)]
+        struct Client {
+            socket: Option<WebSocket>,
+            received: Vec<OwnedWebSocketFrame>,
+            closed_with: Option<u16>,
+        }
+
+        fn update(state: &mut Client, msg: Chat, _: KeyIter, js_io: &dyn JsIo<Chat>) {
+            match msg {
+                Chat::Connect => {
+                    let socket = js_io.websocket("ws://localhost/echo", &["echo"],
+                        Box::new(|event: WebSocketEvent| match event {
+                            WebSocketEvent::Open => Chat::Opened,
+                            WebSocketEvent::Message(frame) => Chat::Received(frame.into()),
+                            WebSocketEvent::Error => Chat::Disconnect,
+                            WebSocketEvent::Close { code, .. } => Chat::Closed(code),
+                        }));
+                    // Dropped, since the connection isn't open yet
+                    socket.send_text("too early");
+                    state.socket = Some(socket);
+                }
+                Chat::Opened => {
+                    let socket = state.socket.as_ref().unwrap();
+                    socket.send_text("hello");
+                    socket.send_binary(&[1, 2, 3]);
+                }
+                Chat::Received(frame) => state.received.push(frame),
+                Chat::Disconnect => state.socket.as_ref().unwrap().close(),
+                Chat::Closed(code) => {
+                    state.socket = None;
+                    state.closed_with = Some(code);
+                }
+            }
+        }
+
+        let render = |_: &Client| button((on("click", |_| Chat::Connect), "Connect"));
+        let mut app = TestApp::new(update, render, Client::default());
+        app.click("button");
+        assert_eq!(1, app.websockets().len());
+        assert_eq!("ws://localhost/echo", app.websockets()[0].url);
+        assert_eq!(vec!["echo"], app.websockets()[0].protocols);
+
+        app.websocket_event(0, WebSocketEvent::Open);
+        let sent = app.websockets()[0].sent().to_vec();
+        assert_eq!(vec![
+            OwnedWebSocketFrame::Text("hello".to_string()),
+            OwnedWebSocketFrame::Binary(vec![1, 2, 3]),
+        ], sent);
+
+        // Echo every frame back to the client
+        for frame in &sent {
+            app.websocket_event(0, WebSocketEvent::Message(frame.as_frame()));
+        }
+        assert_eq!(sent, app.state().received);
+
+        app.send(Chat::Disconnect);
+        assert!(app.websockets()[0].is_closed());
+        app.websocket_event(0, WebSocketEvent::Close { code: 1000, reason: "", was_clean: true });
+        assert!(app.websockets().is_empty());
+        assert!(app.state().socket.is_none());
+        assert_eq!(Some(1000), app.state().closed_with);
+    }
+
+    #[test]
+    fn drives_subscriptions_with_simulated_clock() {
+        enum Timed {
+            Tick(f64),
+            Frame(f64),
+            Stop,
+        }
+
+        #[derive(Default)]
+        struct Clock {
+            ticks: Vec<f64>,
+            frames: Vec<f64>,
+            stopped: bool,
+        }
+
+        fn update(state: &mut Clock, msg: Timed, _: KeyIter, _: &dyn JsIo<Timed>) {
+            match msg {
+                Timed::Tick(time) => state.ticks.push(time),
+                Timed::Frame(time) => state.frames.push(time),
+                Timed::Stop => state.stopped = true,
+            }
+        }
+
+        fn subscriptions(state: &Clock) -> Sub<Timed> {
+            Sub::batch(vec![
+                if state.stopped {
+                    Sub::none()
+                } else {
+                    Sub::batch(vec![Sub::interval(100, Timed::Tick), Sub::timeout(250, |_| Timed::Stop)])
+                },
+                if state.ticks.is_empty() { Sub::animation_frame(Timed::Frame) } else { Sub::none() },
+            ])
+        }
+
+        let render = |state: &Clock| div(state.ticks.len().to_string());
+        let mut app = TestApp::new(with_subscriptions(update, subscriptions), render, Clock::default());
+
+        app.animation_frame();
+        assert_eq!(vec![0.0], app.state().frames);
+
+        app.advance_time(99);
+        assert!(app.state().ticks.is_empty());
+        app.advance_time(251);
+        assert_eq!(350.0, app.now());
+        assert_eq!(vec![100.0, 200.0], app.state().ticks);
+        assert!(app.state().stopped);
+        assert_eq!("<div>2</div>", app.document().to_string());
+
+        // All subscriptions have been stopped
+        app.animation_frame();
+        app.advance_time(1000);
+        assert_eq!(vec![0.0], app.state().frames);
+        assert_eq!(vec![100.0, 200.0], app.state().ticks);
+    }
+
+    #[test]
+    fn delivers_global_events() {
+        // Tracks the window size while online, with "ctrl+enter" as a shortcut for adding
+        let subscriptions = |state: &State| if state.entry == "offline" {
+            Sub::on_online_change(|online| Msg::UpdateField(format!("online: {}", online)))
+        } else {
+            Sub::batch(vec![
+                Sub::on_resize(|width, height| Msg::UpdateField(format!("{}x{}", width, height))),
+                Sub::on_online_change(|online|
+                    Msg::UpdateField(if online { "online" } else { "offline" }.to_string())),
+                Sub::on_keydown(|event| if event.ctrl_key && event.which_keycode == 13 {
+                    Msg::Add
+                } else {
+                    Msg::UpdateField(format!("key {}", event.which_keycode))
+                }),
+            ])
+        };
+        let mut app = TestApp::new(with_subscriptions(update, subscriptions), render, State::default());
+
+        app.trigger(SubscriptionEvent::Resize { width: 800, height: 600 });
+        assert_eq!("800x600", app.state().entry);
+        app.trigger(SubscriptionEvent::KeyDown(Event {
+            type_str: Some("keydown"),
+            which_keycode: 13,
+            ctrl_key: true,
+            ..Event::default()
+        }));
+        assert_eq!(vec!["800x600"], app.state().todos);
+
+        // Events without subscribers are ignored
+        app.trigger(SubscriptionEvent::Scroll { x: 0, y: 100 });
+        app.trigger(SubscriptionEvent::Online(false));
+        assert_eq!("offline", app.state().entry);
+        app.trigger(SubscriptionEvent::Resize { width: 1024, height: 768 });
+        assert_eq!("offline", app.state().entry);
+        app.trigger(SubscriptionEvent::Online(true));
+        assert_eq!("online: true", app.state().entry);
+    }
+
+    #[test]
+    fn coalesces_renders_into_animation_frames() {
+        use std::cell::Cell;
+
+        let renders = Cell::new(0);
+        let update = |count: &mut u32, _: (), _: KeyIter, _: &dyn JsIo<()>| *count += 1;
+        let render = |count: &u32| {
+            renders.set(renders.get() + 1);
+            div((button((on("click", |_| ()), "+")), count.to_string()))
+        };
+        let mut app = TestApp::new(update, render, 0);
+        app.set_render_mode(RenderMode::AnimationFrame);
+
+        // The state is updated right away, but the document waits for the next frame
+        app.click("button");
+        app.click("button");
+        app.send(());
+        assert_eq!(3, *app.state());
+        assert_eq!("<div><button>+</button>0</div>", app.document().to_string());
+        app.animation_frame();
+        assert_eq!("<div><button>+</button>3</div>", app.document().to_string());
+        assert_eq!(2, renders.get());
+
+        // Frames without updates don't render
+        app.animation_frame();
+        assert_eq!(2, renders.get());
+
+        // Switching back renders deferred changes immediately, and every update after that
+        app.send(());
+        app.set_render_mode(RenderMode::Sync);
+        assert_eq!("<div><button>+</button>4</div>", app.document().to_string());
+        app.click("button");
+        assert_eq!("<div><button>+</button>5</div>", app.document().to_string());
+        assert_eq!(4, renders.get());
+    }
+
+    #[test]
+    fn tracks_rendered_head() {
+        let title = |state: &State| Head::new().title(format!("Todos ({})", state.todos.len()));
+        let mut app = TestApp::new(update, with_head(render, title), State::default());
+        assert_eq!(Some("Todos (0)"), app.head().and_then(|head| head.title.as_ref()).map(String::as_str));
+
+        app.send(Msg::Fetched("one".to_string()));
+        assert_eq!(Some("Todos (1)"), app.head().and_then(|head| head.title.as_ref()).map(String::as_str));
+
+        let app = TestApp::new(update, render, State::default());
+        assert!(app.head().is_none());
+    }
+
+    #[test]
+    fn hydrates_prerendered_markup() {
+        let state = || State { entry: String::new(), todos: vec!["one".to_string()] };
+        let document = VirtualDocument::prerendered(&render(&state()));
+        let root = document.root();
+        let list = document.query_selector("ul").unwrap();
+
+        let (mut app, mismatches) = TestApp::hydrate(update, render, state(), document);
+        assert!(mismatches.is_empty());
+        assert_eq!(root, app.document().root());
+        assert_eq!(Some(list), app.document().query_selector("ul"));
+
+        // Listeners are attached to the existing elements
+        app.click("li button");
+        assert!(app.state().todos.is_empty());
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+    }
+
+    #[test]
+    fn hydration_reports_stale_markup() {
+        let document = VirtualDocument::prerendered(&render(&State::default()));
+        let state = State { entry: "draft".to_string(), todos: Vec::new() };
+
+        let (app, mismatches) = TestApp::hydrate(update, render, state, document);
+        assert_eq!(mismatches, vec![HydrationMismatch::Attribute {
+            path: vec![0, 0],
+            key: "value".to_string(),
+            expected: Some("draft".to_string()),
+            found: Some(String::new()),
+        }]);
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+    }
+
+    #[test]
+    fn hydration_removes_stale_attributes() {
+        let mut document = VirtualDocument::prerendered(&render(&State::default()));
+        let add = document.query_selector("#add").unwrap();
+        document.set_attribute(&add, &("class", Str("busy")));
+        document.set_attribute(&add, &("disabled", Bool(true)));
+
+        let (app, mismatches) = TestApp::hydrate(update, render, State::default(), document);
+        assert_eq!(mismatches, vec![
+            HydrationMismatch::Attribute {
+                path: vec![0, 1],
+                key: "class".to_string(),
+                expected: None,
+                found: Some("busy".to_string()),
+            },
+            HydrationMismatch::Attribute {
+                path: vec![0, 1],
+                key: "disabled".to_string(),
+                expected: None,
+                found: Some(String::new()),
+            },
+        ]);
+        assert_eq!(None, app.document().attribute(add, "disabled"));
+        assert_eq!(app.rendered().displayable().to_string(), app.document().to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "No element matched selector")]
+    fn panics_on_missing_element() {
+        let mut app = TestApp::new(update, render, State::default());
+        app.click("#missing");
+    }
+}
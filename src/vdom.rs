@@ -0,0 +1,1284 @@
+use {AttributeValue, DomNode, DomValue, KeyValue, Listener};
+use keys::Keys;
+use processors::{DomNodes, Listeners, DomNodeProcessor, ListenerProcessor};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::{mem, ops, ptr};
+
+/// A `DomBackend` is the document that a `VDom` applies its changes to.
+///
+/// The diffing logic in `VDom` is independent of the document being drawn to: in the browser,
+/// the backend forwards each operation to Javascript, while in native tests it can simply record
+/// the operations or maintain an in-memory tree.
+pub trait DomBackend<Message> {
+
+    /// Handle to an element or text node owned by the backend.
+    type Element;
+
+    /// Handle to a listener attached to an element, used to remove the listener later.
+    type ListenerHandle;
+
+    /// Creates a new, detached element with the tag name `tag`.
+    fn create_element(&mut self, tag: &'static str) -> Self::Element;
+
+    /// Creates a new, detached text node containing `text`.
+    fn create_text_node(&mut self, text: &str) -> Self::Element;
+
+    /// Inserts `child` into `parent` so that it becomes the child at position `index`.
+    ///
+    /// `index` may be equal to the number of children, in which case `child` is appended.
+    fn insert(&mut self, parent: &Self::Element, index: usize, child: &Self::Element);
+
+    /// Moves the child of `parent` at position `old_index` to position `new_index`.
+    fn move_child(&mut self, parent: &Self::Element, old_index: usize, new_index: usize);
+
+    /// Sets an attribute on `element`, replacing any previous value for the same key.
+    fn set_attribute(&mut self, element: &Self::Element, attribute: &KeyValue);
+
+    /// Removes the attribute `key` from `element`.
+    fn remove_attribute(&mut self, element: &Self::Element, key: &str);
+
+    /// Starts forwarding events of type `event_type` on `element` to `listener`.
+    ///
+    /// `keys` are the keys of `element` and all of its parents, and should be handed to the
+    /// updater alongside any message produced by `listener`.
+    ///
+    /// `listener` is only guaranteed to be valid until the next call to `VDom::update` or until
+    /// the listener is removed, whichever comes first.
+    fn add_listener(
+        &mut self,
+        element: &Self::Element,
+        event_type: &'static str,
+        listener: *const dyn Listener<Message>,
+        keys: Keys,
+    ) -> Self::ListenerHandle;
+
+    /// Stops forwarding events to a listener previously returned from `add_listener`.
+    fn remove_listener(
+        &mut self,
+        element: &Self::Element,
+        event_type: &'static str,
+        listener: Self::ListenerHandle,
+    );
+
+    /// Detaches `element` from its parent.
+    fn remove_self(&mut self, element: &Self::Element);
+
+    /// Returns the children of `parent` that are already present in the document, such as
+    /// server-rendered markup. Used by `VDom::hydrate`.
+    ///
+    /// The default implementation reports no children, in which case `VDom::hydrate` renders
+    /// everything from scratch and any existing children are left in place.
+    fn existing_children(&mut self, _parent: &Self::Element) -> Vec<Self::Element> {
+        Vec::new()
+    }
+
+    /// Returns the tag name or text of a node returned from `existing_children`.
+    fn existing_value(&mut self, _node: &Self::Element) -> ExistingValue {
+        ExistingValue::Other
+    }
+
+    /// Returns the value of the attribute `key` of a node returned from `existing_children`,
+    /// or `None` if the attribute isn't present.
+    fn existing_attribute(&mut self, _element: &Self::Element, _key: &str) -> Option<String> {
+        None
+    }
+
+    /// Returns the names of all attributes of a node returned from `existing_children`.
+    fn existing_attribute_names(&mut self, _element: &Self::Element) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Contents of a node that was already present in a `DomBackend`'s document.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExistingValue {
+    /// An element with the given tag name (compared case-insensitively)
+    Element(String),
+    /// A text node
+    Text(String),
+    /// Any other kind of node, such as a comment. These are removed during hydration.
+    Other,
+}
+
+/// Number of operations of each kind performed on a `CountingBackend`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct OpCounts {
+    /// Elements and text nodes created
+    pub created: usize,
+    /// Children inserted
+    pub inserted: usize,
+    /// Children moved
+    pub moved: usize,
+    /// Attributes set
+    pub attributes_set: usize,
+    /// Attributes removed
+    pub attributes_removed: usize,
+    /// Listeners added
+    pub listeners_added: usize,
+    /// Listeners removed
+    pub listeners_removed: usize,
+    /// Elements and text nodes removed
+    pub removed: usize,
+}
+
+impl OpCounts {
+    /// Returns the total number of operations.
+    pub fn total(&self) -> usize {
+        self.created + self.inserted + self.moved + self.attributes_set + self.attributes_removed +
+            self.listeners_added + self.listeners_removed + self.removed
+    }
+}
+
+impl ops::Add for OpCounts {
+    type Output = OpCounts;
+
+    fn add(self, other: OpCounts) -> OpCounts {
+        OpCounts {
+            created: self.created + other.created,
+            inserted: self.inserted + other.inserted,
+            moved: self.moved + other.moved,
+            attributes_set: self.attributes_set + other.attributes_set,
+            attributes_removed: self.attributes_removed + other.attributes_removed,
+            listeners_added: self.listeners_added + other.listeners_added,
+            listeners_removed: self.listeners_removed + other.listeners_removed,
+            removed: self.removed + other.removed,
+        }
+    }
+}
+
+/// A `DomBackend` which counts the operations performed on the backend it wraps, for measuring
+/// how much work `VDom::update` does.
+///
+/// Example:
+///
+/// ```rust
+/// use domafic::DomNode;
+/// use domafic::tags::{li, ul};
+/// use domafic::vdom::{CountingBackend, VDom};
+/// use domafic::virtual_document::VirtualDocument;
+///
+/// let render = |order: &[usize]| ul(
+///     order.iter().map(|&key| li(key.to_string()).with_key(key)).collect::<Vec<_>>()
+/// );
+///
+/// let mut backend = CountingBackend::new(VirtualDocument::<()>::new());
+/// let mut vdom = VDom::new(backend.inner().root());
+/// let (first, second) = (render(&[1, 2, 3, 4]), render(&[4, 1, 2, 3]));
+/// unsafe { vdom.update(&mut backend, &first); }
+/// backend.reset_counts();
+///
+/// unsafe { vdom.update(&mut backend, &second); }
+/// assert_eq!(1, backend.counts().moved);
+/// assert_eq!(1, backend.counts().total());
+/// ```
+#[derive(Debug, Default)]
+pub struct CountingBackend<B> {
+    backend: B,
+    counts: OpCounts,
+}
+
+impl<B> CountingBackend<B> {
+    /// Creates a `CountingBackend` wrapping `backend`, with all counts at zero.
+    pub fn new(backend: B) -> CountingBackend<B> {
+        CountingBackend { backend, counts: OpCounts::default() }
+    }
+
+    /// Returns the number of operations performed since creation or the last `reset_counts`.
+    pub fn counts(&self) -> OpCounts {
+        self.counts
+    }
+
+    /// Sets all counts back to zero.
+    pub fn reset_counts(&mut self) {
+        self.counts = OpCounts::default();
+    }
+
+    /// Returns a reference to the wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the wrapped backend.
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Returns the wrapped backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+}
+
+impl<M, B: DomBackend<M>> DomBackend<M> for CountingBackend<B> {
+    type Element = B::Element;
+    type ListenerHandle = B::ListenerHandle;
+
+    fn create_element(&mut self, tag: &'static str) -> B::Element {
+        self.counts.created += 1;
+        self.backend.create_element(tag)
+    }
+
+    fn create_text_node(&mut self, text: &str) -> B::Element {
+        self.counts.created += 1;
+        self.backend.create_text_node(text)
+    }
+
+    fn insert(&mut self, parent: &B::Element, index: usize, child: &B::Element) {
+        self.counts.inserted += 1;
+        self.backend.insert(parent, index, child)
+    }
+
+    fn move_child(&mut self, parent: &B::Element, old_index: usize, new_index: usize) {
+        self.counts.moved += 1;
+        self.backend.move_child(parent, old_index, new_index)
+    }
+
+    fn set_attribute(&mut self, element: &B::Element, attribute: &KeyValue) {
+        self.counts.attributes_set += 1;
+        self.backend.set_attribute(element, attribute)
+    }
+
+    fn remove_attribute(&mut self, element: &B::Element, key: &str) {
+        self.counts.attributes_removed += 1;
+        self.backend.remove_attribute(element, key)
+    }
+
+    fn add_listener(
+        &mut self,
+        element: &B::Element,
+        event_type: &'static str,
+        listener: *const dyn Listener<M>,
+        keys: Keys,
+    ) -> B::ListenerHandle {
+        self.counts.listeners_added += 1;
+        self.backend.add_listener(element, event_type, listener, keys)
+    }
+
+    fn remove_listener(
+        &mut self,
+        element: &B::Element,
+        event_type: &'static str,
+        listener: B::ListenerHandle,
+    ) {
+        self.counts.listeners_removed += 1;
+        self.backend.remove_listener(element, event_type, listener)
+    }
+
+    fn remove_self(&mut self, element: &B::Element) {
+        self.counts.removed += 1;
+        self.backend.remove_self(element)
+    }
+
+    fn existing_children(&mut self, parent: &B::Element) -> Vec<B::Element> {
+        self.backend.existing_children(parent)
+    }
+
+    fn existing_value(&mut self, node: &B::Element) -> ExistingValue {
+        self.backend.existing_value(node)
+    }
+
+    fn existing_attribute(&mut self, element: &B::Element, key: &str) -> Option<String> {
+        self.backend.existing_attribute(element, key)
+    }
+
+    fn existing_attribute_names(&mut self, element: &B::Element) -> Vec<String> {
+        self.backend.existing_attribute_names(element)
+    }
+}
+
+/// A difference between the existing contents of a document and the first render, found and
+/// corrected by `VDom::hydrate`.
+///
+/// Paths are the indices of the rendered nodes leading from the root element to a node.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HydrationMismatch {
+    /// The rendered node at `path` didn't match the existing node in its place (if any), so it
+    /// was created from scratch.
+    Node {
+        /// Location of the rendered node
+        path: Vec<usize>,
+        /// Description of the rendered node, such as `<div>` or `"text"`
+        expected: String,
+        /// Description of the existing node, or `None` if there were no more existing nodes
+        found: Option<String>,
+    },
+    /// The attribute `key` of the element at `path` had a different value, so it was updated
+    /// (or removed, if it wasn't rendered).
+    Attribute {
+        /// Location of the element
+        path: Vec<usize>,
+        /// Name of the attribute
+        key: String,
+        /// Rendered value of the attribute, or `None` if it should be absent
+        expected: Option<String>,
+        /// Existing value of the attribute, or `None` if it was absent
+        found: Option<String>,
+    },
+    /// An existing node inside of the element at `parent_path` wasn't rendered, so it was
+    /// removed.
+    Extra {
+        /// Location of the parent element
+        parent_path: Vec<usize>,
+        /// Description of the removed node
+        found: String,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum VNodeValue {
+    Text(String),
+    Tag(&'static str),
+}
+
+struct VDomNode<Message: 'static, B: DomBackend<Message>> {
+    value: VNodeValue,
+    keys: Keys,
+    element: B::Element,
+    attributes: Vec<KeyValue>,
+    listeners: Vec<(B::ListenerHandle, *const dyn Listener<Message>, &'static str)>,
+    children: Vec<VDomNode<Message, B>>,
+}
+
+/// Virtual DOM mirroring the contents of a root element in a `DomBackend`.
+///
+/// Each call to `update` compares the newly rendered `DomNode` against the previous one and
+/// issues the minimal set of backend operations needed to bring the backend up to date.
+pub struct VDom<Message: 'static, B: DomBackend<Message>> {
+    root: VDomNode<Message, B>,
+}
+
+impl<M: 'static, B: DomBackend<M>> VDom<M, B> {
+    /// Creates an empty `VDom` that draws into the (assumed empty) element `root_element`.
+    pub fn new(root_element: B::Element) -> VDom<M, B> {
+        VDom {
+            root: VDomNode {
+                value: VNodeValue::Tag("N/A - root"),
+                keys: Keys::new(),
+                element: root_element,
+                attributes: Vec::new(),
+                listeners: Vec::new(),
+                children: Vec::new(),
+            }
+        }
+    }
+
+    /// Returns the root element that this `VDom` draws into.
+    pub fn root_element(&self) -> &B::Element {
+        &self.root.element
+    }
+
+    /// Builds the virtual DOM for `rendered` from the existing contents of the root element
+    /// (such as markup rendered on the server with `HtmlWriter`) instead of creating them again.
+    ///
+    /// Existing nodes are matched in order against `rendered`, and listeners are attached to the
+    /// matching elements. Where the existing nodes don't match, the document is corrected and a
+    /// `HydrationMismatch` is reported. Whitespace-only text nodes (as produced by pretty-printed
+    /// HTML) are removed unless they were rendered. Since HTML can't represent adjacent text
+    /// nodes, rendering several text nodes next to each other results in mismatches.
+    ///
+    /// This should be called instead of the first call to `update`.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as `update` apply.
+    pub unsafe fn hydrate<D: DomNode<M>>(&mut self, backend: &mut B, rendered: &D)
+        -> Vec<HydrationMismatch>
+    {
+        assert!(self.root.children.is_empty(), "Only an empty `VDom` can be hydrated");
+        let mut mismatches = Vec::new();
+        let VDomNode { ref element, ref mut children, .. } = self.root;
+        hydrate_children(backend, Keys::new(), element, children, &[], &mut mismatches, rendered)
+            .expect("Failed to hydrate DomNode from backend");
+        mismatches
+    }
+
+    /// Updates the contents of the root element to match `rendered`.
+    ///
+    /// # Safety
+    ///
+    /// Requires that `rendered` is neither moved nor dropped until the next call to `update`
+    /// (or until the `VDom` is dropped), since the backend holds pointers to its listeners.
+    pub unsafe fn update<D: DomNode<M>>(&mut self, backend: &mut B, rendered: &D) {
+        let VDomNode { ref element, ref mut children, .. } = self.root;
+        write_children(backend, Keys::new(), element, children, rendered)
+            .expect("Failed to write DomNode to backend");
+    }
+}
+
+/// Reconciles `level` (the previous children of `parent`) with `children`.
+///
+/// Each rendered child reuses the first unused previous child with the same keys and value, and
+/// the rest are created. The reused children are then put in order with as few moves as possible:
+/// the longest run of them that is already in the right relative order stays in place, and only
+/// the others are moved around it. Previous children which weren't reused are removed.
+fn write_children<M, B, C>(
+    backend: &mut B,
+    keys: Keys,
+    parent: &B::Element,
+    level: &mut Vec<VDomNode<M, B>>,
+    children: &C) -> Result<(), ()>
+    where
+    M: 'static,
+    B: DomBackend<M>,
+    C: DomNodes<M>
+{
+    let mut old_level: Vec<Option<VDomNode<M, B>>> = level.drain(..).map(Some).collect();
+    let mut sources = Vec::new();
+    {
+        let mut acc = VDomWriterAcc {
+            backend: &mut *backend,
+            keys,
+            old_level: &mut old_level,
+            old_indices: None,
+            new_level: &mut *level,
+            sources: &mut sources,
+        };
+        children.process_all::<VDomWriter<M, B>>(&mut acc)?;
+    }
+    arrange_children(backend, parent, level, &sources, old_level.len());
+    // Remove elements left over from the last render that weren't repurposed
+    for unused_node in old_level.into_iter().rev().flatten() {
+        backend.remove_self(&unused_node.element);
+    }
+    Ok(())
+}
+
+/// Inserts the created children of `parent` and moves the reused ones into their new positions.
+///
+/// `sources[i]` is the previous index of `level[i]` if it was reused, or `None` if it was created.
+/// The document still contains all `old_len` previous children in their previous order.
+fn arrange_children<M, B>(
+    backend: &mut B,
+    parent: &B::Element,
+    level: &[VDomNode<M, B>],
+    sources: &[Option<usize>],
+    old_len: usize)
+    where
+    M: 'static,
+    B: DomBackend<M>
+{
+    let stays = longest_increasing_subsequence(sources);
+    if stays.iter().all(|&stays| stays) {
+        return;
+    }
+
+    // Each child that doesn't stay is placed directly after its new predecessor, so these children
+    // form runs following a child that stays (or at the start). The document order is tracked as
+    // a row of slots: the run at the start, then each previous child followed by the run placed
+    // after it. `anchor` 0 is the start and `anchor` `n + 1` follows the previous child `n`.
+    let mut run_lengths = vec![0; old_len + 1];
+    let mut run_slots = vec![(0, 0); level.len()];
+    let mut anchor = 0;
+    for (index, &source) in sources.iter().enumerate() {
+        if stays[index] {
+            anchor = source.expect("Only reused children can stay") + 1;
+        } else {
+            run_slots[index] = (anchor, run_lengths[anchor]);
+            run_lengths[anchor] += 1;
+        }
+    }
+    let mut run_starts = Vec::with_capacity(old_len + 1);
+    let mut slot_count = 0;
+    for (anchor, &length) in run_lengths.iter().enumerate() {
+        if anchor > 0 {
+            // The slot of the previous child `anchor - 1`
+            slot_count += 1;
+        }
+        run_starts.push(slot_count);
+        slot_count += length;
+    }
+    let old_slot = |old_index: usize| run_starts[old_index + 1] - 1;
+
+    let mut occupied = SlotCounter::new(slot_count);
+    for old_index in 0..old_len {
+        occupied.insert(old_slot(old_index));
+    }
+
+    for (index, vnode) in level.iter().enumerate() {
+        if stays[index] {
+            continue;
+        }
+        let (anchor, offset) = run_slots[index];
+        let slot = run_starts[anchor] + offset;
+        match sources[index] {
+            Some(old_index) => {
+                let current = occupied.count_before(old_slot(old_index));
+                occupied.remove(old_slot(old_index));
+                let target = occupied.count_before(slot);
+                if current != target {
+                    backend.move_child(parent, current, target);
+                }
+            }
+            None => backend.insert(parent, occupied.count_before(slot), &vnode.element),
+        }
+        occupied.insert(slot);
+    }
+}
+
+/// Fenwick tree counting the occupied slots before a given slot in `O(log n)`
+struct SlotCounter(Vec<usize>);
+
+impl SlotCounter {
+    fn new(len: usize) -> SlotCounter {
+        SlotCounter(vec![0; len + 1])
+    }
+
+    fn insert(&mut self, slot: usize) {
+        let mut index = slot + 1;
+        while index < self.0.len() {
+            self.0[index] += 1;
+            index += index & index.wrapping_neg();
+        }
+    }
+
+    fn remove(&mut self, slot: usize) {
+        let mut index = slot + 1;
+        while index < self.0.len() {
+            self.0[index] -= 1;
+            index += index & index.wrapping_neg();
+        }
+    }
+
+    fn count_before(&self, slot: usize) -> usize {
+        let mut count = 0;
+        let mut index = slot;
+        while index > 0 {
+            count += self.0[index];
+            index -= index & index.wrapping_neg();
+        }
+        count
+    }
+}
+
+/// Returns which of the present `values` form a longest strictly increasing subsequence of them.
+fn longest_increasing_subsequence(values: &[Option<usize>]) -> Vec<bool> {
+    // `tails[n]` is the index of the smallest value ending an increasing subsequence of length
+    // `n + 1`, and `predecessors` links each value to the one before it in such a subsequence
+    let mut tails: Vec<usize> = Vec::new();
+    let mut predecessors = vec![None; values.len()];
+    for (index, value) in values.iter().enumerate() {
+        let value = match *value {
+            Some(value) => value,
+            None => continue,
+        };
+        let length = tails.partition_point(|&tail| values[tail] < Some(value));
+        if length > 0 {
+            predecessors[index] = Some(tails[length - 1]);
+        }
+        if length == tails.len() {
+            tails.push(index);
+        } else {
+            tails[length] = index;
+        }
+    }
+
+    let mut in_subsequence = vec![false; values.len()];
+    let mut next = tails.last().cloned();
+    while let Some(index) = next {
+        in_subsequence[index] = true;
+        next = predecessors[index];
+    }
+    in_subsequence
+}
+
+/// Returns the keys of `node`, given the keys of its parent.
+fn node_keys<M, T: DomNode<M>>(parent_keys: &Keys, node: &T) -> Keys {
+    match node.key() {
+        Some(key) => parent_keys.push(key.clone()),
+        None => parent_keys.clone(),
+    }
+}
+
+fn node_value<M, T: DomNode<M>>(node: &T) -> VNodeValue {
+    match node.value() {
+        DomValue::Element { tag } => VNodeValue::Tag(tag),
+        DomValue::Text(text) => VNodeValue::Text(text.to_string()),
+    }
+}
+
+fn node_listeners<M: 'static, T: DomNode<M>>(node: &T) -> Result<Vec<*const dyn Listener<M>>, ()> {
+    let mut listeners = Vec::new();
+    node.listeners().process_all::<ListenersToVec<M>>(&mut listeners)?;
+    Ok(listeners)
+}
+
+/// Creates a detached element for `node` and its children.
+fn create_vnode<M, B, T>(backend: &mut B, keys: Keys, node: &T) -> Result<VDomNode<M, B>, ()>
+    where
+    M: 'static,
+    B: DomBackend<M>,
+    T: DomNode<M>
+{
+    let element = match node.value() {
+        DomValue::Element { tag } => backend.create_element(tag),
+        DomValue::Text(text) => backend.create_text_node(text),
+    };
+
+    let mut listeners_with_metadata = Vec::new();
+    for listener in node_listeners(node)? {
+        let event_type = unsafe { (*listener).event_type_handled() };
+        let handle = backend.add_listener(&element, event_type, listener, keys.clone());
+        listeners_with_metadata.push((handle, listener, event_type));
+    }
+
+    let mut vnode_attributes = Vec::new();
+    for attr in node.attributes() {
+        backend.set_attribute(&element, attr);
+        vnode_attributes.push(attr.clone());
+    }
+
+    let mut vnode = VDomNode {
+        value: node_value(node),
+        keys: keys.clone(),
+        element,
+        attributes: vnode_attributes,
+        listeners: listeners_with_metadata,
+        children: Vec::new(),
+    };
+
+    write_children(backend, keys, &vnode.element, &mut vnode.children, node.children())?;
+    Ok(vnode)
+}
+
+/// Updates the listeners, attributes and children of the existing element `vnode` to match `node`.
+fn update_vnode<M, B, T>(backend: &mut B, keys: Keys, vnode: &mut VDomNode<M, B>, node: &T)
+    -> Result<(), ()>
+    where
+    M: 'static,
+    B: DomBackend<M>,
+    T: DomNode<M>
+{
+    let listeners = node_listeners(node)?;
+
+    // Remove excess listeners
+    let mut i = 0;
+    while i < vnode.listeners.len() {
+        let is_current = {
+            let (_, old_ptr, old_str) = vnode.listeners[i];
+            listeners.iter().any(|listener|
+                ptr::eq(old_ptr, *listener) &&
+                old_str == unsafe { (**listener).event_type_handled() }
+            )
+        };
+        if is_current {
+            i += 1;
+        } else {
+            let (old_handle, _, old_str) = vnode.listeners.remove(i);
+            backend.remove_listener(&vnode.element, old_str, old_handle);
+        }
+    }
+
+    // Add new listeners
+    for listener in listeners {
+        let event_type = unsafe { (*listener).event_type_handled() };
+        if !vnode.listeners.iter().any(|x|
+                ptr::eq(x.1, listener) &&
+                x.2 == event_type
+            ) {
+            let handle = backend.add_listener(
+                &vnode.element,
+                event_type,
+                listener,
+                keys.clone()
+            );
+            vnode.listeners.push((handle, listener, event_type));
+        }
+    }
+
+    // Remove attributes whose keys are absent from the new node
+    let mut i = 0;
+    while i < vnode.attributes.len() {
+        if node.attributes().any(|attr| attr.0 == vnode.attributes[i].0) {
+            i += 1;
+        } else {
+            let old_attribute = vnode.attributes.remove(i);
+            backend.remove_attribute(&vnode.element, old_attribute.0);
+        }
+    }
+
+    // Add new attributes, and set changed ones in place
+    for new_attribute in node.attributes() {
+        match vnode.attributes.iter().position(|attr| attr.0 == new_attribute.0) {
+            Some(index) => if vnode.attributes[index] != *new_attribute {
+                backend.set_attribute(&vnode.element, new_attribute);
+                vnode.attributes[index] = new_attribute.clone();
+            },
+            None => {
+                backend.set_attribute(&vnode.element, new_attribute);
+                vnode.attributes.push(new_attribute.clone());
+            }
+        }
+    }
+
+    // To the children!
+    write_children(backend, keys, &vnode.element, &mut vnode.children, node.children())
+}
+
+type OldIndices = HashMap<(Keys, VNodeValue), VecDeque<usize>>;
+
+struct VDomWriter<'a, 'n, M, B>(PhantomData<(&'a (), &'n (), M, B)>);
+struct VDomWriterAcc<'n, M: 'static, B: DomBackend<M> + 'n> {
+    backend: &'n mut B,
+    keys: Keys,
+    // Previous children, taken out as they are reused
+    old_level: &'n mut Vec<Option<VDomNode<M, B>>>,
+    // Indices of the unused previous children by keys and value, only built once a child doesn't
+    // reuse the previous child in its position
+    old_indices: Option<OldIndices>,
+    new_level: &'n mut Vec<VDomNode<M, B>>,
+    // Previous index of each child in `new_level`, if it was reused
+    sources: &'n mut Vec<Option<usize>>,
+}
+
+impl<'n, M: 'static, B: DomBackend<M> + 'n> VDomWriterAcc<'n, M, B> {
+    /// Returns the index of the first unused previous child with the given keys and value.
+    fn take_match(&mut self, keys: &Keys, value: &VNodeValue) -> Option<usize> {
+        let index = self.new_level.len();
+        if self.old_indices.is_none() {
+            if let Some(Some(old)) = self.old_level.get(index) {
+                if old.keys == *keys && old.value == *value {
+                    return Some(index);
+                }
+            }
+            // All previous children before `index` have been reused in order so far
+            let mut old_indices = OldIndices::new();
+            for (old_index, old) in self.old_level.iter().enumerate().skip(index) {
+                if let Some(ref old) = *old {
+                    old_indices.entry((old.keys.clone(), old.value.clone()))
+                        .or_default()
+                        .push_back(old_index);
+                }
+            }
+            self.old_indices = Some(old_indices);
+        }
+        self.old_indices.as_mut().unwrap()
+            .get_mut(&(keys.clone(), value.clone()))
+            .and_then(VecDeque::pop_front)
+    }
+}
+
+impl<'a, 'n, M, B> DomNodeProcessor<'a, M> for VDomWriter<'a, 'n, M, B>
+    where
+    M: 'static,
+    B: DomBackend<M> + 'n
+{
+    type Acc = VDomWriterAcc<'n, M, B>;
+    type Error = ();
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), Self::Error> {
+        fn add_node<'a, 'n, T, M, B>(
+            acc: &mut VDomWriterAcc<'n, M, B>,
+            node: &'a T) -> Result<(), ()>
+            where
+            T: DomNode<M>,
+            M: 'static,
+            B: DomBackend<M>
+        {
+            let keys = node_keys(&acc.keys, node);
+            let vnode = match acc.take_match(&keys, &node_value(node)) {
+                Some(old_index) => {
+                    // Modify the existing element
+                    let mut vnode = acc.old_level[old_index].take().unwrap();
+                    update_vnode(acc.backend, keys, &mut vnode, node)?;
+                    acc.sources.push(Some(old_index));
+                    vnode
+                }
+                None => {
+                    // Construct as a new element, inserted by `arrange_children`
+                    acc.sources.push(None);
+                    create_vnode(acc.backend, keys, node)?
+                }
+            };
+            acc.new_level.push(vnode);
+            Ok(())
+        }
+
+        add_node
+    }
+}
+
+/// Matches the existing children of `parent` against `children`, building `level`.
+fn hydrate_children<M, B, C>(
+    backend: &mut B,
+    keys: Keys,
+    parent: &B::Element,
+    level: &mut Vec<VDomNode<M, B>>,
+    path: &[usize],
+    mismatches: &mut Vec<HydrationMismatch>,
+    children: &C) -> Result<(), ()>
+    where
+    M: 'static,
+    B: DomBackend<M>,
+    C: DomNodes<M>
+{
+    let mut existing: VecDeque<B::Element> = backend.existing_children(parent).into();
+    {
+        let mut acc = VDomHydratorAcc {
+            backend,
+            keys,
+            parent_element: parent,
+            node_level: level,
+            existing: &mut existing,
+            path,
+            mismatches,
+        };
+        children.process_all::<VDomHydrator<M, B>>(&mut acc)?;
+    }
+    // Remove existing nodes that weren't rendered
+    for element in existing {
+        match backend.existing_value(&element) {
+            ExistingValue::Other => {}
+            ExistingValue::Text(ref text) if text.trim().is_empty() => {}
+            value => mismatches.push(HydrationMismatch::Extra {
+                parent_path: path.to_vec(),
+                found: describe_existing(&value),
+            }),
+        }
+        backend.remove_self(&element);
+    }
+    Ok(())
+}
+
+fn describe_rendered(value: &DomValue) -> String {
+    match *value {
+        DomValue::Element { tag } => format!("<{}>", tag),
+        DomValue::Text(text) => format!("{:?}", text),
+    }
+}
+
+fn describe_existing(value: &ExistingValue) -> String {
+    match *value {
+        ExistingValue::Element(ref tag) => format!("<{}>", tag.to_lowercase()),
+        ExistingValue::Text(ref text) => format!("{:?}", text),
+        ExistingValue::Other => "(other node)".to_string(),
+    }
+}
+
+struct VDomHydrator<'a, 'n, M, B>(PhantomData<(&'a (), &'n (), M, B)>);
+struct VDomHydratorAcc<'n, M: 'static, B: DomBackend<M> + 'n> {
+    backend: &'n mut B,
+    keys: Keys,
+    parent_element: &'n B::Element,
+    node_level: &'n mut Vec<VDomNode<M, B>>,
+    existing: &'n mut VecDeque<B::Element>,
+    path: &'n [usize],
+    mismatches: &'n mut Vec<HydrationMismatch>,
+}
+
+impl<'a, 'n, M, B> DomNodeProcessor<'a, M> for VDomHydrator<'a, 'n, M, B>
+    where
+    M: 'static,
+    B: DomBackend<M> + 'n
+{
+    type Acc = VDomHydratorAcc<'n, M, B>;
+    type Error = ();
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), Self::Error> {
+        fn hydrate_node<'a, 'n, T, M, B>(
+            acc: &mut VDomHydratorAcc<'n, M, B>,
+            node: &'a T) -> Result<(), ()>
+            where
+            T: DomNode<M>,
+            M: 'static,
+            B: DomBackend<M>
+        {
+            let value = node.value();
+            let mut path = acc.path.to_vec();
+            path.push(acc.node_level.len());
+
+            // Discard formatting whitespace and unsupported nodes preceding the match
+            let found = loop {
+                let found = match acc.existing.front() {
+                    Some(element) => acc.backend.existing_value(element),
+                    None => break None,
+                };
+                let discard = match found {
+                    ExistingValue::Other => true,
+                    ExistingValue::Text(ref text) => text.trim().is_empty() &&
+                        !matches!(value, DomValue::Text(rendered) if rendered == text),
+                    ExistingValue::Element(_) => false,
+                };
+                if !discard {
+                    break Some(found);
+                }
+                let element = acc.existing.pop_front().unwrap();
+                acc.backend.remove_self(&element);
+            };
+
+            let is_match = match (&found, &value) {
+                (&Some(ExistingValue::Element(ref found_tag)), &DomValue::Element { tag }) =>
+                    found_tag.eq_ignore_ascii_case(tag),
+                (&Some(ExistingValue::Text(ref found_text)), &DomValue::Text(text)) =>
+                    found_text == text,
+                _ => false,
+            };
+
+            if !is_match {
+                acc.mismatches.push(HydrationMismatch::Node {
+                    path,
+                    expected: describe_rendered(&value),
+                    found: found.as_ref().map(describe_existing),
+                });
+                // Create the node from scratch in front of the remaining existing nodes
+                let vnode = create_vnode(acc.backend, node_keys(&acc.keys, node), node)?;
+                acc.backend.insert(acc.parent_element, acc.node_level.len(), &vnode.element);
+                acc.node_level.push(vnode);
+                return Ok(());
+            }
+
+            let element = acc.existing.pop_front().unwrap();
+            let keys = node_keys(&acc.keys, node);
+
+            let mut listeners_with_metadata = Vec::new();
+            for listener in node_listeners(node)? {
+                let event_type = unsafe { (*listener).event_type_handled() };
+                let handle = acc.backend.add_listener(&element, event_type, listener, keys.clone());
+                listeners_with_metadata.push((handle, listener, event_type));
+            }
+
+            let mut vnode_attributes = Vec::new();
+            for attr in node.attributes() {
+                let expected = match attr.1 {
+                    AttributeValue::Bool(false) => None,
+                    AttributeValue::Bool(true) => Some(""),
+                    ref value => Some(value.as_str()),
+                };
+                let found = acc.backend.existing_attribute(&element, attr.0);
+                let is_match = match attr.1 {
+                    AttributeValue::Bool(true) => found.is_some(),
+                    _ => found.as_deref() == expected,
+                };
+                if !is_match {
+                    acc.mismatches.push(HydrationMismatch::Attribute {
+                        path: path.clone(),
+                        key: attr.0.to_string(),
+                        expected: expected.map(str::to_string),
+                        found,
+                    });
+                    acc.backend.set_attribute(&element, attr);
+                }
+                vnode_attributes.push(attr.clone());
+            }
+            for key in acc.backend.existing_attribute_names(&element) {
+                if !node.attributes().any(|attr| attr.0.eq_ignore_ascii_case(&key)) {
+                    let found = acc.backend.existing_attribute(&element, &key);
+                    acc.backend.remove_attribute(&element, &key);
+                    acc.mismatches.push(HydrationMismatch::Attribute {
+                        path: path.clone(),
+                        key,
+                        expected: None,
+                        found,
+                    });
+                }
+            }
+
+            let mut vnode = VDomNode {
+                value: node_value(node),
+                keys: keys.clone(),
+                element,
+                attributes: vnode_attributes,
+                listeners: listeners_with_metadata,
+                children: Vec::new(),
+            };
+
+            hydrate_children(
+                acc.backend,
+                keys,
+                &vnode.element,
+                &mut vnode.children,
+                &path,
+                acc.mismatches,
+                node.children()
+            )?;
+
+            acc.node_level.push(vnode);
+            Ok(())
+        }
+
+        hydrate_node
+    }
+}
+
+struct ListenersToVec<Message: 'static>(PhantomData<Message>);
+impl<'a, M: 'static> ListenerProcessor<'a, M> for ListenersToVec<M> {
+    type Acc = Vec<*const dyn Listener<M>>;
+    type Error = ();
+
+    fn get_processor<L: Listener<M>>() -> fn(&mut Self::Acc, &'a L) -> Result<(), Self::Error> {
+        fn add_listener_to_vec<M, L: Listener<M>>(
+            vec: &mut Vec<*const dyn Listener<M>>,
+            listener: &L) -> Result<(), ()>
+        {
+            vec.push(
+                // Extend the lifetime of the listener (we know it's valid until at least the
+                // next call to `VDom::update`) and convert it to a *const
+                unsafe { mem::transmute(listener as &dyn Listener<M>) }
+            );
+            Ok(())
+        }
+        add_listener_to_vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{longest_increasing_subsequence, CountingBackend, DomBackend, HydrationMismatch};
+    use super::{OpCounts, VDom};
+    use {DomNode, Key, KeyValue, Listener};
+    use AttributeValue::Str;
+    use keys::Keys;
+    use listener::on;
+    use tags::{attributes, b, div, i, li, p, span, ul};
+    use virtual_document::VirtualDocument;
+
+    /// Backend which records every operation performed on it
+    #[derive(Debug, Default)]
+    struct RecordingBackend {
+        next_id: usize,
+        ops: Vec<String>,
+    }
+
+    impl<M> DomBackend<M> for RecordingBackend {
+        type Element = usize;
+        type ListenerHandle = usize;
+
+        fn create_element(&mut self, tag: &'static str) -> usize {
+            self.next_id += 1;
+            self.ops.push(format!("create {} {}", self.next_id, tag));
+            self.next_id
+        }
+        fn create_text_node(&mut self, text: &str) -> usize {
+            self.next_id += 1;
+            self.ops.push(format!("create {} {:?}", self.next_id, text));
+            self.next_id
+        }
+        fn insert(&mut self, parent: &usize, index: usize, child: &usize) {
+            self.ops.push(format!("insert {} {} {}", parent, index, child));
+        }
+        fn move_child(&mut self, parent: &usize, old_index: usize, new_index: usize) {
+            self.ops.push(format!("move {} {} {}", parent, old_index, new_index));
+        }
+        fn set_attribute(&mut self, element: &usize, attribute: &KeyValue) {
+            self.ops.push(format!("set {} {}={}", element, attribute.0, attribute.1));
+        }
+        fn remove_attribute(&mut self, element: &usize, key: &str) {
+            self.ops.push(format!("unset {} {}", element, key));
+        }
+        fn add_listener(
+            &mut self,
+            element: &usize,
+            event_type: &'static str,
+            _listener: *const dyn Listener<M>,
+            _keys: Keys,
+        ) -> usize {
+            self.ops.push(format!("listen {} {}", element, event_type));
+            *element
+        }
+        fn remove_listener(&mut self, element: &usize, event_type: &'static str, _: usize) {
+            self.ops.push(format!("unlisten {} {}", element, event_type));
+        }
+        fn remove_self(&mut self, element: &usize) {
+            self.ops.push(format!("remove {}", element));
+        }
+    }
+
+    fn update<D: DomNode<()>>(
+        vdom: &mut VDom<(), RecordingBackend>,
+        backend: &mut RecordingBackend,
+        rendered: &D) -> Vec<String>
+    {
+        unsafe { vdom.update(backend, rendered); }
+        backend.ops.drain(..).collect()
+    }
+
+    #[test]
+    fn creates_initial_tree() {
+        let mut backend = RecordingBackend::default();
+        let mut vdom = VDom::new(0);
+        let ops = update(&mut vdom, &mut backend, &div((
+            attributes([("class", Str("big"))]),
+            "hello",
+        )).with_listeners(on("click", |_| ())));
+        assert_eq!(ops, vec![
+            "create 1 div",
+            "listen 1 click",
+            "set 1 class=big",
+            "create 2 \"hello\"",
+            "insert 1 0 2",
+            "insert 0 0 1",
+        ]);
+    }
+
+    #[test]
+    fn rerendering_identical_tree_is_a_no_op() {
+        let render = |text: &'static str| div((
+            attributes([("class", Str("big"))]),
+            text,
+        ));
+        let mut backend = RecordingBackend::default();
+        let mut vdom = VDom::new(0);
+        update(&mut vdom, &mut backend, &render("hello"));
+        assert!(update(&mut vdom, &mut backend, &render("hello")).is_empty());
+        assert_eq!(
+            update(&mut vdom, &mut backend, &render("goodbye")),
+            vec!["create 3 \"goodbye\"", "insert 1 0 3", "remove 2"]
+        );
+    }
+
+    #[test]
+    fn updates_changed_attributes() {
+        let render = |class: &'static str| div((
+            attributes([("id", Str("x")), ("class", Str(class))]),
+            (),
+        ));
+        let mut backend = RecordingBackend::default();
+        let mut vdom = VDom::new(0);
+        update(&mut vdom, &mut backend, &render("a"));
+        assert_eq!(
+            update(&mut vdom, &mut backend, &render("b")),
+            vec!["set 1 class=b"]
+        );
+        assert_eq!(
+            update(&mut vdom, &mut backend, &div((attributes([("id", Str("y"))]), ()))),
+            vec!["unset 1 class", "set 1 id=y"]
+        );
+    }
+
+    #[test]
+    fn moves_keyed_children() {
+        let render = |order: &[usize]| ul(
+            order.iter().map(|&key| li(key.to_string()).with_key(key)).collect::<Vec<_>>()
+        );
+        let mut backend = RecordingBackend::default();
+        let mut vdom = VDom::new(0);
+        update(&mut vdom, &mut backend, &render(&[1, 2, 3]));
+        assert_eq!(
+            update(&mut vdom, &mut backend, &render(&[3, 1, 2])),
+            vec!["move 1 2 0"]
+        );
+    }
+
+    #[test]
+    fn finds_longest_increasing_subsequence() {
+        let sources = [Some(4), None, Some(0), Some(5), Some(1), Some(2), None, Some(3)];
+        assert_eq!(
+            longest_increasing_subsequence(&sources),
+            vec![false, false, true, false, true, true, false, true]
+        );
+        assert!(longest_increasing_subsequence(&[None, None]).iter().all(|&stays| !stays));
+    }
+
+    #[test]
+    fn reorders_keyed_children_with_minimal_moves() {
+        let render = |order: &[usize]| ul(
+            order.iter().map(|&key| li(key.to_string()).with_key(key)).collect::<Vec<_>>()
+        );
+        let mut backend = CountingBackend::new(VirtualDocument::<()>::new());
+        let mut vdom = VDom::new(backend.inner().root());
+        let mut check = |vdom: &mut VDom<(), _>, order: &[usize]| {
+            let rendered = render(order);
+            backend.reset_counts();
+            unsafe { vdom.update(&mut backend, &rendered); }
+            assert_eq!(rendered.to_string(), backend.inner().to_string());
+            backend.counts()
+        };
+
+        let forward: Vec<usize> = (0..100).collect();
+        let reversed: Vec<usize> = (0..100).rev().collect();
+        check(&mut vdom, &forward);
+        assert_eq!(check(&mut vdom, &reversed), OpCounts { moved: 99, ..OpCounts::default() });
+
+        // Moving one child to the other end moves only that child
+        let mut rotated = reversed[1..].to_vec();
+        rotated.push(reversed[0]);
+        assert_eq!(check(&mut vdom, &rotated), OpCounts { moved: 1, ..OpCounts::default() });
+
+        // Only the children outside of the longest ordered run (3, 4, 5, 6, 7) are moved
+        check(&mut vdom, &forward[..10]);
+        assert_eq!(check(&mut vdom, &[9, 3, 4, 11, 5, 6, 7, 0, 12]), OpCounts {
+            created: 4,
+            inserted: 4,
+            moved: 2,
+            removed: 3,
+            ..OpCounts::default()
+        });
+    }
+
+    #[test]
+    fn arranges_large_keyed_lists_with_minimal_operations() {
+        let render = |keys: &[usize]| ul(
+            keys.iter().map(|&key| li(key.to_string()).with_key(key)).collect::<Vec<_>>()
+        );
+        let keys: Vec<usize> = (0..10_000).collect();
+        let mut backend = CountingBackend::new(RecordingBackend::default());
+        let mut vdom = VDom::<(), _>::new(0);
+
+        unsafe { vdom.update(&mut backend, &render(&keys)); }
+        assert_eq!(
+            OpCounts { created: 20_001, inserted: 20_001, ..OpCounts::default() },
+            backend.counts()
+        );
+
+        backend.reset_counts();
+        let reversed: Vec<usize> = keys.iter().rev().cloned().collect();
+        unsafe { vdom.update(&mut backend, &render(&reversed)); }
+        assert_eq!(OpCounts { moved: 9_999, ..OpCounts::default() }, backend.counts());
+    }
+
+    #[test]
+    fn moves_children_keyed_by_strings_and_values() {
+        #[derive(Debug, Hash, Eq, PartialEq)]
+        struct Id(u64);
+
+        let render = |slugs: &[&'static str]| ul((
+            slugs.iter().map(|&slug| li(slug).with_key(slug.to_string())).collect::<Vec<_>>(),
+            slugs.iter().map(|&slug| li(slug).with_key(Key::value(Id(slug.len() as u64))))
+                .collect::<Vec<_>>(),
+        ));
+        let mut backend = RecordingBackend::default();
+        let mut vdom = VDom::new(0);
+        update(&mut vdom, &mut backend, &render(&["a", "bb"]));
+        assert_eq!(
+            update(&mut vdom, &mut backend, &render(&["bb", "a"])),
+            vec!["move 1 1 0", "move 1 3 2"]
+        );
+    }
+
+    #[test]
+    fn hydration_corrects_and_reports_mismatches() {
+        let mut document = VirtualDocument::<()>::prerendered(&div((
+            attributes([("id", Str("main")), ("class", Str("old"))]),
+            ("\n  ", span("a"), "\n  ", i("b"), p("c")),
+        )));
+        let rendered = div((
+            attributes([("id", Str("main")), ("class", Str("new"))]),
+            (span("a"), b("b")),
+        ));
+
+        let mut vdom = VDom::new(document.root());
+        let mismatches = unsafe { vdom.hydrate(&mut document, &rendered) };
+        assert_eq!(mismatches, vec![
+            HydrationMismatch::Attribute {
+                path: vec![0],
+                key: "class".to_string(),
+                expected: Some("new".to_string()),
+                found: Some("old".to_string()),
+            },
+            HydrationMismatch::Node {
+                path: vec![0, 1],
+                expected: "<b>".to_string(),
+                found: Some("<i>".to_string()),
+            },
+            HydrationMismatch::Extra { parent_path: vec![0], found: "<i>".to_string() },
+            HydrationMismatch::Extra { parent_path: vec![0], found: "<p>".to_string() },
+        ]);
+        assert_eq!(rendered.to_string(), document.to_string());
+
+        // The hydrated `VDom` continues to update the document as usual
+        unsafe { vdom.update(&mut document, &rendered); }
+        assert_eq!(rendered.to_string(), document.to_string());
+    }
+}
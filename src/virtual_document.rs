@@ -0,0 +1,448 @@
+use {AttributeValue, DomNode, DomNodes, DomValue, Event, KeyValue, Listener};
+use html_fmt_writer::{Escaped, HtmlAttribute, RawText};
+use keys::Keys;
+use processors::DomNodeProcessor;
+use tags::{is_raw_text_element, is_void_element};
+use vdom::{DomBackend, ExistingValue};
+
+// This module as a whole is "use_std"-only, so these don't need to be cfg'd
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Identifier of a node in a `VirtualDocument`.
+pub type NodeId = usize;
+
+/// Contents of a node in a `VirtualDocument`: either an element's tag name or a text value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NodeValue {
+    /// An element with the given tag name
+    Element(&'static str),
+    /// A text node
+    Text(String),
+}
+
+struct VirtualListener<Message> {
+    id: usize,
+    event_type: &'static str,
+    listener: *const dyn Listener<Message>,
+    keys: Keys,
+}
+
+struct VirtualNode<Message> {
+    value: NodeValue,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    attributes: Vec<KeyValue>,
+    listeners: Vec<VirtualListener<Message>>,
+}
+
+/// An in-memory document implementing `DomBackend`.
+///
+/// `VirtualDocument` keeps track of the node tree, attributes and listeners produced by a `VDom`
+/// so that rendering and event handling can be exercised natively, without a browser.
+/// The document starts out with a single empty `body` element as its root.
+pub struct VirtualDocument<Message> {
+    nodes: Vec<VirtualNode<Message>>,
+    next_listener_id: usize,
+}
+
+impl<M> Default for VirtualDocument<M> {
+    fn default() -> VirtualDocument<M> {
+        VirtualDocument::new()
+    }
+}
+
+impl<M> VirtualDocument<M> {
+    /// Creates a new document containing only an empty root element.
+    pub fn new() -> VirtualDocument<M> {
+        let mut document = VirtualDocument { nodes: Vec::new(), next_listener_id: 0 };
+        document.new_node(NodeValue::Element("body"));
+        document
+    }
+
+    /// Creates a new document whose root contains `nodes` without any of their listeners, as if
+    /// they had been rendered to HTML on the server and loaded by the browser.
+    ///
+    /// Use this with `VDom::hydrate` or `TestApp::hydrate` to test hydration.
+    pub fn prerendered<N: DomNodes<M>>(nodes: &N) -> VirtualDocument<M> {
+        let mut document = VirtualDocument::new();
+        let root = document.root();
+        nodes.process_all::<Prerenderer<M>>(&mut (&mut document, root))
+            .expect("Prerendering can't fail");
+        document
+    }
+
+    /// Returns the root element of the document.
+    pub fn root(&self) -> NodeId {
+        0
+    }
+
+    /// Returns the value of `node`.
+    pub fn value(&self, node: NodeId) -> &NodeValue {
+        &self.nodes[node].value
+    }
+
+    /// Returns the parent of `node`, or `None` if `node` is the root or has been removed.
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        self.nodes[node].parent
+    }
+
+    /// Returns the children of `node`, in order.
+    pub fn children(&self, node: NodeId) -> &[NodeId] {
+        &self.nodes[node].children
+    }
+
+    /// Returns the attributes of `node`, in the order they were set.
+    pub fn attributes(&self, node: NodeId) -> &[KeyValue] {
+        &self.nodes[node].attributes
+    }
+
+    /// Returns the value of the attribute `key` on `node`, if present.
+    pub fn attribute(&self, node: NodeId, key: &str) -> Option<&AttributeValue> {
+        self.nodes[node].attributes.iter()
+            .find(|attribute| attribute.0 == key)
+            .map(|attribute| &attribute.1)
+    }
+
+    /// Returns the event types of all listeners attached to `node`.
+    pub fn listener_types(&self, node: NodeId) -> Vec<&'static str> {
+        self.nodes[node].listeners.iter().map(|listener| listener.event_type).collect()
+    }
+
+    /// Returns the concatenated text of `node` and all of its descendants.
+    pub fn text_content(&self, node: NodeId) -> String {
+        let mut text = String::new();
+        self.append_text(node, &mut text);
+        text
+    }
+
+    fn append_text(&self, node: NodeId, text: &mut String) {
+        match self.nodes[node].value {
+            NodeValue::Text(ref value) => text.push_str(value),
+            NodeValue::Element(_) => {
+                for &child in &self.nodes[node].children {
+                    self.append_text(child, text);
+                }
+            }
+        }
+    }
+
+    /// Returns whether `node` is the root or a descendant of the root.
+    pub fn is_attached(&self, node: NodeId) -> bool {
+        let mut current = node;
+        while let Some(parent) = self.nodes[current].parent {
+            current = parent;
+        }
+        current == self.root()
+    }
+
+    /// Returns the first descendant of the root (in document order) that matches `selector`.
+    ///
+    /// Supported selectors are tag names, `#id`, `.class`, `[attr]` and `[attr=value]`,
+    /// combined into compound selectors (`button.primary`) and separated by whitespace to
+    /// match descendants (`ul li.done`).
+    pub fn query_selector(&self, selector: &str) -> Option<NodeId> {
+        self.query_selector_all(selector).into_iter().next()
+    }
+
+    /// Returns all descendants of the root (in document order) that match `selector`.
+    ///
+    /// See `query_selector` for the supported selector syntax.
+    pub fn query_selector_all(&self, selector: &str) -> Vec<NodeId> {
+        let selector: Vec<CompoundSelector> =
+            selector.split_whitespace().map(CompoundSelector::parse).collect();
+        let mut matches = Vec::new();
+        if !selector.is_empty() {
+            self.collect_matches(self.root(), &selector, &mut matches);
+        }
+        matches
+    }
+
+    fn collect_matches(&self, node: NodeId, selector: &[CompoundSelector], matches: &mut Vec<NodeId>) {
+        for &child in &self.nodes[node].children {
+            if self.matches(child, selector) {
+                matches.push(child);
+            }
+            self.collect_matches(child, selector, matches);
+        }
+    }
+
+    fn matches(&self, node: NodeId, selector: &[CompoundSelector]) -> bool {
+        let (last, ancestors) = selector.split_last().unwrap();
+        if !last.matches(self, node) {
+            return false;
+        }
+        // Greedily match the remaining selectors against the closest possible ancestors
+        let mut remaining = ancestors;
+        let mut current = self.nodes[node].parent;
+        while let Some((next, rest)) = remaining.split_last() {
+            match current {
+                Some(ancestor) if ancestor != self.root() => {
+                    if next.matches(self, ancestor) {
+                        remaining = rest;
+                    }
+                    current = self.nodes[ancestor].parent;
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Calls each listener on `node` that handles events of `event.type_str`, returning the
+    /// resulting messages along with the keys of `node`.
+    ///
+    /// Events are not propagated to the parents of `node`.
+    ///
+    /// # Safety
+    ///
+    /// Requires that the `DomNode` most recently passed to `VDom::update` for this document
+    /// is still alive, since the document only holds pointers to its listeners.
+    pub unsafe fn handle_event(&self, node: NodeId, event: Event) -> Vec<(M, Keys)> {
+        self.nodes[node].listeners.iter()
+            .filter(|listener| Some(listener.event_type) == event.type_str)
+            .map(|listener| ((*listener.listener).handle_event(event), listener.keys.clone()))
+            .collect()
+    }
+
+    fn new_node(&mut self, value: NodeValue) -> NodeId {
+        self.nodes.push(VirtualNode {
+            value,
+            parent: None,
+            children: Vec::new(),
+            attributes: Vec::new(),
+            listeners: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+
+    fn detach(&mut self, node: NodeId) {
+        if let Some(parent) = self.nodes[node].parent.take() {
+            self.nodes[parent].children.retain(|&child| child != node);
+        }
+    }
+
+    fn write_html(&self, node: NodeId, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.nodes[node].value {
+            NodeValue::Element(tag) => {
+                write!(f, "<{}", tag)?;
+                for attr in &self.nodes[node].attributes {
+                    write!(f, "{}", HtmlAttribute(attr))?;
+                }
+                write!(f, ">")?;
+                if is_void_element(tag) {
+                    return Ok(());
+                }
+                for &child in &self.nodes[node].children {
+                    match self.nodes[child].value {
+                        NodeValue::Text(ref text) if is_raw_text_element(tag) =>
+                            write!(f, "{}", RawText(text))?,
+                        _ => self.write_html(child, f)?,
+                    }
+                }
+                write!(f, "</{}>", tag)
+            }
+            NodeValue::Text(ref text) => write!(f, "{}", Escaped(text)),
+        }
+    }
+}
+
+/// Displays the HTML contents of the root element, following the same rules as `HtmlWriter`
+impl<M> fmt::Display for VirtualDocument<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &child in &self.nodes[self.root()].children {
+            self.write_html(child, f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<M> DomBackend<M> for VirtualDocument<M> {
+    type Element = NodeId;
+    type ListenerHandle = usize;
+
+    fn create_element(&mut self, tag: &'static str) -> NodeId {
+        self.new_node(NodeValue::Element(tag))
+    }
+
+    fn create_text_node(&mut self, text: &str) -> NodeId {
+        self.new_node(NodeValue::Text(text.to_string()))
+    }
+
+    fn insert(&mut self, parent: &NodeId, index: usize, child: &NodeId) {
+        self.detach(*child);
+        self.nodes[*parent].children.insert(index, *child);
+        self.nodes[*child].parent = Some(*parent);
+    }
+
+    fn move_child(&mut self, parent: &NodeId, old_index: usize, new_index: usize) {
+        let children = &mut self.nodes[*parent].children;
+        let child = children.remove(old_index);
+        children.insert(new_index, child);
+    }
+
+    fn set_attribute(&mut self, element: &NodeId, attribute: &KeyValue) {
+        let attributes = &mut self.nodes[*element].attributes;
+        attributes.retain(|old| old.0 != attribute.0);
+        attributes.push(attribute.clone());
+    }
+
+    fn remove_attribute(&mut self, element: &NodeId, key: &str) {
+        self.nodes[*element].attributes.retain(|old| old.0 != key);
+    }
+
+    fn add_listener(
+        &mut self,
+        element: &NodeId,
+        event_type: &'static str,
+        listener: *const dyn Listener<M>,
+        keys: Keys,
+    ) -> usize {
+        let id = self.next_listener_id;
+        self.next_listener_id += 1;
+        self.nodes[*element].listeners.push(VirtualListener { id, event_type, listener, keys });
+        id
+    }
+
+    fn remove_listener(&mut self, element: &NodeId, _event_type: &'static str, listener: usize) {
+        self.nodes[*element].listeners.retain(|old| old.id != listener);
+    }
+
+    fn remove_self(&mut self, element: &NodeId) {
+        self.detach(*element);
+
+        // Removed nodes are never reused, so drop their (soon to be dangling) listeners
+        let mut stack = vec![*element];
+        while let Some(node) = stack.pop() {
+            self.nodes[node].listeners.clear();
+            stack.extend_from_slice(&self.nodes[node].children);
+        }
+    }
+
+    fn existing_children(&mut self, parent: &NodeId) -> Vec<NodeId> {
+        self.nodes[*parent].children.clone()
+    }
+
+    fn existing_value(&mut self, node: &NodeId) -> ExistingValue {
+        match self.nodes[*node].value {
+            NodeValue::Element(tag) => ExistingValue::Element(tag.to_string()),
+            NodeValue::Text(ref text) => ExistingValue::Text(text.clone()),
+        }
+    }
+
+    fn existing_attribute(&mut self, element: &NodeId, key: &str) -> Option<String> {
+        match self.attribute(*element, key) {
+            // A `false` boolean attribute isn't present in HTML
+            Some(&AttributeValue::Bool(false)) | None => None,
+            // A `true` boolean attribute has an empty value in HTML
+            Some(&AttributeValue::Bool(true)) => Some(String::new()),
+            Some(value) => Some(value.as_str().to_string()),
+        }
+    }
+
+    fn existing_attribute_names(&mut self, element: &NodeId) -> Vec<String> {
+        self.nodes[*element].attributes.iter()
+            .filter(|attr| attr.1 != AttributeValue::Bool(false))
+            .map(|attr| attr.0.to_string())
+            .collect()
+    }
+}
+
+/// Adds `DomNode`s to a `VirtualDocument` under the given parent, ignoring listeners
+struct Prerenderer<'d, M: 'd>(PhantomData<&'d mut VirtualDocument<M>>);
+impl<'a, 'd, M> DomNodeProcessor<'a, M> for Prerenderer<'d, M> {
+    type Acc = (&'d mut VirtualDocument<M>, NodeId);
+    type Error = ();
+
+    fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), ()> {
+        fn add_node<M, T: DomNode<M>>(
+            acc: &mut (&mut VirtualDocument<M>, NodeId),
+            node: &T) -> Result<(), ()>
+        {
+            let document = &mut *acc.0;
+            let parent = acc.1;
+            let element = match node.value() {
+                DomValue::Element { tag } => document.create_element(tag),
+                DomValue::Text(text) => document.create_text_node(text),
+            };
+            for attr in node.attributes() {
+                document.set_attribute(&element, attr);
+            }
+            let index = document.nodes[parent].children.len();
+            document.insert(&parent, index, &element);
+            node.children().process_all::<Prerenderer<M>>(&mut (document, element))
+        }
+        add_node
+    }
+}
+
+/// A single compound selector, such as `div#main.big[hidden]`
+#[derive(Debug, Default)]
+struct CompoundSelector<'a> {
+    tag: Option<&'a str>,
+    id: Option<&'a str>,
+    classes: Vec<&'a str>,
+    attributes: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> CompoundSelector<'a> {
+    fn parse(selector: &'a str) -> CompoundSelector<'a> {
+        fn name_end(s: &str) -> usize {
+            s.find(['#', '.', '[']).unwrap_or(s.len())
+        }
+
+        let mut compound = CompoundSelector::default();
+        let mut rest = selector;
+        while let Some(first) = rest.chars().next() {
+            match first {
+                '#' | '.' => {
+                    let end = name_end(&rest[1..]) + 1;
+                    if first == '#' {
+                        compound.id = Some(&rest[1..end]);
+                    } else {
+                        compound.classes.push(&rest[1..end]);
+                    }
+                    rest = &rest[end..];
+                }
+                '[' => {
+                    let end = rest.find(']').unwrap_or(rest.len());
+                    let inner = &rest[1..end];
+                    compound.attributes.push(match inner.find('=') {
+                        Some(eq) => (
+                            &inner[..eq],
+                            Some(inner[eq + 1..].trim_matches(|c| c == '"' || c == '\'')),
+                        ),
+                        None => (inner, None),
+                    });
+                    rest = if end < rest.len() { &rest[end + 1..] } else { "" };
+                }
+                _ => {
+                    let end = name_end(rest);
+                    if rest[..end] != *"*" {
+                        compound.tag = Some(&rest[..end]);
+                    }
+                    rest = &rest[end..];
+                }
+            }
+        }
+        compound
+    }
+
+    fn matches<M>(&self, document: &VirtualDocument<M>, node: NodeId) -> bool {
+        let tag = match *document.value(node) {
+            NodeValue::Element(tag) => tag,
+            NodeValue::Text(_) => return false,
+        };
+        let attribute = |key| document.attribute(node, key).map(AttributeValue::as_str);
+
+        self.tag.is_none_or(|expected| expected.eq_ignore_ascii_case(tag)) &&
+            self.id.is_none_or(|expected| attribute("id") == Some(expected)) &&
+            self.classes.iter().all(|class|
+                attribute("class").is_some_and(|classes|
+                    classes.split_whitespace().any(|c| c == *class))) &&
+            self.attributes.iter().all(|&(key, value)| match value {
+                Some(value) => attribute(key) == Some(value),
+                None => attribute(key).is_some(),
+            })
+    }
+}
@@ -1,63 +1,37 @@
-use DomNode;
-use keys::KeyIter;
-
-/// `Updater`s modify the current application state based on messages.
-pub trait Updater<State, Message>: Sized {
-    /// Modify the application state based on a message.
-    ///
-    /// `KeyIter` may be used to identify which component the message originated from.
-    fn update(&self, &mut State, Message, KeyIter, &JsIo<Message>);
-}
-
-impl<F, S, M> Updater<S, M> for F
-    where F: Fn(&mut S, M, KeyIter, &JsIo<M>) -> ()
-{
-    fn update(
-        &self,
-        state: &mut S,
-        msg: M,
-        keys: KeyIter,
-        js_io: &JsIo<M>
-    ) {
-        (self)(state, msg, keys, js_io)
-    }
-}
-
-/// `Renderer`s convert the current state to the current UI `DomNode`.
-pub trait Renderer<State, Message> {
-
-    // Note: this should really be Rendered<'a>: DomNode + 'a
-    // to allow for references to bits of state, but this is
-    // impossible without ATCs
-    /// Type of the rendered `DomNode`
-    type Rendered: DomNode<Message>;
-
-    /// Renders a `DomNode` given the current application state
-    fn render(&self, &State) -> Self::Rendered;
-}
-
-impl<F, S, R, M> Renderer<S, M> for F where F: Fn(&S) -> R, R: DomNode<M> {
-    type Rendered = R;
-    fn render(&self, state: &S) -> Self::Rendered {
-        (self)(state)
-    }
-}
-
-pub use self::private::{run, JsIo, HttpRequest, HttpResponse, HttpResult};
+pub use app::{
+    Updater, Renderer, JsIo, HttpRequest, HttpResponse, HttpResult, HttpError, HttpResponseHandler
+};
+pub use app::{with_progress, HttpProgress, HttpRequestHandle, RenderMode};
+#[cfg(feature = "serde")]
+pub use app::JsonError;
+pub use app::{WebSocket, WebSocketEvent, WebSocketFrame, WebSocketHandler};
+pub use cmd::Cmd;
+pub use self::private::{run, hydrate, run_persisted, set_batched_mutations, set_render_mode};
+pub use self::private::operation_counts;
+pub use persist::Persistence;
+pub use storage::{Storage, StorageArea, StorageError};
 
 mod private {
 
     extern crate libc;
 
-    use super::{Updater, Renderer};
-    use {DomNode, DomValue, Event, KeyValue, Listener};
+    use app::{Updater, Renderer, JsIo, HttpRequest, HttpResponse, HttpError, HttpResponseHandler};
+    use app::{HttpProgress, HttpRequestHandle, RenderMode};
+    use app::{WebSocket, WebSocketConnection, WebSocketEvent, WebSocketFrame, WebSocketHandler};
+    use cmd::{Cmd, Effect};
+    use {DomNode, Event, KeyValue, Listener};
+    use html_document::Head;
     use keys::Keys;
-    use processors::{DomNodes, Listeners, DomNodeProcessor, ListenerProcessor};
+    use persist::Persistence;
+    use sub::{GlobalEventKind, RunningSubscriptions, SubscriptionEvent, SubscriptionKind};
+    use super::WebStorage;
+    use vdom::{CountingBackend, DomBackend, ExistingValue, OpCounts, VDom};
 
     // This module as a whole is "use_std"-only, so these don't need to be cfg'd
+    use std::cell::{Cell, RefCell};
     use std::ffi::{CString, CStr};
     use std::marker::PhantomData;
-    use std::{mem, ptr, str};
+    use std::{mem, ptr, slice, str};
 
     /// Runs the application (`updater`, `renderer`, `initial_state`) on the webpage under the element
     /// specified by `element_selector`.
@@ -67,10 +41,67 @@ mod private {
         M: 'static,
         U: Updater<S, M>,
         R: Renderer<S, M, Rendered=D>
+    {
+        start(element_selector, updater, renderer, initial_state, false, None)
+    }
+
+    /// Runs the application like `run`, but takes over the existing contents of the element
+    /// specified by `element_selector` (such as markup rendered on the server using
+    /// `HtmlWriter`) instead of replacing them.
+    ///
+    /// The existing elements are reused and listeners are attached to them. Any differences
+    /// between the existing markup and the first render are corrected and logged to the console.
+    pub fn hydrate<D, M, U, R, S>(
+        element_selector: &str,
+        updater: U,
+        renderer: R,
+        initial_state: S) -> !
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>
+    {
+        start(element_selector, updater, renderer, initial_state, true, None)
+    }
+
+    /// Runs the application like `run`, but restores the state saved by `persistence` (starting
+    /// from `initial_state` if there is none) and saves the state once no updates have happened
+    /// for `persistence.debounce_millis()` milliseconds, or when the page is being unloaded.
+    pub fn run_persisted<D, M, U, R, S>(
+        element_selector: &str,
+        updater: U,
+        renderer: R,
+        initial_state: S,
+        persistence: Persistence<S>) -> !
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>
+    {
+        let state = persistence.restore(&WebStorage::new(persistence.storage_area()))
+            .unwrap_or(initial_state);
+        start(element_selector, updater, renderer, state, false, Some(persistence))
+    }
+
+    fn start<D, M, U, R, S>(
+        element_selector: &str,
+        updater: U,
+        renderer: R,
+        initial_state: S,
+        hydrate: bool,
+        persistence: Option<Persistence<S>>) -> !
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>
     {
         unsafe {
             // Get initial DomNode
             let rendered = renderer.render(&initial_state);
+            let head = renderer.head(&initial_state);
 
             // Initialize the browser system
             let document = web_init();
@@ -79,7 +110,12 @@ mod private {
                     .expect(&format!(
                         "Target element of `run` was not found: {}", element_selector));
 
-            root_node_element.remove_all_children();
+            if !hydrate {
+                root_node_element.remove_all_children();
+            }
+            if let Some(ref head) = head {
+                apply_head(None, head);
+            }
 
             // Lives forever on the stack, referenced and mutated in callbacks
             let mut app_system = (
@@ -87,34 +123,67 @@ mod private {
                 updater,
                 renderer,
                 initial_state,
-                VDomNode {
-                    value: VNodeValue::Tag("N/A - root"),
-                    keys: Keys::new(),
-                    web_element: root_node_element,
-                    attributes: Vec::new(),
-                    listeners: Vec::new(),
-                    children: Vec::new(),
-                }
+                VDom::new(root_node_element),
+                head,
+                RunningSubscriptions::new(),
+                persistence,
             );
-            let app_system_mut_ptr = (&mut app_system) as *mut (D, U, R, S, VDomNode<M>);
+            let app_system_mut_ptr = (&mut app_system) as *mut AppSystem<D, M, U, R, S>;
 
-            // Draw initial DomNode to browser
-            let mut node_index = 0;
-            let mut input = WebWriterAcc {
-                system_ptr: app_system_mut_ptr,
-                document: document,
-                keys: Keys::new(),
-                parent_element: &(*app_system_mut_ptr).4.web_element,
-                node_level: &mut (*app_system_mut_ptr).4.children,
-                node_index: &mut node_index,
-            };
+            if (*app_system_mut_ptr).7.is_some() {
+                // Saves the state now if a save is pending, which happens at the latest when the
+                // page is hidden or unloaded
+                const JS: &[u8] = b"\
+                    var handler_fn_ptr = $0;\
+                    var app_system = $1;\
+                    __domafic_persist = function() {\
+                        if (__domafic_persist_timer !== null) {\
+                            clearTimeout(__domafic_persist_timer);\
+                            __domafic_persist_timer = null;\
+                            Runtime.dynCall('vi', handler_fn_ptr, [app_system]);\
+                        }\
+                    };\
+                    window.addEventListener('pagehide', __domafic_persist, false);\
+                \0";
+                emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    handle_persist::<D, M, U, R, S> as *const libc::c_void,
+                    app_system_mut_ptr as *const libc::c_void,
+                );
+            }
 
-            (*app_system_mut_ptr).0.process_all::<WebWriter<D, M, U, R, S>>(&mut input).unwrap();
+            // Draw initial DomNode to browser
+            let mut backend =
+                CountingBackend::new(WebBackend::new::<D, U, R, S>(document, app_system_mut_ptr));
+            if hydrate {
+                let mismatches =
+                    (*app_system_mut_ptr).4.hydrate(&mut backend, &(*app_system_mut_ptr).0);
+                for mismatch in mismatches {
+                    console_warn(&format!("Hydration mismatch: {:?}", mismatch));
+                }
+            } else {
+                (*app_system_mut_ptr).4.update(&mut backend, &(*app_system_mut_ptr).0);
+            }
+            record_operations(backend.counts());
+            flush_mutations();
+            update_subscriptions(app_system_mut_ptr);
 
             run_main_web_loop()
         }
     }
 
+    /// The application state and rendering system shared between `run` and the JS callbacks
+    type AppSystem<D, M, U, R, S> = (
+        D,
+        U,
+        R,
+        S,
+        VDom<M, CountingBackend<WebBackend<M>>>,
+        Option<Head>,
+        RunningSubscriptions<M>,
+        Option<Persistence<S>>,
+    );
+
     struct JsIoImpl<D, M, U, R, S>
         where
         D: DomNode<M>,
@@ -122,69 +191,7 @@ mod private {
         U: Updater<S, M>,
         R: Renderer<S, M>
     {
-        app_system: *mut (D, U, R, S, VDomNode<M>)
-    }
-
-    /// A single HTTP request
-    #[derive(Debug, Copy, Clone)]
-    pub struct HttpRequest<'a> {
-        /// HTTP Method ("GET", "POST", etc.)
-        pub method: &'a str,
-        /// A list of HTTP header (key, value) pairs
-        pub headers: &'a [(&'a str, &'a str)],
-        /// Request URL
-        pub url: &'a str,
-        /// Request body
-        pub body: &'a str,
-        /// Optional request timeout in milliseconds
-        pub timeout_millis: Option<u32>,
-    }
-
-    /// HTTP request `Result` indicating a possible network error or timeout
-    pub type HttpResult<'a> = Result<HttpResponse<'a>, HttpError>;
-
-    /// A single HTTP response
-    #[derive(Debug, Copy, Clone)]
-    pub struct HttpResponse<'a> {
-        /// HTTP status
-        pub status_code: u16,
-        /// HTTP status text
-        pub status_text: &'a str,
-        /// A list of HTTP response header (key, value) pairs
-        pub headers: &'a [(&'a str, &'a str)],
-        /// The body of the HTTP response
-        pub body: &'a str,
-    }
-
-    /// HTTP request error indicating either a network connection error or a timeout
-    #[derive(Debug, Copy, Clone)]
-    pub enum HttpError {
-        NetworkError,
-        Timeout,
-    }
-
-    /// Handler for an HTTP response
-    pub trait HttpResponseHandler: 'static {
-        type Message;
-        fn handle<'a>(&self, HttpResult<'a>) -> Self::Message;
-    }
-    impl<F, Message> HttpResponseHandler for F
-        where F: for<'a> Fn(HttpResult<'a>) -> Message + 'static
-    {
-        type Message = Message;
-        fn handle<'a>(&self, response: HttpResult<'a>) -> Message {
-            (self)(response)
-        }
-    }
-
-    /// JavaScript IO interface
-    pub trait JsIo<Message> {
-        /// Issue an asynchronous HTTP request
-        fn http<'b> (
-            &self,
-            http_request: HttpRequest<'b>,
-            handler: Box<HttpResponseHandler<Message=Message>>,
-        );
+        app_system: *mut AppSystem<D, M, U, R, S>
     }
 
     impl<D, M, U, R, S> JsIo<M> for JsIoImpl<D, M, U, R, S>
@@ -198,9 +205,18 @@ mod private {
             &self,
             http_request: HttpRequest<'b>,
             handler: Box<HttpResponseHandler<Message=M>>,
-        ) {
+        ) -> HttpRequestHandle {
             JsIoImpl::http(self, http_request, handler)
         }
+
+        fn websocket(
+            &self,
+            url: &str,
+            protocols: &[&str],
+            handler: Box<dyn WebSocketHandler<Message=M>>,
+        ) -> WebSocket {
+            JsIoImpl::websocket(self, url, protocols, handler)
+        }
     }
 
     impl<D, M, U, R, S> JsIoImpl<D, M, U, R, S>
@@ -214,12 +230,11 @@ mod private {
             &self,
             http_request: HttpRequest<'b>,
             handler: Box<HttpResponseHandler<Message=M>>,
-        ) {
+        ) -> HttpRequestHandle {
             unsafe {
                 let HttpRequest { method, headers, url, body, timeout_millis } = http_request;
                 let method_cstring = CString::new(method).unwrap();
                 let url_cstring = CString::new(url).unwrap();
-                let body_cstring = CString::new(body).unwrap();
 
                 let header_key_cstrings: Vec<CString> =
                 headers.iter().map(|header| CString::new(header.0).unwrap()).collect();
@@ -235,31 +250,46 @@ mod private {
                 header_value_cstrings.iter().map(|cstring|
                     cstring.as_ptr() as libc::c_int).collect();
 
+                let progress_fn_ptr = if handler.reports_progress() {
+                    handle_http_progress::<D, M, U, R, S> as *const libc::c_void
+                } else {
+                    ptr::null()
+                };
+
                 let handler_ptr = Box::into_raw(handler);
 
                 let (handler_data_ptr, handler_vtable_ptr):
                     (*const libc::c_void, *const libc::c_void) =
                     mem::transmute(handler_ptr);
 
+                // Error signals: 1 timeout, 2 network error, 3 invalid request, 4 aborted.
+                // Errors raised synchronously are delivered later, once the current update
+                // has finished.
                 const JS: &'static [u8] = b"\
                     var handler_fn_ptr = $0;\
                     var app_system = $1;\
                     var method = UTF8ToString($2);\
                     var url = UTF8ToString($3);\
-                    var body = UTF8ToString($4);\
-                    var header_len = $5;\
-                    var header_key_ptr = $6;\
-                    var header_value_ptr = $7;\
-                    var timeout = $8;\
-                    var handler_data_ptr = $9;\
-                    var handler_vtable_ptr = $10;\
+                    var body_ptr = $4;\
+                    var body_len = $5;\
+                    var header_len = $6;\
+                    var header_key_ptr = $7;\
+                    var header_value_ptr = $8;\
+                    var timeout = $9;\
+                    var handler_data_ptr = $10;\
+                    var handler_vtable_ptr = $11;\
+                    var progress_fn_ptr = $12;\
+                    var id = __domafic_next_request++;\
                     var xhr = new XMLHttpRequest();\
                     var error_fn = function(error_sig) { return function() {\
-                        Runtime.dynCall('viiiiiiii', handler_fn_ptr, [error_sig, app_system, handler_data_ptr, handler_vtable_ptr, 0, 0, 0, 0]);\
+                        delete __domafic_requests[id];\
+                        Runtime.dynCall('viiiiiiiii', handler_fn_ptr, [error_sig, app_system, handler_data_ptr, handler_vtable_ptr, 0, 0, 0, 0, 0]);\
                     } };\
                     xhr.addEventListener('timeout', error_fn(1));\
                     xhr.addEventListener('error', error_fn(2));\
+                    xhr.addEventListener('abort', function() { setTimeout(error_fn(4), 0); });\
                     xhr.addEventListener('load', function() {\
+                        delete __domafic_requests[id];\
                         var stack = Runtime.stackSave();\
                         var status_code = xhr.status;\
                         var status_text = allocate(\
@@ -268,40 +298,239 @@ mod private {
                         var response_headers = allocate(\
                             intArrayFromString(xhr.getAllResponseHeaders()), 'i8', ALLOC_STACK\
                         );\
-                        var response_body =\
-                            allocate(intArrayFromString(xhr.responseText), 'i8', ALLOC_STACK);\
-                        Runtime.dynCall('viiiiiiii', handler_fn_ptr, [0, app_system, handler_data_ptr, handler_vtable_ptr, status_code, status_text, response_body, response_headers]);\
+                        var response_body = new Uint8Array(xhr.response || new ArrayBuffer(0));\
+                        var response_body_ptr = _malloc(Math.max(response_body.length, 1));\
+                        HEAPU8.set(response_body, response_body_ptr);\
+                        Runtime.dynCall('viiiiiiiii', handler_fn_ptr, [0, app_system, handler_data_ptr, handler_vtable_ptr, status_code, status_text, response_body_ptr, response_body.length, response_headers]);\
+                        _free(response_body_ptr);\
                         Runtime.stackRestore(stack);\
                     });\
-                    try { xhr.open(method, url, true); } catch (e) { error_fn(3); return; }\
-                    for (var i = 0; i < header_len; i++) {\
-                        var header_key = UTF8ToString(getValue(header_key_ptr + (i * 4), '*'));\
-                        var header_value = UTF8ToString(getValue(header_value_ptr + (i * 4), '*'));\
-                        xhr.setRequestHeader(header_key, header_value);\
+                    if (progress_fn_ptr) {\
+                        var progress_fn = function(upload) { return function(event) {\
+                            Runtime.dynCall('viiiidd', progress_fn_ptr, [app_system, handler_data_ptr, handler_vtable_ptr, upload, event.loaded, event.lengthComputable ? event.total : -1]);\
+                        } };\
+                        xhr.upload.addEventListener('progress', progress_fn(1));\
+                        xhr.addEventListener('progress', progress_fn(0));\
+                    }\
+                    try {\
+                        xhr.open(method, url, true);\
+                        for (var i = 0; i < header_len; i++) {\
+                            var header_key = UTF8ToString(getValue(header_key_ptr + (i * 4), '*'));\
+                            var header_value = UTF8ToString(getValue(header_value_ptr + (i * 4), '*'));\
+                            xhr.setRequestHeader(header_key, header_value);\
+                        }\
+                        xhr.responseType = 'arraybuffer';\
+                        if (timeout != 0) { xhr.timeout = timeout; }\
+                        xhr.send(body_len ? HEAPU8.slice(body_ptr, body_ptr + body_len) : null);\
+                    } catch (e) {\
+                        setTimeout(error_fn(3), 0);\
+                        return id;\
                     }\
-                    xhr.responseType = 'text';\
-                    if (timeout != 0) { xhr.timeout = timeout; }\
-                    xhr.send(body);\
+                    __domafic_requests[id] = xhr;\
+                    return id;\
                 \0";
 
-                emscripten_asm_const_int(
+                let id = emscripten_asm_const_int(
                     &JS[0] as *const _ as *const libc::c_char,
                     handle_http_result::<D, M, U, R, S> as *const libc::c_void,
                     self.app_system as *const libc::c_void,
-                   method_cstring.as_ptr() as libc::c_int,
+                    method_cstring.as_ptr() as libc::c_int,
                     url_cstring.as_ptr() as libc::c_int,
-                    body_cstring.as_ptr() as libc::c_int,
+                    body.as_ptr() as libc::c_int,
+                    body.len() as libc::c_int,
                     header_key_pointers.len() as libc::c_int,
                     header_key_pointers.as_ptr() as *const _ as *const libc::c_char,
                     header_value_pointers.as_ptr() as *const _ as *const libc::c_char,
                     timeout_millis.unwrap_or(0) as libc::c_int,
                     handler_data_ptr,
                     handler_vtable_ptr,
+                    progress_fn_ptr,
+                );
+
+                HttpRequestHandle::new(move || {
+                    const JS: &'static [u8] = b"\
+                        var xhr = __domafic_requests[$0];\
+                        if (xhr) { delete __domafic_requests[$0]; xhr.abort(); }\
+                    \0";
+                    unsafe {
+                        emscripten_asm_const_int(&JS[0] as *const _ as *const libc::c_char, id);
+                    }
+                })
+            }
+        }
+    }
+
+    impl<D, M, U, R, S> JsIoImpl<D, M, U, R, S>
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>
+    {
+        fn websocket(
+            &self,
+            url: &str,
+            protocols: &[&str],
+            handler: Box<dyn WebSocketHandler<Message=M>>,
+        ) -> WebSocket {
+            unsafe {
+                let url_cstring = CString::new(url).unwrap();
+                // Commas can't appear in subprotocol names
+                let protocols_cstring = CString::new(protocols.join(",")).unwrap();
+
+                let handler_ptr = Box::into_raw(handler);
+
+                let (handler_data_ptr, handler_vtable_ptr):
+                    (*const libc::c_void, *const libc::c_void) =
+                    mem::transmute(handler_ptr);
+
+                // Event kinds: 0 open, 1 text, 2 binary, 3 error, 4 close
+                const JS: &'static [u8] = b"\
+                    var handler_fn_ptr = $0;\
+                    var app_system = $1;\
+                    var url = UTF8ToString($2);\
+                    var protocols = UTF8ToString($3);\
+                    protocols = protocols ? protocols.split(',') : [];\
+                    var handler_data_ptr = $4;\
+                    var handler_vtable_ptr = $5;\
+                    var id = __domafic_next_socket++;\
+                    var dispatch = function(kind, a, b, str) {\
+                        str = str || '';\
+                        var str_len = lengthBytesUTF8(str);\
+                        var str_ptr = _malloc(str_len + 1);\
+                        stringToUTF8(str, str_ptr, str_len + 1);\
+                        Runtime.dynCall('viiiiiiii', handler_fn_ptr, [app_system, handler_data_ptr, handler_vtable_ptr, kind, a, b, str_ptr, str_len]);\
+                        _free(str_ptr);\
+                    };\
+                    var socket;\
+                    try { socket = new WebSocket(url, protocols); } catch (e) {\
+                        setTimeout(function() { dispatch(3, 0, 0); dispatch(4, 1006, 0); }, 0);\
+                        return id;\
+                    }\
+                    socket.binaryType = 'arraybuffer';\
+                    __domafic_sockets[id] = socket;\
+                    socket.onopen = function() { dispatch(0, 0, 0); };\
+                    socket.onmessage = function(event) {\
+                        if (typeof event.data === 'string') { dispatch(1, 0, 0, event.data); return; }\
+                        var bytes = new Uint8Array(event.data);\
+                        var data_ptr = _malloc(Math.max(bytes.length, 1));\
+                        HEAPU8.set(bytes, data_ptr);\
+                        dispatch(2, data_ptr, bytes.length);\
+                        _free(data_ptr);\
+                    };\
+                    socket.onerror = function() { dispatch(3, 0, 0); };\
+                    socket.onclose = function(event) {\
+                        delete __domafic_sockets[id];\
+                        dispatch(4, event.code, event.wasClean ? 1 : 0, event.reason);\
+                    };\
+                    return id;\
+                \0";
+
+                let id = emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    handle_websocket_event::<D, M, U, R, S> as *const libc::c_void,
+                    self.app_system as *const libc::c_void,
+                    url_cstring.as_ptr() as libc::c_int,
+                    protocols_cstring.as_ptr() as libc::c_int,
+                    handler_data_ptr,
+                    handler_vtable_ptr,
                 );
+                WebSocket::new(WebSocketImpl(id))
+            }
+        }
+    }
+
+    /// A WebSocket connection, identified by its index in `__domafic_sockets`
+    struct WebSocketImpl(libc::c_int);
+
+    impl WebSocketConnection for WebSocketImpl {
+        fn send(&self, frame: WebSocketFrame) {
+            const JS: &'static [u8] = b"\
+                var socket = __domafic_sockets[$0];\
+                if (!socket || socket.readyState !== 1) { return; }\
+                if ($1) {\
+                    socket.send(HEAPU8.slice($2, $2 + $3));\
+                } else {\
+                    socket.send(UTF8ToString($2));\
+                }\
+            \0";
+            unsafe {
+                match frame {
+                    WebSocketFrame::Text(text) => {
+                        let text_cstring = CString::new(text).unwrap();
+                        emscripten_asm_const_int(
+                            &JS[0] as *const _ as *const libc::c_char,
+                            self.0,
+                            0,
+                            text_cstring.as_ptr() as libc::c_int,
+                            0,
+                        );
+                    }
+                    WebSocketFrame::Binary(data) => {
+                        emscripten_asm_const_int(
+                            &JS[0] as *const _ as *const libc::c_char,
+                            self.0,
+                            1,
+                            data.as_ptr() as libc::c_int,
+                            data.len() as libc::c_int,
+                        );
+                    }
+                }
+            }
+        }
+
+        fn close(&self) {
+            const JS: &'static [u8] = b"\
+                var socket = __domafic_sockets[$0];\
+                if (socket) { socket.close(); }\
+            \0";
+            unsafe {
+                emscripten_asm_const_int(&JS[0] as *const _ as *const libc::c_char, self.0);
             }
         }
     }
 
+    unsafe extern fn handle_websocket_event<D, M, U, R, S>
+    (
+        system_c_ptr: *mut libc::c_void,
+        handler_data_ptr: *const libc::c_void,
+        handler_vtable_ptr: *const libc::c_void,
+        kind: libc::c_int,
+        a: libc::c_int,
+        b: libc::c_int,
+        str_ptr: *const u8,
+        str_len: libc::c_int,
+    )
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let handler_ptr: *mut dyn WebSocketHandler<Message=M> =
+            mem::transmute((handler_data_ptr, handler_vtable_ptr));
+
+        // Text frames and close reasons may contain NUL characters, so the length is explicit
+        let text = str::from_utf8(slice::from_raw_parts(str_ptr, str_len as usize)).unwrap();
+        let event = match kind {
+            0 => WebSocketEvent::Open,
+            1 => WebSocketEvent::Message(WebSocketFrame::Text(text)),
+            2 => WebSocketEvent::Message(
+                WebSocketFrame::Binary(slice::from_raw_parts(a as *const u8, b as usize))),
+            3 => WebSocketEvent::Error,
+            4 => WebSocketEvent::Close { code: a as u16, reason: text, was_clean: b != 0 },
+            _ => unreachable!(),
+        };
+        let message = (*handler_ptr).handle(event);
+
+        // No further events are delivered after the connection closes
+        if kind == 4 {
+            drop(Box::from_raw(handler_ptr));
+        }
+
+        update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
+    }
+
     unsafe extern fn handle_http_result<D, M, U, R, S>
     (
         error_sig: libc::c_int,
@@ -310,7 +539,8 @@ mod private {
         handler_vtable_ptr: *const libc::c_void,
         status_code: u16,
         status_text: *const libc::c_char,
-        body: *const libc::c_char,
+        body: *const u8,
+        body_len: libc::c_int,
         headers_ptr: *const libc::c_char
     )
         where
@@ -323,8 +553,6 @@ mod private {
             mem::transmute((handler_data_ptr, handler_vtable_ptr));
         let handler = Box::from_raw(handler_ptr);
 
-        let status_text = str::from_utf8(CStr::from_ptr(status_text).to_bytes()).unwrap();
-
         let headers;
         let response_result = match error_sig {
             0 => {
@@ -338,9 +566,9 @@ mod private {
 
                 Ok(HttpResponse {
                     status_code: status_code,
-                    status_text: status_text,
+                    status_text: str::from_utf8(CStr::from_ptr(status_text).to_bytes()).unwrap(),
                     headers: &headers,
-                    body: str::from_utf8(CStr::from_ptr(body).to_bytes()).unwrap(),
+                    body: slice::from_raw_parts(body, body_len as usize),
                 })
             },
 
@@ -348,6 +576,10 @@ mod private {
 
             2 => Err(HttpError::NetworkError),
 
+            3 => Err(HttpError::InvalidRequest),
+
+            4 => Err(HttpError::Aborted),
+
             _ => unreachable!(),
         };
 
@@ -356,6 +588,35 @@ mod private {
         update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
     }
 
+    unsafe extern fn handle_http_progress<D, M, U, R, S>
+    (
+        system_c_ptr: *mut libc::c_void,
+        handler_data_ptr: *const libc::c_void,
+        handler_vtable_ptr: *const libc::c_void,
+        upload: libc::c_int,
+        loaded: f64,
+        total: f64,
+    )
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        // The handler stays owned by the request until `handle_http_result`
+        let handler_ptr: *const dyn HttpResponseHandler<Message=M> =
+            mem::transmute((handler_data_ptr, handler_vtable_ptr));
+
+        let progress = HttpProgress {
+            upload: upload != 0,
+            loaded,
+            total: if total < 0.0 { None } else { Some(total) },
+        };
+        if let Some(message) = (*handler_ptr).progress(progress) {
+            update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
+        }
+    }
+
     extern "C" {
         pub fn emscripten_asm_const_int(s: *const libc::c_char, ...) -> libc::c_int;
         fn emscripten_pause_main_loop();
@@ -371,11 +632,51 @@ mod private {
     struct WebDocument(()); // Contains private () so that it can't be created externally
 
     fn web_init() -> WebDocument {
+        // `__domafic_make_listener` creates the callbacks of event listeners. Plain left clicks
+        // on `router::link`s are handled by the application instead of the browser.
+        // `set_attribute` assigns properties, so check for both the property and the attribute
+        // (from server-rendered markup)
         const JS: &'static [u8] = b"\
             if('undefined'===typeof __domafic_pool){\
                 console.log('Intializing __domafic_pool');\
                 __domafic_pool=[];\
                 __domafic_pool_free=[];\
+                __domafic_subscriptions={};\
+                __domafic_sockets={};\
+                __domafic_next_socket=0;\
+                __domafic_requests={};\
+                __domafic_next_request=0;\
+                __domafic_persist=null;\
+                __domafic_persist_timer=null;\
+                __domafic_render_frame=null;\
+                __domafic_make_listener=function(handler, data, vtable, system, keys) {\
+                    return function(event) {\
+                        event = event || window.event;\
+                        var link = event.currentTarget;\
+                        if (event.type == 'click' && link && (link['data-domafic-link'] ||\
+                            (link.hasAttribute && link.hasAttribute('data-domafic-link')))) {\
+                            if (event.button || event.shiftKey || event.altKey ||\
+                                event.ctrlKey || event.metaKey) { return; }\
+                            event.preventDefault();\
+                        }\
+                        var stack = Runtime.stackSave();\
+                        var typeStr = event.type ? allocate(intArrayFromString(event.type), 'i8', ALLOC_STACK) : 0;\
+                        var targetValue = (event.target && event.target.value) ? allocate(intArrayFromString(event.target.value), 'i8', ALLOC_STACK) : 0;\
+                        Runtime.dynCall('viiiiiiiiiiiiiii', handler, [data, vtable, system,\
+                        typeStr,\
+                        targetValue,\
+                        Math.floor(event.clientX || 0), Math.floor(event.clientY || 0),\
+                        Math.floor(event.offsetX || 0), Math.floor(event.offsetY || 0),\
+                        event.which || event.keyCode || 0,\
+                        event.shiftKey ? 1 : 0,\
+                        event.altKey ? 1 : 0,\
+                        event.ctrlKey ? 1 : 0,\
+                        event.metaKey ? 1 : 0,\
+                        keys,\
+                        ]);\
+                        Runtime.stackRestore(stack);\
+                    };\
+                };\
             }\
         \0";
 
@@ -386,6 +687,12 @@ mod private {
         WebDocument(())
     }
 
+    /// Creates the pool used to pass values between Rust and JavaScript if it doesn't exist yet,
+    /// for functions which may be called before `run`
+    pub fn init_pool() {
+        web_init();
+    }
+
     extern fn pause_main_web_loop() {
         unsafe { emscripten_pause_main_loop(); }
     }
@@ -395,6 +702,18 @@ mod private {
         panic!("Emscripten main loop should never return")
     }
 
+    /// Logs a warning to the browser console
+    fn console_warn(message: &str) {
+        const JS: &'static [u8] = b"console.warn(UTF8ToString($0));\0";
+        let message_cstring = CString::new(message).unwrap_or_default();
+        unsafe {
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                message_cstring.as_ptr() as libc::c_int,
+            );
+        }
+    }
+
     impl WebDocument {
         fn element_from_selector(&self, selector: &str) -> Option<WebElement> {
             let id = {
@@ -442,11 +761,9 @@ mod private {
                     const JS: &'static [u8] = b"\
                         var text = document.createTextNode(UTF8ToString($0));\
                         if (!text) {return -1;}\
-                        var elem = document.createElement('span');\
-                        elem.appendChild(text);\
                         var index = __domafic_pool_free.pop();\
-                        if (index) { __domafic_pool[index] = elem; return index; }\
-                        return __domafic_pool.push(elem) - 1;\
+                        if (index) { __domafic_pool[index] = text; return index; }\
+                        return __domafic_pool.push(text) - 1;\
                     \0";
                     let text_cstring = CString::new(text).unwrap();
                     emscripten_asm_const_int(
@@ -476,39 +793,7 @@ mod private {
         ctrl_key: libc::c_int,
         meta_key: libc::c_int,
 
-        keys_size: libc::c_uint,
-        key_1: libc::c_uint,
-        key_2: libc::c_uint,
-        key_3: libc::c_uint,
-        key_4: libc::c_uint,
-        key_5: libc::c_uint,
-        key_6: libc::c_uint,
-        key_7: libc::c_uint,
-        key_8: libc::c_uint,
-        key_9: libc::c_uint,
-        key_10: libc::c_uint,
-        key_11: libc::c_uint,
-        key_12: libc::c_uint,
-        key_13: libc::c_uint,
-        key_14: libc::c_uint,
-        key_15: libc::c_uint,
-        key_16: libc::c_uint,
-        key_17: libc::c_uint,
-        key_18: libc::c_uint,
-        key_19: libc::c_uint,
-        key_20: libc::c_uint,
-        key_21: libc::c_uint,
-        key_22: libc::c_uint,
-        key_23: libc::c_uint,
-        key_24: libc::c_uint,
-        key_25: libc::c_uint,
-        key_26: libc::c_uint,
-        key_27: libc::c_uint,
-        key_28: libc::c_uint,
-        key_29: libc::c_uint,
-        key_30: libc::c_uint,
-        key_31: libc::c_uint,
-        key_32: libc::c_uint,
+        keys_ptr: *const Keys,
     )
         where
         (D, U, R, S): Sized,
@@ -545,46 +830,11 @@ mod private {
             meta_key: meta_key == 1,
         };
 
-        let keys = Keys {
-            size: keys_size,
-            stack: [
-                key_1,
-                key_2,
-                key_3,
-                key_4,
-                key_5,
-                key_6,
-                key_7,
-                key_8,
-                key_9,
-                key_10,
-                key_11,
-                key_12,
-                key_13,
-                key_14,
-                key_15,
-                key_16,
-                key_17,
-                key_18,
-                key_19,
-                key_20,
-                key_21,
-                key_22,
-                key_23,
-                key_24,
-                key_25,
-                key_26,
-                key_27,
-                key_28,
-                key_29,
-                key_30,
-                key_31,
-                key_32,
-            ]
-        };
+        // Owned by the `WebListener` of this callback, which outlives it
+        let keys = (*keys_ptr).clone();
 
         let message = listener_ref.handle_event(event);
-        update_system(system_c_ptr, message, keys);
+        update_system::<D, M, U, R, S>(system_c_ptr, message, keys);
     }
 
     unsafe fn update_system<D, M, U, R, S>
@@ -601,19 +851,74 @@ mod private {
         R: Renderer<S, M, Rendered=D>,
     {
 
-        let system_ptr: *mut (D, U, R, S, VDomNode<M>) = mem::transmute(system_c_ptr);
-        let system_ref: &mut (D, U, R, S, VDomNode<M>) = system_ptr.as_mut().unwrap();
+        let system_ptr: *mut AppSystem<D, M, U, R, S> = mem::transmute(system_c_ptr);
+        let system_ref: &mut AppSystem<D, M, U, R, S> = system_ptr.as_mut().unwrap();
+
+        let (_, ref mut updater, _, ref mut state, _, _, _, ref persistence) = *system_ref;
 
+        // Update state
+        let cmd =
+            updater.update(state, message, keys.into_iter(), &JsIoImpl { app_system: system_ptr });
+
+        match render_mode() {
+            RenderMode::Sync => render_system(system_ptr),
+            RenderMode::AnimationFrame => {
+                // Messages arriving before the frame update the state without requesting
+                // another render
+                const JS: &[u8] = b"\
+                    if (__domafic_render_frame === null) {\
+                        var handler_fn_ptr = $0;\
+                        var app_system = $1;\
+                        __domafic_render_frame = requestAnimationFrame(function() {\
+                            __domafic_render_frame = null;\
+                            Runtime.dynCall('vi', handler_fn_ptr, [app_system]);\
+                        });\
+                    }\
+                \0";
+                emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    handle_render_frame::<D, M, U, R, S> as *const libc::c_void,
+                    system_ptr as *const libc::c_void,
+                );
+            }
+        }
+
+        if let Some(ref persistence) = *persistence {
+            // Restart the countdown to saving the state, so that bursts of updates save once
+            const JS: &[u8] = b"\
+                if (__domafic_persist_timer !== null) { clearTimeout(__domafic_persist_timer); }\
+                __domafic_persist_timer = setTimeout(function() {\
+                    __domafic_persist();\
+                }, $0);\
+            \0";
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                persistence.debounce_millis() as libc::c_int,
+            );
+        }
+
+        update_subscriptions(system_ptr);
+        perform(system_ptr, cmd);
+    }
+
+    /// Renders the current state and brings the page up to date
+    unsafe fn render_system<D, M, U, R, S>(system_ptr: *mut AppSystem<D, M, U, R, S>)
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
         let (
             ref mut rendered,
-            ref mut updater,
+            _,
             ref mut renderer,
             ref mut state,
-            ref mut vdom_root,
-        ) = *system_ref;
-
-        // Update state
-        updater.update(state, message, keys.into_iter(), &JsIoImpl { app_system: system_ptr });
+            ref mut vdom,
+            ref mut head,
+            _,
+            _,
+        ) = *system_ptr.as_mut().unwrap();
 
         // Render new DomNode
         // TODO: fix unsafety due to possible `panic` in `render`
@@ -621,17 +926,431 @@ mod private {
         ptr::write(rendered, renderer.render(state));
 
         // Write new DomNode to root element
-        {
-            let mut node_index = 0;
-            let mut input = WebWriterAcc {
-                system_ptr: system_ptr,
-                document: WebDocument(()),
-                keys: Keys::new(),
-                parent_element: &vdom_root.web_element,
-                node_level: &mut vdom_root.children,
-                node_index: &mut node_index,
+        let mut backend =
+            CountingBackend::new(WebBackend::new::<D, U, R, S>(WebDocument(()), system_ptr));
+        vdom.update(&mut backend, rendered);
+        record_operations(backend.counts());
+
+        if let Some(new_head) = renderer.head(state) {
+            if head.as_ref() != Some(&new_head) {
+                apply_head(head.as_ref(), &new_head);
+                *head = Some(new_head);
+            }
+        }
+
+        // Apply all changes to the page with a single call into Javascript, before any effects
+        // which might inspect the page
+        flush_mutations();
+    }
+
+    unsafe extern fn handle_render_frame<D, M, U, R, S>(system_c_ptr: *mut libc::c_void)
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        render_system(system_c_ptr as *mut AppSystem<D, M, U, R, S>);
+    }
+
+    unsafe extern fn handle_persist<D, M, U, R, S>(system_c_ptr: *mut libc::c_void)
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let system_ref = (system_c_ptr as *mut AppSystem<D, M, U, R, S>).as_mut().unwrap();
+        if let Some(ref persistence) = system_ref.7 {
+            let mut storage = WebStorage::new(persistence.storage_area());
+            if let Err(error) = persistence.save(&system_ref.3, &mut storage) {
+                match persistence.save_error_handler() {
+                    Some(handler) => handler(&error),
+                    None => console_warn(
+                        &format!("Failed to save the application state: {:?}", error)),
+                }
+            }
+        }
+    }
+
+    /// Starts and stops timers and animation frame loops to match the application's subscriptions
+    unsafe fn update_subscriptions<D, M, U, R, S>(system_ptr: *mut AppSystem<D, M, U, R, S>)
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let system_ref = system_ptr.as_mut().unwrap();
+        let sub = system_ref.1.subscriptions(&system_ref.3);
+        let running = &mut system_ref.6;
+        let changes = running.update(sub);
+
+        for id in changes.stopped {
+            const JS: &'static [u8] = b"\
+                var subscription = __domafic_subscriptions[$0];\
+                if (subscription.kind == 0) { clearInterval(subscription.handle); }\
+                else if (subscription.kind == 1) { clearTimeout(subscription.handle); }\
+                else if (subscription.kind == 2) { cancelAnimationFrame(subscription.handle); }\
+                else {\
+                    for (var i = 0; i < subscription.names.length; i++) {\
+                        subscription.target.removeEventListener(\
+                            subscription.names[i], subscription.handle, false);\
+                    }\
+                }\
+                delete __domafic_subscriptions[$0];\
+            \0";
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                id as libc::c_int,
+            );
+        }
+
+        for id in changes.started {
+            const TIMER_JS: &'static [u8] = b"\
+                var handler_fn_ptr = $0;\
+                var app_system = $1;\
+                var id = $2;\
+                var kind = $3;\
+                var millis = $4;\
+                var fire = function(time) {\
+                    Runtime.dynCall('viid', handler_fn_ptr, [app_system, id, time]);\
+                };\
+                var subscription = { kind: kind };\
+                __domafic_subscriptions[id] = subscription;\
+                if (kind == 0) {\
+                    subscription.handle = setInterval(function() { fire(performance.now()); }, millis);\
+                } else if (kind == 1) {\
+                    subscription.handle = setTimeout(function() { fire(performance.now()); }, millis);\
+                } else {\
+                    var frame = function(time) {\
+                        fire(time);\
+                        if (__domafic_subscriptions[id] === subscription) {\
+                            subscription.handle = requestAnimationFrame(frame);\
+                        }\
+                    };\
+                    subscription.handle = requestAnimationFrame(frame);\
+                }\
+            \0";
+            // Event kinds: 0 = resize, 1 = scroll, 2 = popstate, 3 = online/offline,
+            // 4 = visibilitychange, 5 = keydown
+            const EVENT_JS: &'static [u8] = b"\
+                var handler_fn_ptr = $0;\
+                var app_system = $1;\
+                var id = $2;\
+                var event_kind = $3;\
+                var names = [['resize'], ['scroll'], ['popstate'], ['online', 'offline'],\
+                    ['visibilitychange'], ['keydown']][event_kind];\
+                var target = (event_kind == 4 || event_kind == 5) ? document : window;\
+                var callback = function(event) {\
+                    var stack = Runtime.stackSave();\
+                    var a = 0, b = 0, str = 0;\
+                    if (event_kind == 0) { a = window.innerWidth; b = window.innerHeight; }\
+                    else if (event_kind == 1) {\
+                        a = Math.floor(window.pageXOffset); b = Math.floor(window.pageYOffset);\
+                    }\
+                    else if (event_kind == 2) {\
+                        var url = location.pathname + location.search + location.hash;\
+                        str = allocate(intArrayFromString(url), 'i8', ALLOC_STACK);\
+                    }\
+                    else if (event_kind == 3) { a = navigator.onLine ? 1 : 0; }\
+                    else if (event_kind == 4) { a = document.hidden ? 0 : 1; }\
+                    else {\
+                        str = allocate(intArrayFromString(event.type), 'i8', ALLOC_STACK);\
+                        a = event.which || event.keyCode || 0;\
+                    }\
+                    Runtime.dynCall('viiiiiiiiiii', handler_fn_ptr, [app_system, id, event_kind,\
+                        a, b, str,\
+                        event.shiftKey ? 1 : 0,\
+                        event.altKey ? 1 : 0,\
+                        event.ctrlKey ? 1 : 0,\
+                        event.metaKey ? 1 : 0,\
+                    ]);\
+                    Runtime.stackRestore(stack);\
+                };\
+                for (var i = 0; i < names.length; i++) {\
+                    target.addEventListener(names[i], callback, false);\
+                }\
+                __domafic_subscriptions[id] =\
+                    { kind: 3, target: target, names: names, handle: callback };\
+            \0";
+            let timer_handler = handle_subscription::<D, M, U, R, S> as *const libc::c_void;
+            let event_handler = handle_global_event::<D, M, U, R, S> as *const libc::c_void;
+            let (js, handler_fn_ptr, kind, millis) = match running.get(id).unwrap().kind() {
+                SubscriptionKind::Interval(millis) => (TIMER_JS, timer_handler, 0, millis),
+                SubscriptionKind::Timeout(millis) => (TIMER_JS, timer_handler, 1, millis),
+                SubscriptionKind::AnimationFrame => (TIMER_JS, timer_handler, 2, 0),
+                SubscriptionKind::Event(event_kind) => (EVENT_JS, event_handler, match event_kind {
+                    GlobalEventKind::Resize => 0,
+                    GlobalEventKind::Scroll => 1,
+                    GlobalEventKind::PopState => 2,
+                    GlobalEventKind::Online => 3,
+                    GlobalEventKind::VisibilityChange => 4,
+                    GlobalEventKind::KeyDown => 5,
+                }, 0),
             };
-            rendered.process_all::<WebWriter<D, M, U, R, S>>(&mut input).unwrap();
+            emscripten_asm_const_int(
+                &js[0] as *const _ as *const libc::c_char,
+                handler_fn_ptr,
+                system_ptr as *const libc::c_void,
+                id as libc::c_int,
+                kind as libc::c_int,
+                millis as libc::c_int,
+            );
+        }
+    }
+
+    unsafe extern fn handle_subscription<D, M, U, R, S>
+    (
+        system_c_ptr: *mut libc::c_void,
+        id: libc::c_int,
+        time: f64,
+    )
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let system_ptr = system_c_ptr as *mut AppSystem<D, M, U, R, S>;
+        let message = match (*system_ptr).6.get(id as u32) {
+            Some(subscription) => subscription.handle(SubscriptionEvent::Time(time)),
+            None => return,
+        };
+        update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
+    }
+
+    unsafe extern fn handle_global_event<D, M, U, R, S>
+    (
+        system_c_ptr: *mut libc::c_void,
+        id: libc::c_int,
+        event_kind: libc::c_int,
+        a: libc::c_int,
+        b: libc::c_int,
+        str_ptr: *const libc::c_char,
+        shift_key: libc::c_int,
+        alt_key: libc::c_int,
+        ctrl_key: libc::c_int,
+        meta_key: libc::c_int,
+    )
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let string = if (str_ptr as usize) != 0 {
+            str::from_utf8(CStr::from_ptr(str_ptr).to_bytes()).ok()
+        } else {
+            None
+        };
+        let event = match event_kind {
+            0 => SubscriptionEvent::Resize { width: a as i32, height: b as i32 },
+            1 => SubscriptionEvent::Scroll { x: a as i32, y: b as i32 },
+            2 => SubscriptionEvent::PopState { url: string.unwrap_or("") },
+            3 => SubscriptionEvent::Online(a == 1),
+            4 => SubscriptionEvent::VisibilityChange(a == 1),
+            _ => SubscriptionEvent::KeyDown(Event {
+                type_str: string,
+                which_keycode: a as i32,
+                shift_key: shift_key == 1,
+                alt_key: alt_key == 1,
+                ctrl_key: ctrl_key == 1,
+                meta_key: meta_key == 1,
+                ..Event::default()
+            }),
+        };
+
+        let system_ptr = system_c_ptr as *mut AppSystem<D, M, U, R, S>;
+        let message = match (*system_ptr).6.get(id as u32) {
+            Some(subscription) => subscription.handle(event),
+            None => return,
+        };
+        update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
+    }
+
+    /// Performs the effects of a `Cmd` returned by the updater
+    unsafe fn perform<D, M, U, R, S>(system_ptr: *mut AppSystem<D, M, U, R, S>, cmd: Cmd<M>)
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let js_io = JsIoImpl { app_system: system_ptr };
+        // Messages produced by effects are passed to the updater once all effects are performed
+        let mut messages = Vec::new();
+        for effect in cmd.into_effects() {
+            match effect {
+                Effect::Http { request, handler } => {
+                    request.with_borrowed(|request| js_io.http(request, handler));
+                }
+                Effect::Delay { millis, message } => {
+                    const JS: &'static [u8] = b"\
+                        var handler_fn_ptr = $0;\
+                        var app_system = $1;\
+                        var message_ptr = $2;\
+                        setTimeout(function() {\
+                            Runtime.dynCall('vii', handler_fn_ptr, [app_system, message_ptr]);\
+                        }, $3);\
+                    \0";
+                    emscripten_asm_const_int(
+                        &JS[0] as *const _ as *const libc::c_char,
+                        handle_delayed_message::<D, M, U, R, S> as *const libc::c_void,
+                        system_ptr as *const libc::c_void,
+                        Box::into_raw(Box::new(message)) as *const libc::c_void,
+                        millis as libc::c_int,
+                    );
+                }
+                Effect::Storage { area, operation } => {
+                    messages.extend(operation.perform(&mut WebStorage::new(area)));
+                }
+                Effect::Navigate { url, replace } => {
+                    // `pushState` and `replaceState` don't fire `popstate` themselves,
+                    // so dispatch one to notify route subscriptions of the new URL
+                    const JS: &'static [u8] = b"\
+                        var url = UTF8ToString($0);\
+                        if ($1) {\
+                            history.replaceState(null, '', url);\
+                        } else {\
+                            history.pushState(null, '', url);\
+                        }\
+                        window.dispatchEvent(new PopStateEvent('popstate', { state: null }));\
+                    \0";
+                    let url_cstring = CString::new(url).unwrap();
+                    emscripten_asm_const_int(
+                        &JS[0] as *const _ as *const libc::c_char,
+                        url_cstring.as_ptr() as libc::c_int,
+                        replace as libc::c_int,
+                    );
+                }
+                Effect::Custom(f) => f(&js_io),
+            }
+        }
+        for message in messages {
+            update_system::<D, M, U, R, S>(system_ptr as *mut libc::c_void, message, Keys::new());
+        }
+    }
+
+    unsafe extern fn handle_delayed_message<D, M, U, R, S>
+    (
+        system_c_ptr: *mut libc::c_void,
+        message_ptr: *mut M,
+    )
+        where
+        D: DomNode<M>,
+        M: 'static,
+        U: Updater<S, M>,
+        R: Renderer<S, M, Rendered=D>,
+    {
+        let message = *Box::from_raw(message_ptr);
+        update_system::<D, M, U, R, S>(system_c_ptr, message, Keys::new());
+    }
+
+    /// Updates the page's metadata to match `new`, given that `old` was the last `Head` applied.
+    fn apply_head(old: Option<&Head>, new: &Head) {
+        let empty = Head::new();
+        let old = old.unwrap_or(&empty);
+
+        if let Some(ref title) = new.title {
+            if old.title.as_ref() != Some(title) {
+                ::web_render::set_title(title);
+            }
+        }
+        if let Some(ref lang) = new.lang {
+            if old.lang.as_ref() != Some(lang) {
+                unsafe {
+                    const JS: &'static [u8] = b"\
+                        document.documentElement.lang = UTF8ToString($0);\
+                    \0";
+                    let lang_cstring = CString::new(lang.as_str()).unwrap();
+                    emscripten_asm_const_int(
+                        &JS[0] as *const _ as *const libc::c_char,
+                        lang_cstring.as_ptr() as libc::c_int
+                    );
+                }
+            }
+        }
+
+        for &(ref name, ref content) in &old.meta {
+            if !new.meta.iter().any(|&(ref new_name, _)| new_name == name) {
+                set_head_element("meta", "name", name, Some(("content", content)), true);
+            }
+        }
+        for meta in &new.meta {
+            if !old.meta.contains(meta) {
+                set_head_element("meta", "name", &meta.0, Some(("content", &meta.1)), false);
+            }
+        }
+
+        for href in &old.stylesheets {
+            if !new.stylesheets.contains(href) {
+                set_head_element("link", "href", href, Some(("rel", "stylesheet")), true);
+            }
+        }
+        for href in &new.stylesheets {
+            if !old.stylesheets.contains(href) {
+                set_head_element("link", "href", href, Some(("rel", "stylesheet")), false);
+            }
+        }
+
+        // Scripts can't be unloaded, so they are only ever added
+        for src in &new.scripts {
+            if !old.scripts.contains(src) {
+                set_head_element("script", "src", src, None, false);
+            }
+        }
+    }
+
+    /// Finds the `tag` element in the document's head whose `key_attr` is `key`, creating it if
+    /// it doesn't exist, and sets its `value` attribute. If `remove` is true, the element is
+    /// removed instead.
+    fn set_head_element(
+        tag: &str,
+        key_attr: &str,
+        key: &str,
+        value: Option<(&str, &str)>,
+        remove: bool)
+    {
+        unsafe {
+            const JS: &'static [u8] = b"\
+                var tag = UTF8ToString($0);\
+                var key_attr = UTF8ToString($1);\
+                var key = UTF8ToString($2);\
+                var element = Array.prototype.filter.call(\
+                    document.head.getElementsByTagName(tag),\
+                    function(e) { return e.getAttribute(key_attr) == key; }\
+                )[0];\
+                if ($5) {\
+                    if (element) { element.parentNode.removeChild(element); }\
+                } else {\
+                    if (!element) {\
+                        element = document.createElement(tag);\
+                        element.setAttribute(key_attr, key);\
+                        document.head.appendChild(element);\
+                    }\
+                    if ($3) { element.setAttribute(UTF8ToString($3), UTF8ToString($4)); }\
+                }\
+            \0";
+            let tag_cstring = CString::new(tag).unwrap();
+            let key_attr_cstring = CString::new(key_attr).unwrap();
+            let key_cstring = CString::new(key).unwrap();
+            let (value_attr_cstring, value_cstring) = match value {
+                Some((attr, value)) =>
+                    (Some(CString::new(attr).unwrap()), Some(CString::new(value).unwrap())),
+                None => (None, None),
+            };
+            let as_int = |cstring: &Option<CString>|
+                cstring.as_ref().map(|cstring| cstring.as_ptr() as libc::c_int).unwrap_or(0);
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                tag_cstring.as_ptr() as libc::c_int,
+                key_attr_cstring.as_ptr() as libc::c_int,
+                key_cstring.as_ptr() as libc::c_int,
+                as_int(&value_attr_cstring),
+                as_int(&value_cstring),
+                remove as libc::c_int
+            );
         }
     }
 
@@ -656,11 +1375,11 @@ mod private {
             let err = unsafe {
                 const JS: &'static [u8] = b"\
                     var parent = __domafic_pool[$0];\
-                    if ($2 > parent.children.length) { return -1; }\
-                    if ($2 == parent.children.length) {\
+                    if ($2 > parent.childNodes.length) { return -1; }\
+                    if ($2 == parent.childNodes.length) {\
                         parent.appendChild(__domafic_pool[$1]);\
                     } else {\
-                        parent.insertBefore(__domafic_pool[$1], parent.children[$2]);\
+                        parent.insertBefore(__domafic_pool[$1], parent.childNodes[$2]);\
                     }\
                     return 0;\
                 \0";
@@ -682,13 +1401,14 @@ mod private {
             let err = unsafe {
                 const JS: &'static [u8] = b"\
                     var parent = __domafic_pool[$0];\
-                    if ($1 > parent.children.length) { return -1; }\
-                    if ($2 > parent.children.length) { return -1; }\
-                    var element = parent.children[$1];\
-                    if ($2 == parent.children.length) {\
+                    if ($1 >= parent.childNodes.length) { return -1; }\
+                    if ($2 >= parent.childNodes.length) { return -1; }\
+                    var element = parent.childNodes[$1];\
+                    parent.removeChild(element);\
+                    if ($2 == parent.childNodes.length) {\
                         parent.appendChild(element);\
                     } else {\
-                        parent.insertBefore(element, parent.children[$2]);\
+                        parent.insertBefore(element, parent.childNodes[$2]);\
                     }\
                     return 0;\
                 \0";
@@ -706,46 +1426,25 @@ mod private {
             if err < 0 { panic!("Attempted to move child DOM element out of bounds") }
         }
 
-        /// Requires that `listener_ptr` and `system_ptr` are valid and that
+        /// Requires that `listener_ptr`, `system_ptr` and `keys_ptr` are valid and that
         /// `root_node_id` is a valid `WebElement` id throughout the duration of
         /// time that it is possible for this callback to be triggered.
+        /// `handler_fn_ptr` must point to the `handle_listener` instance matching `system_ptr`.
         /// Returns an element that is a reference to the created function
-        unsafe fn set_listener<D, M, U, R, S>(
+        unsafe fn set_listener<M>(
             &self,
             event_name: &str,
             listener_ptr: *const Listener<M>,
-            system_ptr: *mut (D, U, R, S, VDomNode<M>),
-            keys: Keys,
+            handler_fn_ptr: *const libc::c_void,
+            system_ptr: *mut libc::c_void,
+            keys_ptr: *const Keys,
         ) -> WebElement
-            where
-            (D, U, R, S): Sized, // Make sure *mut (D, U, R, S) is a thin ptr
-            D: DomNode<M>,
-            M: 'static,
-            U: Updater<S, M>,
-            R: Renderer<S, M, Rendered=D>
         {
             unsafe {
-                const JS: &'static [u8] = b"\
-                    var callback = function(event) {\
-                        var stack = Runtime.stackSave();\
-                        event = event || window.event;\
-                        var typeStr = event.type ? allocate(intArrayFromString(event.type), 'i8', ALLOC_STACK) : 0;\
-                        var targetValue = (event.target && event.target.value) ? allocate(intArrayFromString(event.target.value), 'i8', ALLOC_STACK) : 0;\
-                        Runtime.dynCall('viiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii', $2, [$3, $4, $5,\
-                        typeStr,\
-                        targetValue,\
-                        Math.floor(event.clientX || 0), Math.floor(event.clientY || 0),\
-                        Math.floor(event.offsetX || 0), Math.floor(event.offsetY || 0),\
-                        event.which || event.keyCode || 0,\
-                        event.shiftKey ? 1 : 0,\
-                        event.altKey ? 1 : 0,\
-                        event.ctrlKey ? 1 : 0,\
-                        event.metaKey ? 1 : 0,\
-                        $6, $7,\
-                        $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38,\
-                        ]);\
-                        Runtime.stackRestore(stack);\
-                    };\
+                // The callback is created by `__domafic_make_listener` (see `web_init`), which
+                // is shared with listeners added through the mutation buffer
+                const JS: &'static [u8] = b"\
+                    var callback = __domafic_make_listener($2, $3, $4, $5, $6);\
                     __domafic_pool[$0].addEventListener(\
                         UTF8ToString($1),\
                         callback,\
@@ -757,7 +1456,6 @@ mod private {
                 \0";
 
                 let event_name_cstring = CString::new(event_name).unwrap();
-                let Keys { size: k_size, stack: k } = keys;
                 let (listener_data_c_ptr, listener_vtable_c_ptr):
                     (*const libc::c_void, *const libc::c_void) =
                     mem::transmute(listener_ptr);
@@ -766,43 +1464,11 @@ mod private {
                     &JS[0] as *const _ as *const libc::c_char,
                     self.0,
                     event_name_cstring.as_ptr() as libc::c_int,
-                    handle_listener::<D, M, U, R, S> as *const libc::c_void,
+                    handler_fn_ptr,
                     listener_data_c_ptr,
                     listener_vtable_c_ptr,
                     system_ptr as *const libc::c_void,
-                    k_size,
-                    k[0],
-                    k[1],
-                    k[2],
-                    k[3],
-                    k[4],
-                    k[5],
-                    k[6],
-                    k[7],
-                    k[8],
-                    k[9],
-                    k[10],
-                    k[11],
-                    k[12],
-                    k[13],
-                    k[14],
-                    k[15],
-                    k[16],
-                    k[17],
-                    k[18],
-                    k[19],
-                    k[20],
-                    k[21],
-                    k[22],
-                    k[23],
-                    k[24],
-                    k[25],
-                    k[26],
-                    k[27],
-                    k[28],
-                    k[29],
-                    k[30],
-                    k[31]
+                    keys_ptr
                 ))
             }
         }
@@ -823,6 +1489,101 @@ mod private {
             }
         }
 
+        fn child_nodes(&self) -> Vec<WebElement> {
+            unsafe {
+                const LEN_JS: &'static [u8] = b"\
+                    return __domafic_pool[$0].childNodes.length;\
+                \0";
+                const CHILD_JS: &'static [u8] = b"\
+                    var child = __domafic_pool[$0].childNodes[$1];\
+                    var index = __domafic_pool_free.pop();\
+                    if (index) { __domafic_pool[index] = child; return index; }\
+                    return __domafic_pool.push(child) - 1;\
+                \0";
+                let len = emscripten_asm_const_int(
+                    &LEN_JS[0] as *const _ as *const libc::c_char,
+                    self.0,
+                );
+                (0..len).map(|index| WebElement(emscripten_asm_const_int(
+                    &CHILD_JS[0] as *const _ as *const libc::c_char,
+                    self.0,
+                    index
+                ))).collect()
+            }
+        }
+
+        fn existing_value(&self) -> ExistingValue {
+            unsafe {
+                const JS: &'static [u8] = b"\
+                    var node = __domafic_pool[$0];\
+                    if (node.nodeType != 1 && node.nodeType != 3) { return -1; }\
+                    var value = node.nodeType == 1 ? node.tagName : node.nodeValue;\
+                    var index = __domafic_pool_free.pop();\
+                    if (index) { __domafic_pool[index] = value; return (index << 1) | (node.nodeType == 1); }\
+                    return ((__domafic_pool.push(value) - 1) << 1) | (node.nodeType == 1);\
+                \0";
+                let result = emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    self.0,
+                );
+                if result < 0 {
+                    return ExistingValue::Other;
+                }
+                let value = super::read_str(result >> 1).expect("Failed to read node value");
+                if result & 1 == 1 {
+                    ExistingValue::Element(value)
+                } else {
+                    ExistingValue::Text(value)
+                }
+            }
+        }
+
+        fn existing_attribute(&self, key: &str) -> Option<String> {
+            unsafe {
+                const JS: &'static [u8] = b"\
+                    var value = __domafic_pool[$0].getAttribute(UTF8ToString($1));\
+                    if (value === null) { return -1; }\
+                    var index = __domafic_pool_free.pop();\
+                    if (index) { __domafic_pool[index] = value; return index; }\
+                    return __domafic_pool.push(value) - 1;\
+                \0";
+                let key_cstring = CString::new(key).unwrap();
+                let index = emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    self.0,
+                    key_cstring.as_ptr() as libc::c_int,
+                );
+                if index < 0 {
+                    None
+                } else {
+                    Some(super::read_str(index).expect("Failed to read attribute value"))
+                }
+            }
+        }
+
+        fn existing_attribute_names(&self) -> Vec<String> {
+            unsafe {
+                // Attribute names can't contain whitespace
+                const JS: &'static [u8] = b"\
+                    var attributes = __domafic_pool[$0].attributes;\
+                    var names = [];\
+                    for (var i = 0; i < attributes.length; i++) { names.push(attributes[i].name); }\
+                    var value = names.join(' ');\
+                    var index = __domafic_pool_free.pop();\
+                    if (index) { __domafic_pool[index] = value; return index; }\
+                    return __domafic_pool.push(value) - 1;\
+                \0";
+                let index = emscripten_asm_const_int(
+                    &JS[0] as *const _ as *const libc::c_char,
+                    self.0,
+                );
+                super::read_str(index).expect("Failed to read attribute names")
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect()
+            }
+        }
+
         fn remove_all_children(&self) {
             unsafe {
                 const JS: &'static [u8] = b"\
@@ -836,7 +1597,6 @@ mod private {
             }
         }
 
-        #[allow(dead_code)]
         fn remove_self(&self) {
             unsafe {
                 const JS: &'static [u8] = b"\
@@ -927,6 +1687,10 @@ mod private {
 
     impl Drop for WebElement {
         fn drop(&mut self) {
+            if batched_mutations() {
+                MUTATIONS.with(|mutations| mutations.borrow_mut().free(self.0));
+                return;
+            }
             unsafe {
                 const JS: &'static [u8] = b"\
                     delete __domafic_pool[$0];\
@@ -940,291 +1704,608 @@ mod private {
         }
     }
 
-    #[derive(Debug, Clone, Eq, PartialEq)]
-    enum VNodeValue {
-        Text(String),
-        Tag(&'static str),
+    /// Event listener added by `WebBackend`, along with the keys passed to its callback by pointer
+    struct WebListener {
+        callback: WebElement,
+        _keys: Box<Keys>,
     }
-    #[derive(Debug)]
-    struct VDomNode<Message: 'static> {
-        value: VNodeValue,
-        keys: Keys,
-        web_element: WebElement,
-        attributes: Vec<KeyValue>,
-        listeners: Vec<(WebElement, *const Listener<Message>, &'static str)>,
-        children: VDOMLevel<Message>,
-    }
-    type VDOMLevel<Message: 'static> = Vec<VDomNode<Message>>;
-
-    struct WebWriter<'a, 'n, D, M, U, R, S>(
-        PhantomData<(&'a (), &'n (), D, M, U, R, S)>
-    );
-    struct WebWriterAcc<'n, D, M, U, R, S> where M: 'static {
-        system_ptr: *mut (D, U, R, S, VDomNode<M>),
-        keys: Keys,
-        document: WebDocument,
-        parent_element: &'n WebElement,
-        node_level: &'n mut VDOMLevel<M>,
-        node_index: &'n mut usize,
+
+    // Opcodes of the operations in a `MutationBuffer`, matching the cases in `flush_mutations`
+    const OP_CREATE_ELEMENT: i32 = 0;
+    const OP_CREATE_TEXT: i32 = 1;
+    const OP_INSERT: i32 = 2;
+    const OP_MOVE: i32 = 3;
+    const OP_SET_ATTRIBUTE: i32 = 4;
+    const OP_REMOVE_ATTRIBUTE: i32 = 5;
+    const OP_ADD_LISTENER: i32 = 6;
+    const OP_REMOVE_LISTENER: i32 = 7;
+    const OP_REMOVE_SELF: i32 = 8;
+    const OP_FREE: i32 = 9;
+
+    /// Number of `__domafic_pool` slots reserved at once for elements created in the buffer
+    const RESERVED_IDS: JsElementId = 256;
+
+    /// DOM mutations queued by `WebBackend` and applied in a single call by `flush_mutations`.
+    ///
+    /// Each operation is stored as its opcode followed by its arguments. Strings are stored as
+    /// their length in bytes followed by their NUL-terminated UTF-8 contents, padded to a whole
+    /// word.
+    ///
+    /// Since Javascript runs the operations later, the ids of created elements and listeners are
+    /// allocated here rather than in Javascript: slots in `__domafic_pool` are reserved in blocks
+    /// and the slots of dropped `WebElement`s are reused.
+    struct MutationBuffer {
+        words: Vec<i32>,
+        free_ids: Vec<JsElementId>,
+        next_id: JsElementId,
+        reserved_end: JsElementId,
     }
 
-    impl<'a, 'n, D, M, U, R, S> DomNodeProcessor<'a, M> for WebWriter<'a, 'n, D, M, U, R, S>
-        where
-        D: DomNode<M>,
-        M: 'static,
-        U: Updater<S, M>,
-        R: Renderer<S, M, Rendered=D>
-    {
-        type Acc = WebWriterAcc<'n, D, M, U, R, S>;
-        type Error = ();
-
-        fn get_processor<T: DomNode<M>>() -> fn(&mut Self::Acc, &'a T) -> Result<(), Self::Error> {
-            fn add_node<'a, 'n, T, D, M, U, R, S>(
-                acc: &mut WebWriterAcc<'n, D, M, U, R, S>,
-                node: &'a T) -> Result<(), ()>
-                where
-                T: DomNode<M>,
-                D: DomNode<M>,
-                M: 'static,
-                U: Updater<S, M>,
-                R: Renderer<S, M, Rendered=D>
-            {
-
-                let vnode_value = match node.value() {
-                    DomValue::Element { tag } => VNodeValue::Tag(tag),
-                    DomValue::Text(text) => VNodeValue::Text(text.to_string()),
-                };
+    impl MutationBuffer {
+        fn new() -> MutationBuffer {
+            MutationBuffer { words: Vec::new(), free_ids: Vec::new(), next_id: 0, reserved_end: 0 }
+        }
 
-                let keys = if let Some(new_key) = node.key() {
-                    acc.keys.push(new_key)
-                } else {
-                    acc.keys
+        fn alloc_id(&mut self) -> JsElementId {
+            if let Some(id) = self.free_ids.pop() {
+                return id;
+            }
+            if self.next_id == self.reserved_end {
+                const JS: &[u8] = b"\
+                    var start = __domafic_pool.length;\
+                    for (var i = 0; i < $0; i++) { __domafic_pool.push(null); }\
+                    return start;\
+                \0";
+                self.next_id = unsafe {
+                    emscripten_asm_const_int(&JS[0] as *const _ as *const libc::c_char, RESERVED_IDS)
                 };
+                self.reserved_end = self.next_id + RESERVED_IDS;
+            }
+            self.next_id += 1;
+            self.next_id - 1
+        }
 
-                let listeners = {
-                    let mut listeners = Vec::new();
-                    node.listeners().process_all::<ListenersToVec<M>>(&mut listeners)?;
-                    listeners
-                };
+        fn push_op(&mut self, op: i32, args: &[i32]) {
+            self.words.push(op);
+            self.words.extend_from_slice(args);
+        }
 
-                let vnode_match_opt_index = {
-                    let mut vnode_match_opt_index = None;
-                    let mut trial_index = *acc.node_index;
-                    while let Some(trial_vnode) = acc.node_level.get(trial_index) {
-                        // Match iff "keys" and "value" are equal.
-                        // Cannot match elements with lower indices than
-                        // `acc.node_index`, as they are the output of prior calls to `add_node`.
-                        if (trial_vnode.keys == keys) &&
-                            (trial_vnode.value == vnode_value)
-                        {
-                            vnode_match_opt_index = Some(trial_index);
-                            break;
-                        } else {
-                            trial_index += 1;
-                        }
-                    }
-                    vnode_match_opt_index
-                };
+        fn push_str(&mut self, value: &str) {
+            let bytes = value.as_bytes();
+            self.words.push(bytes.len() as i32);
+            for chunk in bytes.chunks(4) {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                self.words.push(i32::from_le_bytes(word));
+            }
+            if bytes.len() % 4 == 0 {
+                // NUL terminator
+                self.words.push(0);
+            }
+        }
 
-                if let Some(vnode_index) = vnode_match_opt_index {
-                    // Modify the existing element
-                    // Add new listeners, unify attributes, unify children
-
-                    {
-                        let mut vnode = &mut acc.node_level[vnode_index];
-
-                        // Remove excess listeners
-                        {
-                            let mut i = 0;
-                            while i < vnode.listeners.len() {
-                                let do_remove = {
-                                    let ref listener = vnode.listeners[i];
-                                    let (ref old_element, ref old_ptr, ref old_str) = *listener;
-
-                                    if !listeners.iter().any(|listener|
-                                        *old_ptr == *listener &&
-                                        *old_str == unsafe{ (**listener).event_type_handled() }
-                                    ) {
-                                        vnode.web_element.remove_listener(old_str, &old_element);
-                                        true
-                                    } else {
-                                        i += 1;
-                                        false
-                                    }
-                                };
-
-                                if do_remove {
-                                    vnode.listeners.remove(i);
-                                }
-                            }
-                        }
-
-                        // Add new listeners
-                        for listener in listeners {
-                            unsafe {
-                                let event_type = (*listener).event_type_handled();
-                                if !vnode.listeners.iter().any(|x|
-                                        x.1 == listener &&
-                                        x.2 == event_type
-                                    ) {
-                                    let element = vnode.web_element.set_listener(
-                                        event_type,
-                                        listener,
-                                        acc.system_ptr,
-                                        keys
-                                    );
-                                    vnode.listeners.push((element, listener, event_type));
-                                }
-                            }
-                        }
-
-                        // Remove excess attributes
-                        {
-                            let mut i = 0;
-                            while i < vnode.attributes.len() {
-                                let do_remove = {
-                                    let ref old_attribute = vnode.attributes[i];
-                                    if !node.attributes().any(|attr| *attr == *old_attribute) {
-                                        vnode.web_element.remove_attribute(old_attribute.0);
-                                        true
-                                    } else {
-                                        false
-                                    }
-                                };
-
-                                if do_remove {
-                                    vnode.attributes.remove(i);
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                        }
-
-                        // Add new attributes
-                        for new_attribute in node.attributes() {
-                            if !vnode.attributes.contains(new_attribute) {
-                                vnode.web_element.set_attribute(new_attribute);
-                                vnode.attributes.push(new_attribute.clone());
-                            }
-                        }
-
-                        // To the children!
-                        let mut child_node_index = 0;
-                        {
-                            let mut new_acc = WebWriterAcc {
-                                system_ptr: acc.system_ptr,
-                                keys: keys,
-                                document: acc.document,
-                                parent_element: &vnode.web_element,
-                                node_level: &mut vnode.children,
-                                node_index: &mut child_node_index,
-                            };
-                            node.children().process_all::<WebWriter<D, M, U, R, S>>(&mut new_acc)?;
-                        }
-                        // Remove DOM elements left over from the last render that weren't repurposed
-                        while child_node_index < vnode.children.len() {
-                            let unused_dom_element = vnode.children.pop().unwrap();
-                            unused_dom_element.web_element.remove_self();
-                        }
-                    }
+        fn free(&mut self, id: JsElementId) {
+            self.push_op(OP_FREE, &[id]);
+            self.free_ids.push(id);
+        }
+    }
 
-                    // Move the element if the new index is different from the old one
-                    if *acc.node_index != vnode_index {
-                        acc.parent_element.move_child(vnode_index, *acc.node_index);
-                        let old_vnode = acc.node_level.remove(vnode_index);
-                        acc.node_level.insert(*acc.node_index, old_vnode);
-                    }
-                } else {
-                    // Construct as a new element
-
-                    let html_element = match node.value() {
-                        DomValue::Element { tag } => {
-                            acc.document.create_element(tag).unwrap()},
-                        DomValue::Text(text) =>
-                            acc.document.create_text_node(text).unwrap(),
-                    };
-
-                    let mut listeners_with_metadata = Vec::new();
-                    for listener in listeners {
-                        unsafe {
-                            let event_type = (*listener).event_type_handled();
-                            let element = html_element.set_listener(
-                                event_type,
-                                listener,
-                                acc.system_ptr,
-                                keys
-                            );
-                            listeners_with_metadata.push((element, listener, event_type));
-                        }
-                    }
+    thread_local! {
+        static MUTATIONS: RefCell<MutationBuffer> = RefCell::new(MutationBuffer::new());
+        static BATCHED_MUTATIONS: Cell<bool> = const { Cell::new(true) };
+    }
 
-                    let mut vnode_attributes = Vec::new();
-                    for attr in node.attributes() {
-                        html_element.set_attribute(attr);
-                        vnode_attributes.push(attr.clone());
-                    }
+    /// Sets whether changes to the page are queued and applied with a single call into
+    /// Javascript at the end of each update (the default), or applied one at a time as the
+    /// page is diffed.
+    ///
+    /// Applying changes one at a time is slower, but can be useful for debugging or for
+    /// comparing performance.
+    pub fn set_batched_mutations(batched: bool) {
+        BATCHED_MUTATIONS.with(|cell| cell.set(batched));
+    }
 
-                    let mut vnode = VDomNode {
-                        value: vnode_value,
-                        keys: keys,
-                        web_element: html_element,
-                        attributes: vnode_attributes,
-                        listeners: listeners_with_metadata,
-                        children: Vec::new(),
-                    };
-
-                    let mut child_node_index = 0;
-                    {
-                        let mut new_acc = WebWriterAcc {
-                            system_ptr: acc.system_ptr,
-                            keys: keys,
-                            document: acc.document,
-                            parent_element: &vnode.web_element,
-                            node_level: &mut vnode.children,
-                            node_index: &mut child_node_index,
-                        };
-                        node.children().process_all::<WebWriter<D, M, U, R, S>>(&mut new_acc)?;
-                    }
-                    // Remove DOM elements left over from the last render that weren't repurposed
-                    while child_node_index < vnode.children.len() {
-                        let unused_dom_element = vnode.children.pop().unwrap();
-                        unused_dom_element.web_element.remove_self();
-                    }
+    fn batched_mutations() -> bool {
+        BATCHED_MUTATIONS.with(Cell::get)
+    }
 
-                    acc.parent_element.insert(*acc.node_index, &vnode.web_element);
-                    acc.node_level.insert(*acc.node_index, vnode);
-                }
+    thread_local! {
+        static OPERATION_COUNTS: Cell<OpCounts> = Cell::new(OpCounts::default());
+    }
+
+    /// Returns the number of operations of each kind that rendering has applied to the page
+    /// since the application started.
+    pub fn operation_counts() -> OpCounts {
+        OPERATION_COUNTS.with(Cell::get)
+    }
+
+    fn record_operations(counts: OpCounts) {
+        OPERATION_COUNTS.with(|cell| cell.set(cell.get() + counts));
+    }
+
+    thread_local! {
+        static RENDER_MODE: Cell<RenderMode> = const { Cell::new(RenderMode::Sync) };
+    }
+
+    /// Sets when the application is rendered after its state is updated.
+    ///
+    /// By default, the application is rendered after every message (`RenderMode::Sync`). Use
+    /// `RenderMode::AnimationFrame` to defer rendering to the next animation frame instead, so
+    /// that a burst of events renders only once.
+    pub fn set_render_mode(mode: RenderMode) {
+        RENDER_MODE.with(|cell| cell.set(mode));
+    }
+
+    fn render_mode() -> RenderMode {
+        RENDER_MODE.with(Cell::get)
+    }
+
+    /// Applies all queued mutations to the page.
+    ///
+    /// The queue is taken out of `MUTATIONS` first, so that event listeners called synchronously
+    /// by the browser while the mutations are applied can queue (and flush) their own.
+    fn flush_mutations() {
+        let mut words = MUTATIONS.with(|mutations| mem::take(&mut mutations.borrow_mut().words));
+        if words.is_empty() {
+            return;
+        }
+        let err = unsafe {
+            const JS: &[u8] = b"\
+                var p = $0 >> 2;\
+                var end = p + $1;\
+                function str() {\
+                    var len = HEAP32[p];\
+                    var value = UTF8ToString((p + 1) << 2);\
+                    p += 1 + ((len + 4) >> 2);\
+                    return value;\
+                }\
+                while (p < end) {\
+                    var op = HEAP32[p++];\
+                    if (op == 0) {\
+                        var id = HEAP32[p++];\
+                        __domafic_pool[id] = document.createElement(str());\
+                    } else if (op == 1) {\
+                        var id = HEAP32[p++];\
+                        __domafic_pool[id] = document.createTextNode(str());\
+                    } else if (op == 2) {\
+                        var parent = __domafic_pool[HEAP32[p]];\
+                        var child = __domafic_pool[HEAP32[p + 1]];\
+                        var index = HEAP32[p + 2];\
+                        p += 3;\
+                        if (index > parent.childNodes.length) { return -1; }\
+                        if (index == parent.childNodes.length) {\
+                            parent.appendChild(child);\
+                        } else {\
+                            parent.insertBefore(child, parent.childNodes[index]);\
+                        }\
+                    } else if (op == 3) {\
+                        var parent = __domafic_pool[HEAP32[p]];\
+                        var oldIndex = HEAP32[p + 1];\
+                        var newIndex = HEAP32[p + 2];\
+                        p += 3;\
+                        if (oldIndex >= parent.childNodes.length) { return -2; }\
+                        if (newIndex >= parent.childNodes.length) { return -2; }\
+                        var elem = parent.childNodes[oldIndex];\
+                        parent.removeChild(elem);\
+                        if (newIndex == parent.childNodes.length) {\
+                            parent.appendChild(elem);\
+                        } else {\
+                            parent.insertBefore(elem, parent.childNodes[newIndex]);\
+                        }\
+                    } else if (op == 4) {\
+                        var elem = __domafic_pool[HEAP32[p++]];\
+                        var key = str();\
+                        elem[key] = str();\
+                    } else if (op == 5) {\
+                        var elem = __domafic_pool[HEAP32[p++]];\
+                        elem[str()] = null;\
+                    } else if (op == 6) {\
+                        var elem = __domafic_pool[HEAP32[p]];\
+                        var id = HEAP32[p + 1];\
+                        var callback = __domafic_make_listener(HEAP32[p + 2], HEAP32[p + 3],\
+                            HEAP32[p + 4], HEAP32[p + 5], HEAP32[p + 6]);\
+                        p += 7;\
+                        elem.addEventListener(str(), callback, false);\
+                        __domafic_pool[id] = callback;\
+                    } else if (op == 7) {\
+                        var elem = __domafic_pool[HEAP32[p]];\
+                        var callback = __domafic_pool[HEAP32[p + 1]];\
+                        p += 2;\
+                        elem.removeEventListener(str(), callback);\
+                    } else if (op == 8) {\
+                        var elem = __domafic_pool[HEAP32[p++]];\
+                        if (elem.parentNode) { elem.parentNode.removeChild(elem); }\
+                    } else if (op == 9) {\
+                        delete __domafic_pool[HEAP32[p++]];\
+                    }\
+                }\
+                return 0;\
+            \0";
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                words.as_ptr(),
+                words.len() as libc::c_int,
+            )
+        };
+
+        // Must panic on error because failure to properly add/remove nodes
+        // containing listeners can cause memory unsafety
+        match err {
+            -1 => panic!("Attempted to insert child DOM element out of bounds"),
+            -2 => panic!("Attempted to move child DOM element out of bounds"),
+            _ => {}
+        }
+
+        // Hand the allocation back for reuse, unless mutations were queued in the meantime
+        words.clear();
+        MUTATIONS.with(|mutations| {
+            let mut mutations = mutations.borrow_mut();
+            if mutations.words.is_empty() {
+                mutations.words = words;
+            }
+        });
+    }
+
+    /// `DomBackend` that draws to the browser's DOM via emscripten
+    ///
+    /// Unless disabled using `set_batched_mutations`, changes are queued in `MUTATIONS` and must
+    /// be applied using `flush_mutations` once the `VDom` is updated.
+    struct WebBackend<Message: 'static> {
+        document: WebDocument,
+        system_ptr: *mut libc::c_void,
+        handler_fn_ptr: *const libc::c_void,
+        batched: bool,
+        _marker: PhantomData<Message>,
+    }
 
-                *acc.node_index += 1;
-                Ok(())
+    impl<M: 'static> WebBackend<M> {
+        fn new<D, U, R, S>(document: WebDocument, system_ptr: *mut AppSystem<D, M, U, R, S>)
+            -> WebBackend<M>
+            where
+            D: DomNode<M>,
+            U: Updater<S, M>,
+            R: Renderer<S, M, Rendered=D>
+        {
+            WebBackend {
+                document: document,
+                system_ptr: system_ptr as *mut libc::c_void,
+                handler_fn_ptr: handle_listener::<D, M, U, R, S> as *const libc::c_void,
+                batched: batched_mutations(),
+                _marker: PhantomData,
             }
+        }
+
+        fn create_batched(&mut self, op: i32, value: &str) -> WebElement {
+            MUTATIONS.with(|mutations| {
+                let mut mutations = mutations.borrow_mut();
+                let id = mutations.alloc_id();
+                mutations.push_op(op, &[id]);
+                mutations.push_str(value);
+                WebElement(id)
+            })
+        }
 
-            add_node
+        fn push_batched(&mut self, op: i32, args: &[i32], strings: &[&str]) {
+            MUTATIONS.with(|mutations| {
+                let mut mutations = mutations.borrow_mut();
+                mutations.push_op(op, args);
+                for string in strings {
+                    mutations.push_str(string);
+                }
+            })
         }
     }
 
-    struct ListenersToVec<Message: 'static>(PhantomData<Message>);
-    impl<'a, M: 'static> ListenerProcessor<'a, M> for ListenersToVec<M> {
-        type Acc = Vec<*const Listener<M>>;
-        type Error = ();
+    impl<M: 'static> DomBackend<M> for WebBackend<M> {
+        type Element = WebElement;
+        type ListenerHandle = WebListener;
 
-        fn get_processor<L: Listener<M>>() -> fn(&mut Self::Acc, &'a L) -> Result<(), Self::Error> {
-            fn add_listener_to_vec<M, L: Listener<M>>(
-                vec: &mut Vec<*const Listener<M>>,
-                listener: &L) -> Result<(), ()>
-            {
-                vec.push(
-                    // Extend the lifetime of the listener (we know it's valid until at least the
-                    // next callback) and convert it to a *const
-                    unsafe { mem::transmute(listener as &Listener<M>) }
-                );
-                Ok(())
+        fn create_element(&mut self, tag: &'static str) -> WebElement {
+            if self.batched {
+                return self.create_batched(OP_CREATE_ELEMENT, tag);
+            }
+            self.document.create_element(tag).unwrap()
+        }
+
+        fn create_text_node(&mut self, text: &str) -> WebElement {
+            if self.batched {
+                return self.create_batched(OP_CREATE_TEXT, text);
+            }
+            self.document.create_text_node(text).unwrap()
+        }
+
+        fn insert(&mut self, parent: &WebElement, index: usize, child: &WebElement) {
+            if self.batched {
+                return self.push_batched(OP_INSERT, &[parent.0, child.0, index as i32], &[]);
+            }
+            parent.insert(index, child)
+        }
+
+        fn move_child(&mut self, parent: &WebElement, old_index: usize, new_index: usize) {
+            if self.batched {
+                let args = [parent.0, old_index as i32, new_index as i32];
+                return self.push_batched(OP_MOVE, &args, &[]);
+            }
+            parent.move_child(old_index, new_index)
+        }
+
+        fn set_attribute(&mut self, element: &WebElement, attribute: &KeyValue) {
+            if !self.batched {
+                return element.set_attribute(attribute);
+            }
+            let value = attribute.1.as_str();
+            self.push_batched(OP_SET_ATTRIBUTE, &[element.0], &[attribute.0, value]);
+            if attribute.0 == "codemirror" {
+                // The editor is set up on the element itself, so it must exist by now
+                flush_mutations();
+                element.codemirror(value);
+            }
+        }
+
+        fn remove_attribute(&mut self, element: &WebElement, key: &str) {
+            if self.batched {
+                return self.push_batched(OP_REMOVE_ATTRIBUTE, &[element.0], &[key]);
+            }
+            element.remove_attribute(key)
+        }
+
+        fn add_listener(
+            &mut self,
+            element: &WebElement,
+            event_type: &'static str,
+            listener: *const Listener<M>,
+            keys: Keys,
+        ) -> WebListener {
+            let keys = Box::new(keys);
+            if self.batched {
+                let (listener_data_c_ptr, listener_vtable_c_ptr): (i32, i32) =
+                    unsafe { mem::transmute(listener) };
+                let callback = MUTATIONS.with(|mutations| {
+                    let mut mutations = mutations.borrow_mut();
+                    let id = mutations.alloc_id();
+                    mutations.push_op(OP_ADD_LISTENER, &[
+                        element.0,
+                        id,
+                        self.handler_fn_ptr as i32,
+                        listener_data_c_ptr,
+                        listener_vtable_c_ptr,
+                        self.system_ptr as i32,
+                        &*keys as *const Keys as i32,
+                    ]);
+                    mutations.push_str(event_type);
+                    WebElement(id)
+                });
+                return WebListener { callback, _keys: keys };
+            }
+            let callback = unsafe {
+                element.set_listener(
+                    event_type,
+                    listener,
+                    self.handler_fn_ptr,
+                    self.system_ptr,
+                    &*keys
+                )
+            };
+            WebListener { callback, _keys: keys }
+        }
+
+        fn remove_listener(
+            &mut self,
+            element: &WebElement,
+            event_type: &'static str,
+            listener: WebListener,
+        ) {
+            if self.batched {
+                let args = [element.0, listener.callback.0];
+                return self.push_batched(OP_REMOVE_LISTENER, &args, &[event_type]);
+            }
+            element.remove_listener(event_type, &listener.callback)
+        }
+
+        fn remove_self(&mut self, element: &WebElement) {
+            if self.batched {
+                return self.push_batched(OP_REMOVE_SELF, &[element.0], &[]);
             }
-            add_listener_to_vec
+            element.remove_self()
+        }
+
+        // Queued mutations are applied before reading the page, so that reads see their effects
+
+        fn existing_children(&mut self, parent: &WebElement) -> Vec<WebElement> {
+            flush_mutations();
+            parent.child_nodes()
+        }
+
+        fn existing_value(&mut self, node: &WebElement) -> ExistingValue {
+            flush_mutations();
+            node.existing_value()
+        }
+
+        fn existing_attribute(&mut self, element: &WebElement, key: &str) -> Option<String> {
+            flush_mutations();
+            element.existing_attribute(key)
+        }
+
+        fn existing_attribute_names(&mut self, element: &WebElement) -> Vec<String> {
+            flush_mutations();
+            element.existing_attribute_names()
+        }
+    }
+}
+
+/// Reads the serialized initial state written on the server by `html_document::state_script`
+/// (or `HtmlDocument::with_state`), returning `None` if the page doesn't contain one.
+///
+/// Call this before `run` or `hydrate` to start the client with the same state that the server
+/// rendered.
+pub fn read_initial_state() -> Option<String> {
+    use html_document::STATE_SCRIPT_ID;
+    use web_render::private::emscripten_asm_const_int;
+    private::init_pool();
+    unsafe {
+        const JS: &'static [u8] = b"\
+            var element = document.getElementById(UTF8ToString($0));\
+            if (!element) { return -1; }\
+            var index = __domafic_pool_free.pop();\
+            if (index) { __domafic_pool[index] = element.textContent; return index; }\
+            return __domafic_pool.push(element.textContent) - 1;\
+        \0";
+        let id_cstring = CString::new(STATE_SCRIPT_ID).unwrap();
+        let index = emscripten_asm_const_int(
+            &JS[0] as *const _ as *const libc::c_char,
+            id_cstring.as_ptr() as libc::c_int,
+        );
+        if index < 0 {
+            None
+        } else {
+            Some(read_str(index).expect("Failed to read initial state"))
         }
     }
 }
 
+/// Returns the current URL of the page, excluding the scheme and host (such as
+/// `/todos/3?filter=done`), for parsing the initial route with `router::Route::from_url`.
+pub fn current_url() -> String {
+    use web_render::private::emscripten_asm_const_int;
+    private::init_pool();
+    unsafe {
+        const JS: &'static [u8] = b"\
+            var url = location.pathname + location.search + location.hash;\
+            var index = __domafic_pool_free.pop();\
+            if (index) { __domafic_pool[index] = url; return index; }\
+            return __domafic_pool.push(url) - 1;\
+        \0";
+        let index = emscripten_asm_const_int(&JS[0] as *const _ as *const libc::c_char);
+        read_str(index).expect("Failed to read current URL")
+    }
+}
+
+/// The browser's `localStorage` or `sessionStorage`.
+///
+/// Reads and writes happen immediately, so a `WebStorage` can be used to load the initial state
+/// before calling `run`. Updaters should use the commands in the `storage` module instead, which
+/// `TestApp` can simulate.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WebStorage {
+    area: StorageArea,
+}
+
+impl WebStorage {
+    /// Creates a `WebStorage` accessing `area`.
+    pub fn new(area: StorageArea) -> WebStorage {
+        private::init_pool();
+        WebStorage { area }
+    }
+
+    fn area_int(&self) -> libc::c_int {
+        (self.area == StorageArea::Session) as libc::c_int
+    }
+}
+
+impl Storage for WebStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        const JS: &'static [u8] = b"\
+            var value;\
+            try {\
+                value = ($0 ? sessionStorage : localStorage).getItem(UTF8ToString($1));\
+            } catch (e) { return -1; }\
+            if (value === null) { return -1; }\
+            var index = __domafic_pool_free.pop();\
+            if (index) { __domafic_pool[index] = value; return index; }\
+            return __domafic_pool.push(value) - 1;\
+        \0";
+        let key_cstring = CString::new(key).unwrap();
+        let index = unsafe {
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                self.area_int(),
+                key_cstring.as_ptr() as libc::c_int,
+            )
+        };
+        if index < 0 {
+            None
+        } else {
+            Some(read_str(index).expect("Failed to read stored value"))
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> ::std::result::Result<(), StorageError> {
+        const JS: &'static [u8] = b"\
+            try {\
+                ($0 ? sessionStorage : localStorage).setItem(UTF8ToString($1), UTF8ToString($2));\
+                return 0;\
+            } catch (e) {\
+                var quota = e.name === 'QuotaExceededError' ||\
+                    e.name === 'NS_ERROR_DOM_QUOTA_REACHED' || e.code === 22;\
+                return quota ? 1 : 2;\
+            }\
+        \0";
+        let key_cstring = CString::new(key).unwrap();
+        let value_cstring = CString::new(value).unwrap();
+        let result = unsafe {
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                self.area_int(),
+                key_cstring.as_ptr() as libc::c_int,
+                value_cstring.as_ptr() as libc::c_int,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            1 => Err(StorageError::QuotaExceeded),
+            _ => Err(StorageError::Unavailable),
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        const JS: &'static [u8] = b"\
+            try { ($0 ? sessionStorage : localStorage).removeItem(UTF8ToString($1)); } catch (e) {}\
+        \0";
+        let key_cstring = CString::new(key).unwrap();
+        unsafe {
+            emscripten_asm_const_int(
+                &JS[0] as *const _ as *const libc::c_char,
+                self.area_int(),
+                key_cstring.as_ptr() as libc::c_int,
+            );
+        }
+    }
+
+    fn clear(&mut self) {
+        const JS: &'static [u8] = b"\
+            try { ($0 ? sessionStorage : localStorage).clear(); } catch (e) {}\
+        \0";
+        unsafe {
+            emscripten_asm_const_int(&JS[0] as *const _ as *const libc::c_char, self.area_int());
+        }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        const LENGTH_JS: &'static [u8] = b"\
+            try { return ($0 ? sessionStorage : localStorage).length; } catch (e) { return 0; }\
+        \0";
+        const KEY_JS: &'static [u8] = b"\
+            var key = ($0 ? sessionStorage : localStorage).key($1);\
+            var index = __domafic_pool_free.pop();\
+            if (index) { __domafic_pool[index] = key; return index; }\
+            return __domafic_pool.push(key) - 1;\
+        \0";
+        unsafe {
+            let length = emscripten_asm_const_int(
+                &LENGTH_JS[0] as *const _ as *const libc::c_char,
+                self.area_int(),
+            );
+            (0..length).map(|i| {
+                let index = emscripten_asm_const_int(
+                    &KEY_JS[0] as *const _ as *const libc::c_char,
+                    self.area_int(),
+                    i,
+                );
+                read_str(index).expect("Failed to read storage key")
+            }).collect()
+        }
+    }
+}
 
 /// set title of the document
 pub fn set_title(title: &str) {
@@ -1339,7 +2420,7 @@ fn get_strlen(index: i32) -> Result<i32>{
     use web_render::private::emscripten_asm_const_int;
     unsafe {
         const JS: &'static [u8] = b"\
-            if(__domafic_pool[$0]){\
+            if(typeof __domafic_pool[$0] === 'string'){\
                 return lengthBytesUTF8(__domafic_pool[$0]);\
             }\
             return -1;\
@@ -1369,7 +2450,7 @@ fn read_str(index: i32) -> Result<String>{
 
     unsafe {
         const JS: &'static [u8] = b"\
-            if(__domafic_pool[$0]){\
+            if(typeof __domafic_pool[$0] === 'string'){\
                 stringToUTF8(__domafic_pool[$0], $1, lengthBytesUTF8(__domafic_pool[$0])+1);\
             }\
             return -1;\
@@ -1494,17 +2575,7 @@ pub fn delayed_click_on(id: &str, delay: i32){
 
 /// simulate a delayed click on element
 pub fn save_last_opened_file(filename: &str){
-    unsafe {
-        const JS: &'static [u8] = b"\
-            localStorage.setItem('last_opened_file', UTF8ToString($0));\
-        \0";
-
-        let filename_cstring = CString::new(filename).unwrap();
-        emscripten_asm_const_int(
-            &JS[0] as *const _ as *const libc::c_char,
-            filename_cstring.as_ptr() as libc::c_int
-        );
-    }
+    let _ = WebStorage::new(StorageArea::Local).set("last_opened_file", filename);
 }
 
 